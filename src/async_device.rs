@@ -0,0 +1,385 @@
+// This file is part of ctap, a Rust implementation of the FIDO2 protocol.
+// Copyright (c) Ariën Holthuizen <contact@ardaxi.com>
+// Licensed under the Apache License, Version 2.0, <LICENSE-APACHE or
+// http://apache.org/licenses/LICENSE-2.0> or the MIT license <LICENSE-MIT or
+// http://opensource.org/licenses/MIT>, at your option. This file may not be
+// copied, modified, or distributed except according to those terms.
+use std::future;
+use std::io;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use failure::ResultExt;
+use rand::prelude::*;
+use tokio::time;
+
+use super::error::*;
+use super::hid::{AsyncHidrawDevice, DeviceInfo};
+#[cfg(feature = "trace")]
+use super::protocol_log;
+use super::packet::{self, CtapCommand, DeviceCapabilities, InitResponse, KeepaliveStatus};
+use super::retry::RetryPolicy;
+use super::transport::{AsyncTransport, ReportWriter};
+use super::{cbor, crypto, u2f, FidoCredential, BROADCAST_CID, CANCEL_TIMEOUT, U2F_POLL_INTERVAL};
+
+/// An opened FIDO authenticator with an async API, for use with tokio. This
+/// offers the same operations as `FidoDevice`, but waits for the authenticator
+/// without blocking the thread.
+///
+/// There is no timeout setting or cancel handle. To stop waiting for the
+/// authenticator, drop the future, for example using `tokio::time::timeout`.
+/// The authenticator is then sent CTAPHID_CANCEL.
+///
+/// ```
+/// # use std::time::Duration;
+/// # async fn do_fido() -> ctap::FidoResult<()> {
+/// let device_info = ctap::get_devices()?.next().unwrap();
+/// let mut device = ctap::AsyncFidoDevice::new(&device_info).await?;
+/// let cred = tokio::time::timeout(
+///     Duration::from_secs(30),
+///     device.make_credential("rp_id", &[0], "user_name", &[0; 32]),
+/// ).await;
+/// # Ok(())
+/// # }
+/// ```
+pub struct AsyncFidoDevice<T: AsyncTransport = AsyncHidrawDevice> {
+    device: T,
+    channel_id: [u8; 4],
+    init_response: InitResponse,
+    u2f: bool,
+    needs_pin: bool,
+    shared_secret: Option<crypto::SharedSecret>,
+    pin_token: Option<crypto::PinToken>,
+    aaguid: [u8; 16],
+    keepalive: Option<Box<dyn FnMut(KeepaliveStatus) + Send>>,
+    retry_policy: RetryPolicy,
+    writer: Option<Arc<Mutex<Box<dyn ReportWriter>>>>,
+    // A request that was cancelled by dropping its future. The authenticator
+    // may still respond to it.
+    cancelled: Option<CtapCommand>,
+}
+
+impl AsyncFidoDevice {
+    /// Open and initialize a given device, like `FidoDevice::new`. This must
+    /// be called from within a tokio runtime.
+    pub async fn new(device: &DeviceInfo) -> FidoResult<Self> {
+        let device = AsyncHidrawDevice::open(device).context(FidoErrorKind::Io)?;
+        AsyncFidoDevice::with_transport(device).await
+    }
+}
+
+impl<T: AsyncTransport> AsyncFidoDevice<T> {
+    /// Initialize a device reachable over the given transport, like
+    /// `FidoDevice::with_transport`.
+    pub async fn with_transport(transport: T) -> FidoResult<Self> {
+        if transport.input_report_size() < 8 || transport.output_report_size() < 8 {
+            Err(FidoErrorKind::DeviceUnsupported)?
+        }
+        let writer = transport
+            .report_writer()
+            .map(|writer| Arc::new(Mutex::new(writer)));
+        let mut dev = AsyncFidoDevice {
+            device: transport,
+            channel_id: BROADCAST_CID,
+            init_response: InitResponse::default(),
+            u2f: false,
+            needs_pin: false,
+            shared_secret: None,
+            pin_token: None,
+            aaguid: [0; 16],
+            keepalive: None,
+            retry_policy: RetryPolicy::default(),
+            writer,
+            cancelled: None,
+        };
+        dev.init().await?;
+        Ok(dev)
+    }
+
+    async fn init(&mut self) -> FidoResult<()> {
+        let mut nonce = [0u8; 8];
+        thread_rng().fill_bytes(&mut nonce);
+        let response = self.exchange(CtapCommand::Init, &nonce).await?;
+        let response = InitResponse::decode(&nonce, &response)?;
+        self.channel_id = response.channel_id;
+        self.init_response = response;
+        if self.init_response.capabilities.cbor {
+            let response = super::get_info_from_response(self.cbor(cbor::Request::GetInfo).await?)?;
+            if super::supports_ctap2(&response)? {
+                self.needs_pin = response.options.client_pin == Some(true);
+                self.aaguid = response.aaguid;
+                return Ok(());
+            }
+        }
+        if self.init_response.capabilities.nmsg {
+            Err(FidoErrorKind::DeviceUnsupported)?
+        }
+        super::check_u2f_version(&self.u2f(&u2f::encode_version()?).await?)?;
+        self.u2f = true;
+        Ok(())
+    }
+
+    /// Get the authenticator's response to CTAPHID_INIT.
+    pub fn init_response(&self) -> &InitResponse {
+        &self.init_response
+    }
+
+    /// Get the capabilities the authenticator advertises.
+    pub fn capabilities(&self) -> DeviceCapabilities {
+        self.init_response.capabilities
+    }
+
+    /// Get the authenticator's AAGUID.
+    pub fn aaguid(&self) -> &[u8] {
+        &self.aaguid
+    }
+
+    /// Register a callback that is invoked whenever the authenticator sends a
+    /// keepalive message, like `FidoDevice::set_keepalive_callback`.
+    pub fn set_keepalive_callback<F>(&mut self, callback: F)
+    where
+        F: FnMut(KeepaliveStatus) + Send + 'static,
+    {
+        self.keepalive = Some(Box::new(callback));
+    }
+
+    /// Set how requests are repeated when the authenticator is busy with another
+    /// application, or reports that a message timed out.
+    pub fn set_retry_policy(&mut self, policy: RetryPolicy) {
+        self.retry_policy = policy;
+    }
+
+    /// Send a CTAPHID_PING and wait for the authenticator to echo it back, like
+    /// `FidoDevice::ping`. Returns the round-trip time.
+    pub async fn ping(&mut self, payload: &[u8]) -> FidoResult<Duration> {
+        let start = Instant::now();
+        let response = self.exchange(CtapCommand::Ping, payload).await?;
+        let elapsed = start.elapsed();
+        if response != payload {
+            Err(FidoErrorKind::PingMismatch)?
+        }
+        Ok(elapsed)
+    }
+
+    async fn init_shared_secret(&mut self) -> FidoResult<()> {
+        let request = super::key_agreement_request();
+        let response = self.cbor(cbor::Request::ClientPin(request)).await?;
+        self.shared_secret = Some(super::shared_secret_from_response(response)?);
+        Ok(())
+    }
+
+    /// Unlock the device with the provided PIN, like `FidoDevice::unlock`.
+    pub async fn unlock(&mut self, pin: &str) -> FidoResult<()> {
+        if self.u2f {
+            Err(FidoErrorKind::CapabilityUnsupported)?
+        }
+        while self.shared_secret.is_none() {
+            self.init_shared_secret().await?;
+        }
+        // If the PIN is invalid the device should create a new agreementKey,
+        // so we only replace shared_secret on success.
+        let shared_secret = self.shared_secret.take().unwrap();
+        let request = super::pin_token_request(&shared_secret, pin)?;
+        let response = self.cbor(cbor::Request::ClientPin(request)).await?;
+        self.pin_token = Some(super::pin_token_from_response(&shared_secret, response)?);
+        self.shared_secret = Some(shared_secret);
+        Ok(())
+    }
+
+    /// Request a new credential from the authenticator, like
+    /// `FidoDevice::make_credential`.
+    ///
+    /// This waits for the user to touch the authenticator for as long as it
+    /// takes, wrap it in `tokio::time::timeout` to give up at some point.
+    pub async fn make_credential(
+        &mut self,
+        rp_id: &str,
+        user_id: &[u8],
+        user_name: &str,
+        client_data_hash: &[u8],
+    ) -> FidoResult<FidoCredential> {
+        super::check_request(self.needs_pin, &self.pin_token, client_data_hash)?;
+        if self.u2f {
+            let request = super::u2f_register_request(rp_id, client_data_hash)?;
+            let response = self.u2f_with_presence(&request).await?;
+            return super::u2f_credential_from_response(rp_id, &response);
+        }
+        let pin_auth = super::pin_auth(&self.pin_token, client_data_hash);
+        let request = super::make_credential_request(
+            rp_id,
+            user_id,
+            user_name,
+            client_data_hash,
+            pin_auth.as_ref().map(|pin_auth| &pin_auth[..]),
+        );
+        let response = self.cbor(cbor::Request::MakeCredential(request)).await?;
+        super::credential_from_response(rp_id, response)
+    }
+
+    /// Request an assertion from the authenticator for a given credential, like
+    /// `FidoDevice::get_assertion`.
+    ///
+    /// Like `make_credential`, this waits for the user for as long as it takes.
+    pub async fn get_assertion(
+        &mut self,
+        credential: &FidoCredential,
+        client_data_hash: &[u8],
+    ) -> FidoResult<bool> {
+        super::check_request(self.needs_pin, &self.pin_token, client_data_hash)?;
+        if self.u2f {
+            let request = super::u2f_authenticate_request(credential, client_data_hash)?;
+            let response = self.u2f_with_presence(&request).await?;
+            return super::u2f_verify_response(credential, client_data_hash, &response);
+        }
+        let pin_auth = super::pin_auth(&self.pin_token, client_data_hash);
+        let allow_list = super::allow_list(credential);
+        let request =
+            super::get_assertion_request(credential, client_data_hash, &allow_list, pin_auth);
+        let response = self.cbor(cbor::Request::GetAssertion(request)).await?;
+        super::verify_assertion(credential, client_data_hash, response)
+    }
+
+    /// Send a U2F request that requires user presence, repeating it until the
+    /// user touches the authenticator. This never gives up by itself, callers
+    /// are expected to bound it by dropping the future.
+    async fn u2f_with_presence(&mut self, apdu: &[u8]) -> FidoResult<Vec<u8>> {
+        let waiting = FidoErrorKind::U2fError(u2f::SW_CONDITIONS_NOT_SATISFIED);
+        loop {
+            match self.u2f(apdu).await {
+                Err(ref err) if err.kind() == waiting => (),
+                result => return result,
+            }
+            time::sleep(U2F_POLL_INTERVAL).await;
+        }
+    }
+
+    async fn u2f(&mut self, apdu: &[u8]) -> FidoResult<Vec<u8>> {
+        let response = self.exchange(CtapCommand::Msg, apdu).await?;
+        u2f::response_data(response)
+    }
+
+    async fn cbor(&mut self, request: cbor::Request<'_>) -> FidoResult<cbor::Response> {
+        let buf = super::encode_cbor(&request)?;
+        let response = self.exchange(CtapCommand::Cbor, &buf).await?;
+        protocol_log!(debug, "response: {}", protocol_log::response(buf[0], &response));
+        super::decode_cbor(&request, response)
+    }
+
+    async fn exchange(&mut self, cmd: CtapCommand, payload: &[u8]) -> FidoResult<Vec<u8>> {
+        if let Some(cancelled) = self.cancelled.take() {
+            // Give the authenticator a chance to answer the cancelled request,
+            // so its response doesn't get mistaken for the response to this one.
+            let _ = time::timeout(CANCEL_TIMEOUT, self.receive(&cancelled)).await;
+        }
+        let cancel = packet::encode_init_packet(
+            self.device.output_report_id(),
+            self.device.output_report_size(),
+            &self.channel_id,
+            &CtapCommand::Cancel,
+            0,
+            &[],
+        )?;
+        let mut attempt = 0;
+        loop {
+            // If this future is dropped before the response arrives, the
+            // request is cancelled.
+            self.cancelled = Some(cmd.clone());
+            let mut pending = PendingRequest {
+                writer: self.writer.clone(),
+                cancel: cancel.clone(),
+                done: false,
+            };
+            let result = match self.send(&cmd, payload).await {
+                Ok(()) => self.receive(&cmd).await,
+                Err(err) => Err(err),
+            };
+            pending.done = true;
+            self.cancelled = None;
+            match result {
+                Err(ref err) if attempt < self.retry_policy.attempts && super::is_transient(err) => {
+                    time::sleep(self.retry_policy.delay(attempt)).await;
+                    attempt += 1;
+                }
+                _ => return result,
+            }
+        }
+    }
+
+    async fn send(&mut self, cmd: &CtapCommand, payload: &[u8]) -> FidoResult<()> {
+        let packets = packet::encode_message(
+            self.device.output_report_id(),
+            self.device.output_report_size(),
+            &self.channel_id,
+            cmd,
+            payload,
+        )?;
+        protocol_log!(
+            trace,
+            "sending {:?} on channel {:02x?}, {} bytes",
+            cmd,
+            self.channel_id,
+            payload.len()
+        );
+        for packet in packets {
+            let device = &mut self.device;
+            future::poll_fn(|cx| device.poll_write_report(cx, &packet))
+                .await
+                .context(FidoErrorKind::WritePacket)?;
+        }
+        Ok(())
+    }
+
+    async fn receive(&mut self, cmd: &CtapCommand) -> FidoResult<Vec<u8>> {
+        let mut reassembler = packet::Reassembler::new(self.channel_id, cmd.clone());
+        loop {
+            match reassembler.push(self.read_packet().await?)? {
+                packet::Received::Message(data) => return Ok(data),
+                packet::Received::Keepalive(status) => {
+                    if let (Some(status), Some(callback)) = (status, self.keepalive.as_mut()) {
+                        callback(status);
+                    }
+                }
+                packet::Received::Nothing => (),
+            }
+        }
+    }
+
+    /// Read the data of an input report carrying a CTAPHID packet, like
+    /// `packet::read_packet`.
+    async fn read_packet(&mut self) -> FidoResult<Vec<u8>> {
+        let report_id = self.device.input_report_id();
+        let mut buf = vec![0; self.device.input_report_size() + report_id.is_some() as usize];
+        loop {
+            let device = &mut self.device;
+            let read = match future::poll_fn(|cx| device.poll_read_report(cx, &mut buf)).await {
+                Err(ref err) if err.kind() == io::ErrorKind::TimedOut => {
+                    Err(FidoErrorKind::Timeout)?
+                }
+                result => result.context(FidoErrorKind::ReadPacket)?,
+            };
+            if packet::check_report(report_id, &mut buf, read, 5)? {
+                return Ok(buf);
+            }
+        }
+    }
+}
+
+/// Sends CTAPHID_CANCEL when dropped, unless the request it guards is done.
+/// This is how dropping the future of a pending request cancels it.
+struct PendingRequest {
+    writer: Option<Arc<Mutex<Box<dyn ReportWriter>>>>,
+    cancel: Vec<u8>,
+    done: bool,
+}
+
+impl Drop for PendingRequest {
+    fn drop(&mut self) {
+        if self.done {
+            return;
+        }
+        if let Some(ref writer) = self.writer {
+            // If this fails the authenticator will time out by itself.
+            let _ = writer.lock().unwrap().write_report(&self.cancel);
+        }
+    }
+}
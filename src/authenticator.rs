@@ -0,0 +1,933 @@
+// This file is part of ctap, a Rust implementation of the FIDO2 protocol.
+// Copyright (c) Ariën Holthuizen <contact@ardaxi.com>
+// Licensed under the Apache License, Version 2.0, <LICENSE-APACHE or
+// http://apache.org/licenses/LICENSE-2.0> or the MIT license <LICENSE-MIT or
+// http://opensource.org/licenses/MIT>, at your option. This file may not be
+// copied, modified, or distributed except according to those terms.
+//! A software CTAP2 authenticator, meant for testing code that talks to
+//! authenticators without needing a physical one.
+use std::collections::BTreeMap;
+use std::io::Cursor;
+
+use byteorder::{BigEndian, ByteOrder};
+use cbor_codec::value::{self, Key, Value};
+use cbor_codec::{Config, EncodeResult, Encoder, GenericDecoder};
+use ring::error::Unspecified;
+use ring::rand::{SecureRandom, SystemRandom};
+use ring::{agreement, constant_time, digest, hmac, signature};
+use rust_crypto::aes;
+use rust_crypto::blockmodes::NoPadding;
+use rust_crypto::buffer::{RefReadBuffer, RefWriteBuffer};
+use untrusted::Input;
+
+static CTAP2_OK: u8 = 0x00;
+static CTAP1_ERR_INVALID_COMMAND: u8 = 0x01;
+static CTAP1_ERR_INVALID_PARAMETER: u8 = 0x02;
+static CTAP1_ERR_INVALID_LENGTH: u8 = 0x03;
+static CTAP2_ERR_CBOR_UNEXPECTED_TYPE: u8 = 0x11;
+static CTAP2_ERR_INVALID_CBOR: u8 = 0x12;
+static CTAP2_ERR_MISSING_PARAMETER: u8 = 0x14;
+static CTAP2_ERR_CREDENTIAL_EXCLUDED: u8 = 0x19;
+static CTAP2_ERR_UNSUPPORTED_ALGORITHM: u8 = 0x26;
+static CTAP2_ERR_OPERATION_DENIED: u8 = 0x27;
+static CTAP2_ERR_UNSUPPORTED_OPTION: u8 = 0x2b;
+static CTAP2_ERR_INVALID_OPTION: u8 = 0x2c;
+static CTAP2_ERR_NO_CREDENTIALS: u8 = 0x2e;
+static CTAP2_ERR_NOT_ALLOWED: u8 = 0x30;
+static CTAP2_ERR_PIN_INVALID: u8 = 0x31;
+static CTAP2_ERR_PIN_BLOCKED: u8 = 0x32;
+static CTAP2_ERR_PIN_AUTH_INVALID: u8 = 0x33;
+static CTAP2_ERR_PIN_AUTH_BLOCKED: u8 = 0x34;
+static CTAP2_ERR_PIN_NOT_SET: u8 = 0x35;
+static CTAP2_ERR_PIN_REQUIRED: u8 = 0x36;
+static CTAP2_ERR_PIN_POLICY_VIOLATION: u8 = 0x37;
+static CTAP1_ERR_OTHER: u8 = 0x7f;
+
+static FLAG_USER_PRESENT: u8 = 0x01;
+static FLAG_USER_VERIFIED: u8 = 0x04;
+static FLAG_ATTESTED_CREDENTIAL: u8 = 0x40;
+
+static COSE_ES256: i64 = -7;
+static COSE_ECDH_ES_HKDF_256: i32 = -25;
+static MAX_MSG_SIZE: u16 = 1200;
+static MIN_PIN_LENGTH: usize = 4;
+// The number of wrong PINs that can be entered before the authenticator has
+// to be power cycled.
+static MAX_CONSECUTIVE_PIN_FAILURES: u8 = 3;
+
+type Params = BTreeMap<Key, Value>;
+
+/// The initial state of a `VirtualAuthenticator`.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct AuthenticatorConfig {
+    /// The protocol versions reported by authenticatorGetInfo. Including
+    /// `FIDO_2_1` enables authenticatorSelection.
+    pub versions: Vec<String>,
+    pub aaguid: [u8; 16],
+    /// Whether resident keys can be created.
+    pub resident_keys: bool,
+    /// Whether the authenticator has a built-in form of user verification,
+    /// such as a fingerprint reader. Verification always succeeds.
+    pub user_verification: bool,
+    /// Whether the simulated user touches the authenticator when it asks for
+    /// presence. If not, those requests are denied.
+    pub user_present: bool,
+    /// The PIN that is set on the authenticator, if any.
+    pub pin: Option<String>,
+    /// The number of times a wrong PIN can be entered before the PIN is blocked.
+    pub pin_retries: u8,
+}
+
+impl Default for AuthenticatorConfig {
+    fn default() -> Self {
+        AuthenticatorConfig {
+            versions: vec!["FIDO_2_0".to_string()],
+            aaguid: [0; 16],
+            resident_keys: true,
+            user_verification: true,
+            user_present: true,
+            pin: None,
+            pin_retries: 8,
+        }
+    }
+}
+
+struct Credential {
+    id: Vec<u8>,
+    rp_id: String,
+    user_id: Vec<u8>,
+    user_name: Option<String>,
+    user_display_name: Option<String>,
+    resident: bool,
+    private_key: Vec<u8>,
+}
+
+/// The credentials left to return through authenticatorGetNextAssertion.
+struct PendingAssertions {
+    credentials: Vec<Vec<u8>>,
+    rp_id: String,
+    client_data_hash: Vec<u8>,
+    flags: u8,
+    include_user: bool,
+}
+
+struct KeyAgreementKey {
+    private: agreement::EphemeralPrivateKey,
+    public: [u8; 65],
+}
+
+#[derive(Default)]
+struct RequestOptions {
+    rk: bool,
+    up: Option<bool>,
+    uv: bool,
+}
+
+/// A CTAP2 authenticator implemented in software. Credentials, the PIN and
+/// all other state are kept in memory.
+///
+/// Requests are passed to `handle_request` as they would be sent in a
+/// CTAPHID_CBOR message. To use the authenticator through `FidoDevice`, wrap it
+/// in a `MemoryTransport`.
+pub struct VirtualAuthenticator {
+    config: AuthenticatorConfig,
+    rng: SystemRandom,
+    credentials: Vec<Credential>,
+    sign_count: u32,
+    pin_hash: Option<[u8; 16]>,
+    pin_retries: u8,
+    pin_failures: u8,
+    pin_token: [u8; 16],
+    // ring only implements ephemeral ECDH keys, which are consumed by a single
+    // agreement. The secrets agreed with each platform key are kept so that
+    // platforms can keep using them until the key would be replaced anyway.
+    key_agreement: Option<KeyAgreementKey>,
+    shared_secrets: Vec<([u8; 65], [u8; 32])>,
+    pending_assertions: Option<PendingAssertions>,
+}
+
+impl VirtualAuthenticator {
+    pub fn new(config: AuthenticatorConfig) -> Self {
+        let mut authenticator = VirtualAuthenticator {
+            rng: SystemRandom::new(),
+            credentials: Vec::new(),
+            sign_count: 0,
+            pin_hash: config.pin.as_ref().map(|pin| pin_hash(pin.as_bytes())),
+            pin_retries: config.pin_retries,
+            pin_failures: 0,
+            pin_token: [0; 16],
+            key_agreement: None,
+            shared_secrets: Vec::new(),
+            pending_assertions: None,
+            config,
+        };
+        authenticator.power_cycle();
+        authenticator
+    }
+
+    /// Get the configuration the authenticator was created with.
+    pub fn config(&self) -> &AuthenticatorConfig {
+        &self.config
+    }
+
+    /// Set whether the simulated user touches the authenticator when asked to.
+    pub fn set_user_present(&mut self, present: bool) {
+        self.config.user_present = present;
+    }
+
+    /// The number of credentials stored on the authenticator, both resident and
+    /// non-resident.
+    pub fn credential_count(&self) -> usize {
+        self.credentials.len()
+    }
+
+    /// The number of times a wrong PIN can still be entered.
+    pub fn pin_retries(&self) -> u8 {
+        self.pin_retries
+    }
+
+    /// Whether a PIN is set.
+    pub fn has_pin(&self) -> bool {
+        self.pin_hash.is_some()
+    }
+
+    /// Simulate unplugging the authenticator. This invalidates PIN tokens and
+    /// key agreement keys, and lifts the block after too many wrong PINs.
+    pub fn power_cycle(&mut self) {
+        self.pin_token = self.random();
+        self.pin_failures = 0;
+        self.key_agreement = None;
+        self.shared_secrets.clear();
+        self.pending_assertions = None;
+    }
+
+    /// Handle a CTAP2 request, consisting of a command byte followed by its CBOR
+    /// encoded parameters. Returns the status byte followed by the CBOR encoded
+    /// response.
+    pub fn handle_request(&mut self, request: &[u8]) -> Vec<u8> {
+        let (&command, data) = match request.split_first() {
+            Some(request) => request,
+            None => return vec![CTAP1_ERR_INVALID_LENGTH],
+        };
+        // authenticatorGetNextAssertion only follows another assertion request.
+        let pending_assertions = self.pending_assertions.take();
+        let result = match command {
+            0x01 => parse_params(data).and_then(|params| self.make_credential(&params)),
+            0x02 => parse_params(data).and_then(|params| self.get_assertion(&params)),
+            0x04 => self.get_info(),
+            0x06 => parse_params(data).and_then(|params| self.client_pin(&params)),
+            0x07 => self.reset(),
+            0x08 => self.get_next_assertion(pending_assertions),
+            0x0b if self.supports_selection() => self.user_presence().map(|_| Vec::new()),
+            _ => Err(CTAP1_ERR_INVALID_COMMAND),
+        };
+        match result {
+            Ok(mut response) => {
+                response.insert(0, CTAP2_OK);
+                response
+            }
+            Err(status) => vec![status],
+        }
+    }
+
+    fn supports_selection(&self) -> bool {
+        self.config
+            .versions
+            .iter()
+            .any(|version| version == "FIDO_2_1")
+    }
+
+    fn get_info(&self) -> Result<Vec<u8>, u8> {
+        encode(|encoder| {
+            encoder.object(5)?;
+            encoder.u8(0x01)?; // versions
+            encoder.array(self.config.versions.len())?;
+            for version in &self.config.versions {
+                encoder.text(version)?;
+            }
+            encoder.u8(0x03)?; // aaguid
+            encoder.bytes(&self.config.aaguid)?;
+            encoder.u8(0x04)?; // options
+            encoder.object(4 + self.config.user_verification as usize)?;
+            encoder.text("plat")?;
+            encoder.bool(false)?;
+            encoder.text("rk")?;
+            encoder.bool(self.config.resident_keys)?;
+            encoder.text("clientPin")?;
+            encoder.bool(self.pin_hash.is_some())?;
+            encoder.text("up")?;
+            encoder.bool(true)?;
+            if self.config.user_verification {
+                encoder.text("uv")?;
+                encoder.bool(true)?;
+            }
+            encoder.u8(0x05)?; // maxMsgSize
+            encoder.u16(MAX_MSG_SIZE)?;
+            encoder.u8(0x06)?; // pinProtocols
+            encoder.array(1)?;
+            encoder.u8(0x01)
+        })
+    }
+
+    fn make_credential(&mut self, params: &Params) -> Result<Vec<u8>, u8> {
+        let client_data_hash = bytes(required(param(params, 0x01))?)?;
+        let rp = map(required(param(params, 0x02))?)?;
+        let rp_id = text(required(field(rp, "id"))?)?;
+        let user = map(required(param(params, 0x03))?)?;
+        let user_id = bytes(required(field(user, "id"))?)?;
+        let user_name = field(user, "name").map(text).transpose()?;
+        let user_display_name = field(user, "displayName").map(text).transpose()?;
+        let mut supported = false;
+        for algorithm in array(required(param(params, 0x04))?)? {
+            let algorithm = map(algorithm)?;
+            let cred_type = text(required(field(algorithm, "type"))?)?;
+            let alg = int(required(field(algorithm, "alg"))?)?;
+            supported |= cred_type == "public-key" && alg == COSE_ES256;
+        }
+        if !supported {
+            return Err(CTAP2_ERR_UNSUPPORTED_ALGORITHM);
+        }
+        let options = request_options(param(params, 0x07))?;
+        if options.up.is_some() {
+            return Err(CTAP2_ERR_INVALID_OPTION);
+        }
+        if options.rk && !self.config.resident_keys {
+            return Err(CTAP2_ERR_UNSUPPORTED_OPTION);
+        }
+        let verified = self.verify_user(
+            param(params, 0x08),
+            param(params, 0x09),
+            client_data_hash,
+            options.uv,
+        )?;
+        if self.pin_hash.is_some() && !verified {
+            return Err(CTAP2_ERR_PIN_REQUIRED);
+        }
+        if let Some(exclude_list) = param(params, 0x05) {
+            for descriptor in array(exclude_list)? {
+                let id = bytes(required(field(map(descriptor)?, "id"))?)?;
+                if self.find_credential(rp_id, id).is_some() {
+                    self.user_presence()?;
+                    return Err(CTAP2_ERR_CREDENTIAL_EXCLUDED);
+                }
+            }
+        }
+        self.user_presence()?;
+
+        let pkcs8 = signature::ECDSAKeyPair::generate_pkcs8(
+            &signature::ECDSA_P256_SHA256_ASN1_SIGNING,
+            &self.rng,
+        )
+        .map_err(|_| CTAP1_ERR_OTHER)?;
+        let id: [u8; 32] = self.random();
+        if options.rk {
+            // A new resident key replaces the one for the same account.
+            self.credentials.retain(|credential| {
+                !(credential.resident && credential.rp_id == rp_id && credential.user_id == user_id)
+            });
+        }
+        let credential = Credential {
+            id: id.to_vec(),
+            rp_id: rp_id.to_string(),
+            user_id: user_id.to_vec(),
+            user_name: user_name.map(str::to_string),
+            user_display_name: user_display_name.map(str::to_string),
+            resident: options.rk,
+            private_key: pkcs8.as_ref().to_vec(),
+        };
+        let mut flags = FLAG_USER_PRESENT | FLAG_ATTESTED_CREDENTIAL;
+        if verified {
+            flags |= FLAG_USER_VERIFIED;
+        }
+        self.sign_count += 1;
+        let mut auth_data = authenticator_data(rp_id, flags, self.sign_count);
+        auth_data.extend_from_slice(&self.config.aaguid);
+        auth_data.extend_from_slice(&[0; 2]);
+        BigEndian::write_u16(&mut auth_data[(37 + 16)..], credential.id.len() as u16);
+        auth_data.extend_from_slice(&credential.id);
+        // The public key makes up the end of the PKCS#8 document.
+        let public_key = &pkcs8.as_ref()[(pkcs8.as_ref().len() - 65)..];
+        auth_data.extend(encode(|encoder| {
+            encode_cose_key(encoder, public_key, COSE_ES256 as i32)
+        })?);
+        // Self attestation, signed with the credential's own key.
+        let signature = self.sign(&credential, &auth_data, client_data_hash)?;
+        self.credentials.push(credential);
+        encode(|encoder| {
+            encoder.object(3)?;
+            encoder.u8(0x01)?; // fmt
+            encoder.text("packed")?;
+            encoder.u8(0x02)?; // authData
+            encoder.bytes(&auth_data)?;
+            encoder.u8(0x03)?; // attStmt
+            encoder.object(2)?;
+            encoder.text("alg")?;
+            encoder.i64(COSE_ES256)?;
+            encoder.text("sig")?;
+            encoder.bytes(&signature)
+        })
+    }
+
+    fn get_assertion(&mut self, params: &Params) -> Result<Vec<u8>, u8> {
+        let rp_id = text(required(param(params, 0x01))?)?;
+        let client_data_hash = bytes(required(param(params, 0x02))?)?;
+        let options = request_options(param(params, 0x05))?;
+        if options.rk {
+            return Err(CTAP2_ERR_INVALID_OPTION);
+        }
+        let verified = self.verify_user(
+            param(params, 0x06),
+            param(params, 0x07),
+            client_data_hash,
+            options.uv,
+        )?;
+        let allow_list = match param(params, 0x03) {
+            Some(allow_list) => array(allow_list)?,
+            None => &[],
+        };
+        let mut credentials = Vec::new();
+        for descriptor in allow_list {
+            let id = bytes(required(field(map(descriptor)?, "id"))?)?;
+            if let Some(credential) = self.find_credential(rp_id, id) {
+                credentials.push(credential.id.clone());
+            }
+        }
+        let include_user = allow_list.is_empty();
+        if include_user {
+            // Resident keys are returned most recent first.
+            credentials.extend(
+                self.credentials
+                    .iter()
+                    .rev()
+                    .filter(|credential| credential.resident && credential.rp_id == rp_id)
+                    .map(|credential| credential.id.clone()),
+            );
+        }
+        if credentials.is_empty() {
+            return Err(CTAP2_ERR_NO_CREDENTIALS);
+        }
+        let mut flags = 0;
+        if options.up.unwrap_or(true) {
+            self.user_presence()?;
+            flags |= FLAG_USER_PRESENT;
+        }
+        if verified {
+            flags |= FLAG_USER_VERIFIED;
+        }
+        let count = credentials.len();
+        let mut pending = PendingAssertions {
+            credentials,
+            rp_id: rp_id.to_string(),
+            client_data_hash: client_data_hash.to_vec(),
+            flags,
+            include_user,
+        };
+        let response = self.next_assertion(&mut pending, if include_user { count } else { 1 })?;
+        if !pending.credentials.is_empty() {
+            self.pending_assertions = Some(pending);
+        }
+        Ok(response)
+    }
+
+    fn get_next_assertion(&mut self, pending: Option<PendingAssertions>) -> Result<Vec<u8>, u8> {
+        let mut pending = match pending {
+            Some(pending) => pending,
+            None => return Err(CTAP2_ERR_NOT_ALLOWED),
+        };
+        let response = self.next_assertion(&mut pending, 1)?;
+        if !pending.credentials.is_empty() {
+            self.pending_assertions = Some(pending);
+        }
+        Ok(response)
+    }
+
+    /// Sign an assertion with the first of the pending credentials. The number
+    /// of credentials is only included if it is more than one.
+    fn next_assertion(
+        &mut self,
+        pending: &mut PendingAssertions,
+        count: usize,
+    ) -> Result<Vec<u8>, u8> {
+        let id = pending.credentials.remove(0);
+        self.sign_count += 1;
+        let auth_data = authenticator_data(&pending.rp_id, pending.flags, self.sign_count);
+        let credential = self
+            .credentials
+            .iter()
+            .find(|credential| credential.id == id)
+            .ok_or(CTAP2_ERR_NO_CREDENTIALS)?;
+        let signature = self.sign(credential, &auth_data, &pending.client_data_hash)?;
+        // Only identifying information the user has been verified for is returned.
+        let user_details = pending.flags & FLAG_USER_VERIFIED != 0;
+        let user_name = credential.user_name.as_ref().filter(|_| user_details);
+        let user_display_name = credential
+            .user_display_name
+            .as_ref()
+            .filter(|_| user_details);
+        encode(|encoder| {
+            encoder.object(3 + pending.include_user as usize + (count > 1) as usize)?;
+            encoder.u8(0x01)?; // credential
+            encoder.object(2)?;
+            encoder.text("id")?;
+            encoder.bytes(&credential.id)?;
+            encoder.text("type")?;
+            encoder.text("public-key")?;
+            encoder.u8(0x02)?; // authData
+            encoder.bytes(&auth_data)?;
+            encoder.u8(0x03)?; // signature
+            encoder.bytes(&signature)?;
+            if pending.include_user {
+                encoder.u8(0x04)?; // user
+                encoder.object(
+                    1 + user_name.is_some() as usize + user_display_name.is_some() as usize,
+                )?;
+                encoder.text("id")?;
+                encoder.bytes(&credential.user_id)?;
+                if let Some(name) = user_name {
+                    encoder.text("name")?;
+                    encoder.text(name)?;
+                }
+                if let Some(display_name) = user_display_name {
+                    encoder.text("displayName")?;
+                    encoder.text(display_name)?;
+                }
+            }
+            if count > 1 {
+                encoder.u8(0x05)?; // numberOfCredentials
+                encoder.u64(count as u64)?;
+            }
+            Ok(())
+        })
+    }
+
+    fn client_pin(&mut self, params: &Params) -> Result<Vec<u8>, u8> {
+        if int(required(param(params, 0x01))?)? != 1 {
+            return Err(CTAP1_ERR_INVALID_PARAMETER);
+        }
+        match int(required(param(params, 0x02))?)? {
+            0x01 => encode(|encoder| {
+                encoder.object(1)?;
+                encoder.u8(0x03)?; // retries
+                encoder.u8(self.pin_retries)
+            }),
+            0x02 => {
+                let public_key = self.key_agreement_key()?.public;
+                encode(|encoder| {
+                    encoder.object(1)?;
+                    encoder.u8(0x01)?; // keyAgreement
+                    encode_cose_key(encoder, &public_key, COSE_ECDH_ES_HKDF_256)
+                })
+            }
+            0x03 => self.set_pin(params).map(|_| Vec::new()),
+            0x04 => self.change_pin(params).map(|_| Vec::new()),
+            0x05 => {
+                let shared_secret = self.check_pin(params)?;
+                let pin_token = encrypt(&shared_secret, &self.pin_token)?;
+                encode(|encoder| {
+                    encoder.object(1)?;
+                    encoder.u8(0x02)?; // pinToken
+                    encoder.bytes(&pin_token)
+                })
+            }
+            _ => Err(CTAP1_ERR_INVALID_PARAMETER),
+        }
+    }
+
+    fn set_pin(&mut self, params: &Params) -> Result<(), u8> {
+        let new_pin_enc = bytes(required(param(params, 0x05))?)?;
+        let pin_auth = bytes(required(param(params, 0x04))?)?;
+        if self.pin_hash.is_some() {
+            return Err(CTAP2_ERR_NOT_ALLOWED);
+        }
+        let shared_secret = self.shared_secret(params)?;
+        verify_pin_auth(&shared_secret, new_pin_enc, pin_auth)?;
+        self.pin_hash = Some(decrypt_pin(&shared_secret, new_pin_enc)?);
+        self.pin_retries = self.config.pin_retries;
+        Ok(())
+    }
+
+    fn change_pin(&mut self, params: &Params) -> Result<(), u8> {
+        let new_pin_enc = bytes(required(param(params, 0x05))?)?;
+        let pin_hash_enc = bytes(required(param(params, 0x06))?)?;
+        let pin_auth = bytes(required(param(params, 0x04))?)?;
+        let shared_secret = self.shared_secret(params)?;
+        let mut data = new_pin_enc.to_vec();
+        data.extend_from_slice(pin_hash_enc);
+        verify_pin_auth(&shared_secret, &data, pin_auth)?;
+        self.check_pin(params)?;
+        self.pin_hash = Some(decrypt_pin(&shared_secret, new_pin_enc)?);
+        self.pin_token = self.random();
+        Ok(())
+    }
+
+    /// Check the encrypted PIN hash in a request against the PIN, returning the
+    /// secret shared with the platform.
+    fn check_pin(&mut self, params: &Params) -> Result<[u8; 32], u8> {
+        let pin_hash_enc = bytes(required(param(params, 0x06))?)?;
+        let shared_secret = self.shared_secret(params)?;
+        let expected = self.pin_hash.ok_or(CTAP2_ERR_PIN_NOT_SET)?;
+        if self.pin_retries == 0 {
+            return Err(CTAP2_ERR_PIN_BLOCKED);
+        }
+        if self.pin_failures >= MAX_CONSECUTIVE_PIN_FAILURES {
+            return Err(CTAP2_ERR_PIN_AUTH_BLOCKED);
+        }
+        self.pin_retries -= 1;
+        let pin_hash = decrypt(&shared_secret, pin_hash_enc)?;
+        if constant_time::verify_slices_are_equal(&pin_hash, &expected).is_err() {
+            // The platform has to start over with a new key agreement key.
+            self.key_agreement = None;
+            self.shared_secrets.clear();
+            self.pin_failures += 1;
+            return Err(if self.pin_retries == 0 {
+                CTAP2_ERR_PIN_BLOCKED
+            } else if self.pin_failures >= MAX_CONSECUTIVE_PIN_FAILURES {
+                CTAP2_ERR_PIN_AUTH_BLOCKED
+            } else {
+                CTAP2_ERR_PIN_INVALID
+            });
+        }
+        self.pin_retries = self.config.pin_retries;
+        self.pin_failures = 0;
+        Ok(shared_secret)
+    }
+
+    fn key_agreement_key(&mut self) -> Result<&KeyAgreementKey, u8> {
+        if self.key_agreement.is_none() {
+            let private =
+                agreement::EphemeralPrivateKey::generate(&agreement::ECDH_P256, &self.rng)
+                    .map_err(|_| CTAP1_ERR_OTHER)?;
+            let mut public = [0; 65];
+            private
+                .compute_public_key(&mut public)
+                .map_err(|_| CTAP1_ERR_OTHER)?;
+            self.key_agreement = Some(KeyAgreementKey { private, public });
+        }
+        Ok(self.key_agreement.as_ref().unwrap())
+    }
+
+    /// Agree on a secret with the platform key in a ClientPIN request.
+    fn shared_secret(&mut self, params: &Params) -> Result<[u8; 32], u8> {
+        let platform_key = cose_public_key(map(required(param(params, 0x03))?)?)?;
+        if let Some(&(_, secret)) = self
+            .shared_secrets
+            .iter()
+            .find(|(key, _)| *key == platform_key)
+        {
+            return Ok(secret);
+        }
+        let key = self
+            .key_agreement
+            .take()
+            .ok_or(CTAP2_ERR_PIN_AUTH_INVALID)?;
+        let digest = agreement::agree_ephemeral(
+            key.private,
+            &agreement::ECDH_P256,
+            Input::from(&platform_key),
+            Unspecified,
+            |material| Ok(digest::digest(&digest::SHA256, material)),
+        )
+        .map_err(|_| CTAP1_ERR_INVALID_PARAMETER)?;
+        let mut secret = [0; 32];
+        secret.copy_from_slice(digest.as_ref());
+        self.shared_secrets.push((platform_key, secret));
+        Ok(secret)
+    }
+
+    /// Check the pinAuth of a MakeCredential or GetAssertion request, and
+    /// perform built-in user verification if the platform asks for it. Returns
+    /// whether the user is verified.
+    fn verify_user(
+        &mut self,
+        pin_auth: Option<&Value>,
+        pin_protocol: Option<&Value>,
+        client_data_hash: &[u8],
+        uv: bool,
+    ) -> Result<bool, u8> {
+        let pin_auth = match pin_auth {
+            Some(pin_auth) => pin_auth,
+            None => {
+                if uv && !self.config.user_verification {
+                    return Err(CTAP2_ERR_UNSUPPORTED_OPTION);
+                }
+                return Ok(uv);
+            }
+        };
+        let pin_auth = bytes(pin_auth)?;
+        if pin_auth.is_empty() {
+            // Platforms use this to have the user pick one of several
+            // authenticators.
+            self.user_presence()?;
+            return Err(if self.pin_hash.is_some() {
+                CTAP2_ERR_PIN_INVALID
+            } else {
+                CTAP2_ERR_PIN_NOT_SET
+            });
+        }
+        if int(required(pin_protocol)?)? != 1 {
+            return Err(CTAP2_ERR_PIN_AUTH_INVALID);
+        }
+        if self.pin_hash.is_none() {
+            return Err(CTAP2_ERR_PIN_NOT_SET);
+        }
+        verify_pin_auth(&self.pin_token, client_data_hash, pin_auth)?;
+        Ok(true)
+    }
+
+    fn reset(&mut self) -> Result<Vec<u8>, u8> {
+        self.user_presence()?;
+        self.credentials.clear();
+        self.pin_hash = None;
+        self.pin_retries = self.config.pin_retries;
+        self.power_cycle();
+        Ok(Vec::new())
+    }
+
+    fn user_presence(&self) -> Result<(), u8> {
+        if self.config.user_present {
+            Ok(())
+        } else {
+            Err(CTAP2_ERR_OPERATION_DENIED)
+        }
+    }
+
+    fn find_credential(&self, rp_id: &str, id: &[u8]) -> Option<&Credential> {
+        self.credentials
+            .iter()
+            .find(|credential| credential.rp_id == rp_id && credential.id == id)
+    }
+
+    fn sign(
+        &self,
+        credential: &Credential,
+        auth_data: &[u8],
+        client_data_hash: &[u8],
+    ) -> Result<Vec<u8>, u8> {
+        let key = signature::ECDSAKeyPair::from_pkcs8(
+            &signature::ECDSA_P256_SHA256_ASN1_SIGNING,
+            Input::from(&credential.private_key),
+        )
+        .map_err(|_| CTAP1_ERR_OTHER)?;
+        let mut msg = auth_data.to_vec();
+        msg.extend_from_slice(client_data_hash);
+        let signature = key
+            .sign(Input::from(&msg), &self.rng)
+            .map_err(|_| CTAP1_ERR_OTHER)?;
+        Ok(signature.as_ref().to_vec())
+    }
+
+    fn random<T: AsMut<[u8]> + Default>(&self) -> T {
+        let mut bytes = T::default();
+        self.rng
+            .fill(bytes.as_mut())
+            .expect("system random number generator failed");
+        bytes
+    }
+}
+
+impl Default for VirtualAuthenticator {
+    fn default() -> Self {
+        VirtualAuthenticator::new(AuthenticatorConfig::default())
+    }
+}
+
+fn authenticator_data(rp_id: &str, flags: u8, sign_count: u32) -> Vec<u8> {
+    let mut data = digest::digest(&digest::SHA256, rp_id.as_bytes())
+        .as_ref()
+        .to_vec();
+    data.push(flags);
+    data.extend_from_slice(&[0; 4]);
+    BigEndian::write_u32(&mut data[33..], sign_count);
+    data
+}
+
+fn pin_hash(pin: &[u8]) -> [u8; 16] {
+    let mut hash = [0; 16];
+    hash.copy_from_slice(&digest::digest(&digest::SHA256, pin).as_ref()[0..16]);
+    hash
+}
+
+fn verify_pin_auth(key: &[u8], data: &[u8], pin_auth: &[u8]) -> Result<(), u8> {
+    let signature = hmac::sign(&hmac::SigningKey::new(&digest::SHA256, key), data);
+    constant_time::verify_slices_are_equal(&signature.as_ref()[0..16], pin_auth)
+        .map_err(|_| CTAP2_ERR_PIN_AUTH_INVALID)
+}
+
+/// Decrypt a new PIN, which is padded with zeroes to at least 64 bytes, and
+/// return its hash.
+fn decrypt_pin(shared_secret: &[u8; 32], new_pin_enc: &[u8]) -> Result<[u8; 16], u8> {
+    if new_pin_enc.len() < 64 {
+        return Err(CTAP1_ERR_INVALID_PARAMETER);
+    }
+    let padded = decrypt(shared_secret, new_pin_enc)?;
+    let pin = padded.split(|&byte| byte == 0).next().unwrap_or(&[]);
+    if pin.len() < MIN_PIN_LENGTH || pin.len() > 63 {
+        return Err(CTAP2_ERR_PIN_POLICY_VIOLATION);
+    }
+    Ok(pin_hash(pin))
+}
+
+fn encrypt(shared_secret: &[u8; 32], data: &[u8]) -> Result<Vec<u8>, u8> {
+    let mut encryptor =
+        aes::cbc_encryptor(aes::KeySize::KeySize256, shared_secret, &[0; 16], NoPadding);
+    let mut output = vec![0; data.len()];
+    encryptor
+        .encrypt(
+            &mut RefReadBuffer::new(data),
+            &mut RefWriteBuffer::new(&mut output),
+            true,
+        )
+        .map_err(|_| CTAP1_ERR_INVALID_PARAMETER)?;
+    Ok(output)
+}
+
+fn decrypt(shared_secret: &[u8; 32], data: &[u8]) -> Result<Vec<u8>, u8> {
+    if data.is_empty() || data.len() % 16 != 0 {
+        return Err(CTAP1_ERR_INVALID_PARAMETER);
+    }
+    let mut decryptor =
+        aes::cbc_decryptor(aes::KeySize::KeySize256, shared_secret, &[0; 16], NoPadding);
+    let mut output = vec![0; data.len()];
+    decryptor
+        .decrypt(
+            &mut RefReadBuffer::new(data),
+            &mut RefWriteBuffer::new(&mut output),
+            true,
+        )
+        .map_err(|_| CTAP1_ERR_INVALID_PARAMETER)?;
+    Ok(output)
+}
+
+fn encode_cose_key(
+    encoder: &mut Encoder<Vec<u8>>,
+    public_key: &[u8],
+    algorithm: i32,
+) -> EncodeResult {
+    encoder.object(5)?;
+    encoder.u8(0x01)?; // kty: EC2
+    encoder.u8(0x02)?;
+    encoder.u8(0x03)?; // alg
+    encoder.i32(algorithm)?;
+    encoder.i8(-1)?; // crv: P-256
+    encoder.u8(0x01)?;
+    encoder.i8(-2)?; // x
+    encoder.bytes(&public_key[1..33])?;
+    encoder.i8(-3)?; // y
+    encoder.bytes(&public_key[33..65])
+}
+
+/// Get the uncompressed P-256 point of a COSE key.
+fn cose_public_key(key: &BTreeMap<Key, Value>) -> Result<[u8; 65], u8> {
+    let x = bytes(required(key.get(&Key::i64(-2)))?)?;
+    let y = bytes(required(key.get(&Key::i64(-3)))?)?;
+    if int(required(key.get(&Key::u64(0x01)))?)? != 2
+        || int(required(key.get(&Key::i64(-1)))?)? != 1
+        || x.len() != 32
+        || y.len() != 32
+    {
+        return Err(CTAP1_ERR_INVALID_PARAMETER);
+    }
+    let mut point = [0; 65];
+    point[0] = 0x04;
+    point[1..33].copy_from_slice(x);
+    point[33..65].copy_from_slice(y);
+    Ok(point)
+}
+
+fn encode<F>(f: F) -> Result<Vec<u8>, u8>
+where
+    F: FnOnce(&mut Encoder<Vec<u8>>) -> EncodeResult,
+{
+    let mut encoder = Encoder::new(Vec::new());
+    f(&mut encoder).map_err(|_| CTAP1_ERR_OTHER)?;
+    Ok(encoder.into_writer())
+}
+
+fn parse_params(data: &[u8]) -> Result<Params, u8> {
+    if data.is_empty() {
+        return Err(CTAP2_ERR_MISSING_PARAMETER);
+    }
+    let mut decoder = GenericDecoder::new(Config::default(), Cursor::new(data));
+    match decoder.value() {
+        Ok(Value::Map(params)) => Ok(params),
+        Ok(_) => Err(CTAP2_ERR_CBOR_UNEXPECTED_TYPE),
+        Err(_) => Err(CTAP2_ERR_INVALID_CBOR),
+    }
+}
+
+fn request_options(options: Option<&Value>) -> Result<RequestOptions, u8> {
+    let mut parsed = RequestOptions::default();
+    let options = match options {
+        Some(options) => map(options)?,
+        None => return Ok(parsed),
+    };
+    for (key, value) in options {
+        match key {
+            Key::Text(value::Text::Text(key)) if key == "rk" => parsed.rk = boolean(value)?,
+            Key::Text(value::Text::Text(key)) if key == "up" => parsed.up = Some(boolean(value)?),
+            Key::Text(value::Text::Text(key)) if key == "uv" => parsed.uv = boolean(value)?,
+            _ => continue,
+        }
+    }
+    Ok(parsed)
+}
+
+fn param(params: &Params, key: u64) -> Option<&Value> {
+    params.get(&Key::u64(key))
+}
+
+fn field<'a>(map: &'a BTreeMap<Key, Value>, name: &str) -> Option<&'a Value> {
+    map.get(&Key::Text(value::Text::Text(name.to_string())))
+}
+
+fn required(value: Option<&Value>) -> Result<&Value, u8> {
+    value.ok_or(CTAP2_ERR_MISSING_PARAMETER)
+}
+
+fn int(value: &Value) -> Result<i64, u8> {
+    match *value {
+        Value::U8(n) => Ok(i64::from(n)),
+        Value::U16(n) => Ok(i64::from(n)),
+        Value::U32(n) => Ok(i64::from(n)),
+        Value::I8(n) => Ok(i64::from(n)),
+        Value::I16(n) => Ok(i64::from(n)),
+        Value::I32(n) => Ok(i64::from(n)),
+        Value::I64(n) => Ok(n),
+        _ => Err(CTAP2_ERR_CBOR_UNEXPECTED_TYPE),
+    }
+}
+
+fn bytes(value: &Value) -> Result<&[u8], u8> {
+    match value {
+        Value::Bytes(value::Bytes::Bytes(bytes)) => Ok(bytes),
+        _ => Err(CTAP2_ERR_CBOR_UNEXPECTED_TYPE),
+    }
+}
+
+fn text(value: &Value) -> Result<&str, u8> {
+    match value {
+        Value::Text(value::Text::Text(text)) => Ok(text),
+        _ => Err(CTAP2_ERR_CBOR_UNEXPECTED_TYPE),
+    }
+}
+
+fn map(value: &Value) -> Result<&BTreeMap<Key, Value>, u8> {
+    match value {
+        Value::Map(map) => Ok(map),
+        _ => Err(CTAP2_ERR_CBOR_UNEXPECTED_TYPE),
+    }
+}
+
+fn array(value: &Value) -> Result<&[Value], u8> {
+    match value {
+        Value::Array(array) => Ok(array),
+        _ => Err(CTAP2_ERR_CBOR_UNEXPECTED_TYPE),
+    }
+}
+
+fn boolean(value: &Value) -> Result<bool, u8> {
+    match *value {
+        Value::Bool(value) => Ok(value),
+        _ => Err(CTAP2_ERR_CBOR_UNEXPECTED_TYPE),
+    }
+}
@@ -21,6 +21,7 @@ pub enum Request<'a> {
     GetAssertion(GetAssertionRequest<'a>),
     GetInfo,
     ClientPin(ClientPinRequest<'a>),
+    Selection,
 }
 
 impl<'a> Request<'a> {
@@ -37,10 +38,17 @@ impl<'a> Request<'a> {
                     .map_err(From::from)
             }
             Request::ClientPin(req) => req.encode(&mut encoder),
+            Request::Selection => {
+                encoder
+                    .writer()
+                    .write_u8(0x0b)
+                    .context(FidoErrorKind::CborEncode)
+                    .map_err(From::from)
+            }
         }
     }
 
-    pub fn decode<R: ReadBytesExt>(&self, reader: R) -> FidoResult<Response> {
+    pub fn decode<R: ReadBytesExt>(&self, mut reader: R) -> FidoResult<Response> {
         Ok(match self {
             Request::MakeCredential(_) => Response::MakeCredential(
                 MakeCredentialResponse::decode(reader)?,
@@ -50,6 +58,13 @@ impl<'a> Request<'a> {
             ),
             Request::GetInfo => Response::GetInfo(GetInfoResponse::decode(reader)?),
             Request::ClientPin(_) => Response::ClientPin(ClientPinResponse::decode(reader)?),
+            Request::Selection => {
+                let status = reader.read_u8().context(FidoErrorKind::CborDecode)?;
+                if status != 0 {
+                    Err(FidoErrorKind::CborError(status))?
+                }
+                Response::Selection
+            }
         })
     }
 }
@@ -60,6 +75,7 @@ pub enum Response {
     GetAssertion(GetAssertionResponse),
     GetInfo(GetInfoResponse),
     ClientPin(ClientPinResponse),
+    Selection,
 }
 
 #[derive(Default, Debug)]
@@ -71,7 +87,7 @@ pub struct MakeCredentialRequest<'a> {
     pub exclude_list: &'a [PublicKeyCredentialDescriptor],
     pub extensions: &'a [(&'a str, &'a Value)],
     pub options: Option<AuthenticatorOptions>,
-    pub pin_auth: Option<[u8; 16]>,
+    pub pin_auth: Option<&'a [u8]>,
     pub pin_protocol: Option<u8>,
 }
 
@@ -0,0 +1,390 @@
+// This file is part of ctap, a Rust implementation of the FIDO2 protocol.
+// Copyright (c) Ariën Holthuizen <contact@ardaxi.com>
+// Licensed under the Apache License, Version 2.0, <LICENSE-APACHE or
+// http://apache.org/licenses/LICENSE-2.0> or the MIT license <LICENSE-MIT or
+// http://opensource.org/licenses/MIT>, at your option. This file may not be
+// copied, modified, or distributed except according to those terms.
+//! The authenticator side of CTAPHID, which turns the packets written by hosts
+//! back into messages and frames the responses.
+use std::collections::{HashSet, VecDeque};
+use std::time::{Duration, Instant};
+
+use byteorder::{BigEndian, ByteOrder};
+
+use super::error::*;
+use super::packet::{
+    self, ContPacket, CtapCommand, CtapError, DeviceCapabilities, InitPacket, KeepaliveStatus,
+    FRAME_INIT,
+};
+use super::BROADCAST_CID;
+
+static CTAPHID_PROTOCOL_VERSION: u8 = 2;
+static MAX_LOCK_SECONDS: u8 = 10;
+
+/// How long a host may take between two packets of the same message.
+const DEFAULT_MESSAGE_TIMEOUT: Duration = Duration::from_millis(500);
+
+/// A message received from a host, which the authenticator has to answer
+/// through `ChannelManager::respond` or `ChannelManager::error`.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Message {
+    pub cid: [u8; 4],
+    pub cmd: CtapCommand,
+    pub payload: Vec<u8>,
+}
+
+enum Transaction {
+    /// A message of which continuation packets are still expected.
+    Receiving {
+        packet: InitPacket,
+        seq: u8,
+        last_packet: Instant,
+    },
+    /// A message that has been received but not yet answered.
+    Processing { cid: [u8; 4] },
+}
+
+/// Keeps track of the channels of a CTAPHID authenticator.
+///
+/// Reports written by hosts are passed to `handle_report`, which returns the
+/// messages that have to be handled by the authenticator. INIT, PING and LOCK
+/// are handled here, as are framing errors. Only one message is handled at a
+/// time, hosts on other channels are told that the authenticator is busy until
+/// it has been answered. The reports to send back are queued and taken with
+/// `next_report`.
+pub struct ChannelManager {
+    input_report_size: usize,
+    output_report_size: usize,
+    capabilities: DeviceCapabilities,
+    version: [u8; 3],
+    channels: HashSet<[u8; 4]>,
+    next_cid: u32,
+    transaction: Option<Transaction>,
+    lock: Option<([u8; 4], Instant)>,
+    message_timeout: Duration,
+    reports: VecDeque<Vec<u8>>,
+}
+
+impl ChannelManager {
+    /// Create a manager for an authenticator with reports of `report_size`
+    /// bytes, not counting the report ID, which advertises the given
+    /// capabilities in its response to INIT.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `report_size` is less than 8 bytes, which doesn't fit an
+    /// initialization packet header and a byte of payload.
+    pub fn new(report_size: usize, capabilities: DeviceCapabilities) -> Self {
+        ChannelManager::with_report_sizes(report_size, report_size, capabilities)
+    }
+
+    /// Create a manager for an authenticator whose input reports, sent to the
+    /// host, and output reports, written by the host, differ in size.
+    ///
+    /// # Panics
+    ///
+    /// Panics if either size is less than 8 bytes.
+    pub fn with_report_sizes(
+        input_report_size: usize,
+        output_report_size: usize,
+        capabilities: DeviceCapabilities,
+    ) -> Self {
+        assert!(
+            input_report_size >= 8 && output_report_size >= 8,
+            "reports must be at least 8 bytes long"
+        );
+        ChannelManager {
+            input_report_size,
+            output_report_size,
+            capabilities,
+            version: [0; 3],
+            channels: HashSet::new(),
+            next_cid: 1,
+            transaction: None,
+            lock: None,
+            message_timeout: DEFAULT_MESSAGE_TIMEOUT,
+            reports: VecDeque::new(),
+        }
+    }
+
+    /// Set the firmware version reported in the response to INIT.
+    pub fn set_version(&mut self, major: u8, minor: u8, build: u8) {
+        self.version = [major, minor, build];
+    }
+
+    /// Set how long a host may take between two packets of the same message
+    /// before it is discarded. Defaults to 500 milliseconds.
+    pub fn set_message_timeout(&mut self, timeout: Duration) {
+        self.message_timeout = timeout;
+    }
+
+    /// The channel whose message is being received or handled, if any.
+    pub fn busy_channel(&self) -> Option<[u8; 4]> {
+        match self.transaction {
+            Some(Transaction::Receiving { ref packet, .. }) => Some(packet.cid),
+            Some(Transaction::Processing { cid }) => Some(cid),
+            None => None,
+        }
+    }
+
+    /// Handle an output report written by a host, without its report ID.
+    /// Returns a message once all of its packets have been received.
+    ///
+    /// While a message is being handled, a CANCEL from the same channel is
+    /// returned as well. The authenticator should abort the request and
+    /// answer it with `CTAP2_ERR_KEEPALIVE_CANCEL`.
+    pub fn handle_report(&mut self, report: &[u8]) -> Option<Message> {
+        if report.len() != self.output_report_size {
+            return None;
+        }
+        self.check_timeout();
+        if report[4] & FRAME_INIT != 0 {
+            self.handle_init_packet(InitPacket::from_report(report.to_vec()))
+        } else {
+            self.handle_cont_packet(report)
+        }
+    }
+
+    /// Discard a partially received message if the host stopped sending it,
+    /// and tell the host. This should be called periodically, the timeout is
+    /// otherwise only checked when a report arrives.
+    pub fn check_timeout(&mut self) {
+        let cid = match self.transaction {
+            Some(Transaction::Receiving {
+                ref packet,
+                last_packet,
+                ..
+            }) if last_packet.elapsed() > self.message_timeout => packet.cid,
+            _ => return,
+        };
+        self.transaction = None;
+        self.error(cid, CtapError::MsgTimeout);
+    }
+
+    /// Queue the response to a message. If this answers the message being
+    /// handled, the authenticator is free to receive the next one.
+    ///
+    /// This fails if the payload doesn't fit in a CTAPHID message.
+    pub fn respond(&mut self, cid: [u8; 4], cmd: CtapCommand, payload: &[u8]) -> FidoResult<()> {
+        if payload.len() > packet::max_message_size(self.input_report_size) {
+            Err(FidoErrorKind::WritePacket)?
+        }
+        if self.busy_channel() == Some(cid) {
+            self.transaction = None;
+        }
+        for report in packet::encode_message(None, self.input_report_size, &cid, &cmd, payload)? {
+            // Input reports of unnumbered devices don't start with a report ID.
+            self.reports.push_back(report[1..].to_vec());
+        }
+        Ok(())
+    }
+
+    /// Answer a message with an error.
+    pub fn error(&mut self, cid: [u8; 4], error: CtapError) {
+        // A single byte always fits.
+        let _ = self.respond(cid, CtapCommand::Error, &[error as u8]);
+    }
+
+    /// Tell the host whose message is being handled that the authenticator is
+    /// still working on it. Does nothing if no message is being handled.
+    pub fn keepalive(&mut self, status: KeepaliveStatus) {
+        if let Some(Transaction::Processing { cid }) = self.transaction {
+            let report = packet::encode_init_packet(
+                None,
+                self.input_report_size,
+                &cid,
+                &CtapCommand::Keepalive,
+                1,
+                &[status as u8],
+            );
+            if let Ok(report) = report {
+                self.reports.push_back(report[1..].to_vec());
+            }
+        }
+    }
+
+    /// Take the next input report to send to the host, without report ID.
+    pub fn next_report(&mut self) -> Option<Vec<u8>> {
+        self.reports.pop_front()
+    }
+
+    fn handle_init_packet(&mut self, packet: InitPacket) -> Option<Message> {
+        let cid = packet.cid;
+        if packet.cmd == CtapCommand::Init {
+            self.init(packet);
+            return None;
+        }
+        if !self.channels.contains(&cid) {
+            self.error(cid, CtapError::InvalidChannel);
+            return None;
+        }
+        if self.locked_by().map_or(false, |owner| owner != cid) {
+            self.error(cid, CtapError::ChannelBusy);
+            return None;
+        }
+        match self.transaction {
+            Some(Transaction::Processing { cid: busy })
+                if busy == cid && packet.cmd == CtapCommand::Cancel =>
+            {
+                return Some(Message {
+                    cid,
+                    cmd: CtapCommand::Cancel,
+                    payload: Vec::new(),
+                });
+            }
+            // There is nothing to cancel.
+            _ if packet.cmd == CtapCommand::Cancel => return None,
+            Some(Transaction::Receiving {
+                packet: ref receiving,
+                ..
+            }) if receiving.cid == cid => {
+                self.transaction = None;
+                self.error(cid, CtapError::InvalidSeq);
+                return None;
+            }
+            Some(_) => {
+                self.error(cid, CtapError::ChannelBusy);
+                return None;
+            }
+            None => (),
+        }
+        if packet.size as usize > self.max_message_size() {
+            self.error(cid, CtapError::InvalidLen);
+            return None;
+        }
+        if packet.payload.len() < packet.size as usize {
+            self.transaction = Some(Transaction::Receiving {
+                packet,
+                seq: 0,
+                last_packet: Instant::now(),
+            });
+            return None;
+        }
+        self.complete(packet)
+    }
+
+    fn handle_cont_packet(&mut self, report: &[u8]) -> Option<Message> {
+        let (mut receiving, seq) = match self.transaction.take() {
+            Some(Transaction::Receiving { packet, seq, .. }) => (packet, seq),
+            // Stray continuation packets are ignored.
+            transaction => {
+                self.transaction = transaction;
+                return None;
+            }
+        };
+        let remaining = receiving.size as usize - receiving.payload.len();
+        let packet = ContPacket::from_report(report.to_vec(), remaining);
+        if packet.cid != receiving.cid {
+            self.transaction = Some(Transaction::Receiving {
+                packet: receiving,
+                seq,
+                last_packet: Instant::now(),
+            });
+            if self.channels.contains(&packet.cid) {
+                self.error(packet.cid, CtapError::ChannelBusy);
+            }
+            return None;
+        }
+        if packet.seq != seq {
+            self.error(packet.cid, CtapError::InvalidSeq);
+            return None;
+        }
+        receiving.payload.extend(packet.payload);
+        if receiving.payload.len() < receiving.size as usize {
+            self.transaction = Some(Transaction::Receiving {
+                packet: receiving,
+                seq: seq + 1,
+                last_packet: Instant::now(),
+            });
+            return None;
+        }
+        self.complete(receiving)
+    }
+
+    fn init(&mut self, packet: InitPacket) {
+        let cid = packet.cid;
+        if packet.size != 8 || packet.payload.len() != 8 {
+            self.error(cid, CtapError::InvalidLen);
+            return;
+        }
+        let channel_id = if cid == BROADCAST_CID {
+            self.allocate_channel()
+        } else if self.channels.contains(&cid) {
+            // Resynchronize the channel, abandoning its current message.
+            if self.busy_channel() == Some(cid) {
+                self.transaction = None;
+            }
+            cid
+        } else {
+            self.error(cid, CtapError::InvalidChannel);
+            return;
+        };
+        let mut response = packet.payload;
+        response.extend_from_slice(&channel_id);
+        response.push(CTAPHID_PROTOCOL_VERSION);
+        response.extend_from_slice(&self.version);
+        response.push(self.capabilities.to_flags());
+        let _ = self.respond(cid, CtapCommand::Init, &response);
+    }
+
+    fn complete(&mut self, packet: InitPacket) -> Option<Message> {
+        let cid = packet.cid;
+        match packet.cmd {
+            CtapCommand::Ping => {
+                let _ = self.respond(cid, CtapCommand::Ping, &packet.payload);
+                None
+            }
+            CtapCommand::Lock => {
+                match packet.payload.first() {
+                    Some(&seconds) if packet.payload.len() == 1 && seconds <= MAX_LOCK_SECONDS => {
+                        self.lock = if seconds == 0 {
+                            None
+                        } else {
+                            Some((
+                                cid,
+                                Instant::now() + Duration::from_secs(u64::from(seconds)),
+                            ))
+                        };
+                        let _ = self.respond(cid, CtapCommand::Lock, &[]);
+                    }
+                    Some(_) if packet.payload.len() == 1 => self.error(cid, CtapError::InvalidPar),
+                    _ => self.error(cid, CtapError::InvalidLen),
+                }
+                None
+            }
+            cmd => {
+                self.transaction = Some(Transaction::Processing { cid });
+                Some(Message {
+                    cid,
+                    cmd,
+                    payload: packet.payload,
+                })
+            }
+        }
+    }
+
+    fn allocate_channel(&mut self) -> [u8; 4] {
+        let mut cid = [0; 4];
+        loop {
+            BigEndian::write_u32(&mut cid, self.next_cid);
+            self.next_cid = self.next_cid.wrapping_add(1);
+            if cid != [0; 4] && cid != BROADCAST_CID && !self.channels.contains(&cid) {
+                break;
+            }
+        }
+        self.channels.insert(cid);
+        cid
+    }
+
+    /// The channel holding the lock, if it hasn't expired.
+    fn locked_by(&mut self) -> Option<[u8; 4]> {
+        if self.lock.map_or(false, |(_, until)| Instant::now() >= until) {
+            self.lock = None;
+        }
+        self.lock.map(|(cid, _)| cid)
+    }
+
+    fn max_message_size(&self) -> usize {
+        packet::max_message_size(self.output_report_size)
+    }
+}
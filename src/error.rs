@@ -49,6 +49,20 @@ pub enum FidoErrorKind {
     DeviceUnsupported,
     #[fail(display = "This operating requires a PIN but none was provided.")]
     PinRequired,
+    #[fail(display = "The operation was cancelled.")]
+    Cancelled,
+    #[fail(display = "Timed out waiting for the device to respond.")]
+    Timeout,
+    #[fail(display = "Device did not echo the ping payload correctly.")]
+    PingMismatch,
+    #[fail(display = "Device does not support this operation.")]
+    CapabilityUnsupported,
+    #[fail(display = "Error while parsing U2F response from device.")]
+    ParseU2f,
+    #[fail(display = "Device returned U2F status: 0x{:04x}", _0)]
+    U2fError(u16),
+    #[fail(display = "No FIDO devices are connected.")]
+    NoDevices,
 }
 
 impl Fail for FidoError {
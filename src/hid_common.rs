@@ -6,11 +6,79 @@
 // copied, modified, or distributed except according to those terms.
 use std::path::PathBuf;
 
+pub static FIDO_USAGE_PAGE: u16 = 0xf1d0;
+pub static FIDO_USAGE: u16 = 0x01;
+
 #[derive(Debug, Clone)]
 /// Storage for device related information
 pub struct DeviceInfo {
     pub path: PathBuf,
+    /// The usage page of the device's top-level collection. For composite
+    /// devices this is the FIDO collection if there is one.
     pub usage_page: u16,
+    /// The usage of the device's top-level collection.
     pub usage: u16,
-    pub report_size: u16,
+    /// Length of an input report in bytes, not counting the report ID.
+    pub input_report_size: u16,
+    /// Length of an output report in bytes, not counting the report ID.
+    pub output_report_size: u16,
+    /// The ID of the input reports used for CTAPHID, or `None` if the device
+    /// doesn't use numbered reports.
+    pub input_report_id: Option<u8>,
+    /// The ID of the output reports used for CTAPHID, or `None` if the device
+    /// doesn't use numbered reports.
+    pub output_report_id: Option<u8>,
+    /// The vendor ID, or 0 if unknown.
+    pub vendor_id: u16,
+    /// The product ID, or 0 if unknown.
+    pub product_id: u16,
+    /// The manufacturer's name for the device, if known.
+    pub manufacturer: Option<String>,
+    /// The product name of the device, if known.
+    pub product: Option<String>,
+    /// The device's serial number, if it has one.
+    pub serial_number: Option<String>,
+    /// The bus the device is connected through.
+    pub bus_type: BusType,
+}
+
+/// The bus a HID device is connected through.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BusType {
+    Usb,
+    Bluetooth,
+    Virtual,
+    I2c,
+    /// Any other bus, identified by its Linux bus type number.
+    Other(u16),
+    /// The bus could not be determined.
+    Unknown,
+}
+
+impl BusType {
+    pub fn from_u16(bus: u16) -> Self {
+        match bus {
+            0x03 => BusType::Usb,
+            0x05 => BusType::Bluetooth,
+            0x06 => BusType::Virtual,
+            0x18 => BusType::I2c,
+            other => BusType::Other(other),
+        }
+    }
+}
+
+impl DeviceInfo {
+    /// Whether this device's HID usage marks it as a FIDO authenticator.
+    pub fn is_fido(&self) -> bool {
+        self.usage_page == FIDO_USAGE_PAGE && self.usage == FIDO_USAGE
+    }
+}
+
+/// A change in the set of connected FIDO devices.
+#[derive(Debug, Clone)]
+pub enum DeviceEvent {
+    /// A FIDO device was connected.
+    Added(DeviceInfo),
+    /// The FIDO device at this path was disconnected.
+    Removed(PathBuf),
 }
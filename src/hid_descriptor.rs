@@ -0,0 +1,272 @@
+// This file is part of ctap, a Rust implementation of the FIDO2 protocol.
+// Copyright (c) Ariën Holthuizen <contact@ardaxi.com>
+// Licensed under the Apache License, Version 2.0, <LICENSE-APACHE or
+// http://apache.org/licenses/LICENSE-2.0> or the MIT license <LICENSE-MIT or
+// http://opensource.org/licenses/MIT>, at your option. This file may not be
+// copied, modified, or distributed except according to those terms.
+//! A parser for HID report descriptors, as described in section 6.2.2 of the
+//! HID 1.11 specification.
+use byteorder::{ByteOrder, LittleEndian};
+
+static LONG_ITEM: u8 = 0xfe;
+
+static TYPE_MAIN: u8 = 0x00;
+static TYPE_GLOBAL: u8 = 0x01;
+static TYPE_LOCAL: u8 = 0x02;
+
+static MAIN_INPUT: u8 = 0x08;
+static MAIN_OUTPUT: u8 = 0x09;
+static MAIN_COLLECTION: u8 = 0x0a;
+static MAIN_FEATURE: u8 = 0x0b;
+static MAIN_END_COLLECTION: u8 = 0x0c;
+
+static GLOBAL_USAGE_PAGE: u8 = 0x00;
+static GLOBAL_REPORT_SIZE: u8 = 0x07;
+static GLOBAL_REPORT_ID: u8 = 0x08;
+static GLOBAL_REPORT_COUNT: u8 = 0x09;
+static GLOBAL_PUSH: u8 = 0x0a;
+static GLOBAL_POP: u8 = 0x0b;
+
+static LOCAL_USAGE: u8 = 0x00;
+static LOCAL_USAGE_MINIMUM: u8 = 0x01;
+
+/// An error in a HID report descriptor. Offsets are in bytes from the start of
+/// the descriptor.
+#[derive(Fail, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DescriptorError {
+    #[fail(display = "Item at offset {} extends past the end of the descriptor", _0)]
+    Truncated(usize),
+    #[fail(display = "End Collection at offset {} has no matching Collection", _0)]
+    UnmatchedEndCollection(usize),
+    #[fail(display = "Collection is not closed at the end of the descriptor")]
+    UnclosedCollection,
+    #[fail(display = "Pop at offset {} has no matching Push", _0)]
+    UnmatchedPop(usize),
+    #[fail(display = "Report ID at offset {} is zero or larger than 255", _0)]
+    InvalidReportId(usize),
+    #[fail(display = "Collection at offset {} has a type larger than 255", _0)]
+    InvalidCollectionType(usize),
+}
+
+/// The kind of report a field is part of.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ReportKind {
+    Input,
+    Output,
+    Feature,
+}
+
+/// A field of a report, declared by an Input, Output or Feature item.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ReportField {
+    pub kind: ReportKind,
+    /// The report this field is part of, if the device uses numbered reports.
+    pub report_id: Option<u8>,
+    /// The size of a single value in bits.
+    pub report_size: u32,
+    /// The number of values in this field.
+    pub report_count: u32,
+    /// The data of the main item, such as whether the field is constant.
+    pub flags: u32,
+}
+
+impl ReportField {
+    /// The total size of this field in bits.
+    pub fn bits(&self) -> u32 {
+        self.report_size.saturating_mul(self.report_count)
+    }
+}
+
+/// A collection of report fields and other collections.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Collection {
+    /// The type of collection, such as 0x01 for an application collection.
+    pub collection_type: u8,
+    pub usage_page: u16,
+    pub usage: u16,
+    /// The fields declared directly in this collection.
+    pub fields: Vec<ReportField>,
+    /// The collections nested in this collection.
+    pub children: Vec<Collection>,
+}
+
+impl Collection {
+    /// All fields declared in this collection and the collections nested in it.
+    pub fn all_fields(&self) -> Vec<&ReportField> {
+        let mut fields: Vec<_> = self.fields.iter().collect();
+        for child in &self.children {
+            fields.extend(child.all_fields());
+        }
+        fields
+    }
+
+    /// The IDs of the reports of the given kind that this collection has fields
+    /// in, in the order they are declared.
+    pub fn report_ids(&self, kind: ReportKind) -> Vec<u8> {
+        let mut ids = Vec::new();
+        for field in self.all_fields() {
+            if let Some(id) = field.report_id {
+                if field.kind == kind && !ids.contains(&id) {
+                    ids.push(id);
+                }
+            }
+        }
+        ids
+    }
+
+    /// The length in bytes of a report of the given kind and ID, not counting
+    /// the report ID itself. Only fields in this collection are counted.
+    pub fn report_length(&self, kind: ReportKind, report_id: Option<u8>) -> usize {
+        let bits = self
+            .all_fields()
+            .iter()
+            .filter(|field| field.kind == kind && field.report_id == report_id)
+            .fold(0u64, |bits, field| bits + u64::from(field.bits()));
+        ((bits + 7) / 8) as usize
+    }
+}
+
+/// A parsed HID report descriptor.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct ReportDescriptor {
+    /// The top-level collections of the descriptor. Composite devices have one
+    /// for each function they implement.
+    pub collections: Vec<Collection>,
+}
+
+#[derive(Clone, Default)]
+struct GlobalState {
+    usage_page: u16,
+    report_size: u32,
+    report_count: u32,
+    report_id: Option<u8>,
+}
+
+impl ReportDescriptor {
+    pub fn parse(data: &[u8]) -> Result<Self, DescriptorError> {
+        let mut globals = GlobalState::default();
+        let mut global_stack = Vec::new();
+        // Usages are stored with their usage page if the item included one.
+        let mut usages: Vec<(Option<u16>, u16)> = Vec::new();
+        let mut open: Vec<Collection> = Vec::new();
+        let mut collections = Vec::new();
+        let mut pos = 0;
+
+        while pos < data.len() {
+            let prefix = data[pos];
+            if prefix == LONG_ITEM {
+                // Long items are reserved for future use, so they are skipped.
+                let size = *data.get(pos + 1).ok_or(DescriptorError::Truncated(pos))? as usize;
+                let end = pos + 3 + size;
+                if end > data.len() {
+                    return Err(DescriptorError::Truncated(pos));
+                }
+                pos = end;
+                continue;
+            }
+
+            let size = match prefix & 0x03 {
+                0x03 => 4,
+                size => size as usize,
+            };
+            let end = pos + 1 + size;
+            if end > data.len() {
+                return Err(DescriptorError::Truncated(pos));
+            }
+            let value = item_value(&data[(pos + 1)..end]);
+            let item_type = (prefix >> 2) & 0x03;
+            let tag = prefix >> 4;
+
+            if item_type == TYPE_MAIN {
+                if tag == MAIN_INPUT || tag == MAIN_OUTPUT || tag == MAIN_FEATURE {
+                    let kind = if tag == MAIN_INPUT {
+                        ReportKind::Input
+                    } else if tag == MAIN_OUTPUT {
+                        ReportKind::Output
+                    } else {
+                        ReportKind::Feature
+                    };
+                    // Fields outside of any collection are not allowed, and
+                    // are ignored.
+                    if let Some(collection) = open.last_mut() {
+                        collection.fields.push(ReportField {
+                            kind,
+                            report_id: globals.report_id,
+                            report_size: globals.report_size,
+                            report_count: globals.report_count,
+                            flags: value,
+                        });
+                    }
+                } else if tag == MAIN_COLLECTION {
+                    if value > 0xff {
+                        return Err(DescriptorError::InvalidCollectionType(pos));
+                    }
+                    let (usage_page, usage) = usages.first().cloned().unwrap_or((None, 0));
+                    open.push(Collection {
+                        collection_type: value as u8,
+                        usage_page: usage_page.unwrap_or(globals.usage_page),
+                        usage,
+                        ..Default::default()
+                    });
+                } else if tag == MAIN_END_COLLECTION {
+                    let collection = open.pop().ok_or(DescriptorError::UnmatchedEndCollection(pos))?;
+                    match open.last_mut() {
+                        Some(parent) => parent.children.push(collection),
+                        None => collections.push(collection),
+                    }
+                }
+                usages.clear();
+            } else if item_type == TYPE_GLOBAL {
+                if tag == GLOBAL_USAGE_PAGE {
+                    globals.usage_page = value as u16;
+                } else if tag == GLOBAL_REPORT_SIZE {
+                    globals.report_size = value;
+                } else if tag == GLOBAL_REPORT_ID {
+                    if value == 0 || value > 0xff {
+                        return Err(DescriptorError::InvalidReportId(pos));
+                    }
+                    globals.report_id = Some(value as u8);
+                } else if tag == GLOBAL_REPORT_COUNT {
+                    globals.report_count = value;
+                } else if tag == GLOBAL_PUSH {
+                    global_stack.push(globals.clone());
+                } else if tag == GLOBAL_POP {
+                    globals = global_stack.pop().ok_or(DescriptorError::UnmatchedPop(pos))?;
+                }
+            } else if item_type == TYPE_LOCAL && (tag == LOCAL_USAGE || tag == LOCAL_USAGE_MINIMUM) {
+                // Four-byte usages carry their own usage page in the high bits.
+                let usage_page = if size == 4 {
+                    Some((value >> 16) as u16)
+                } else {
+                    None
+                };
+                usages.push((usage_page, value as u16));
+            }
+
+            pos = end;
+        }
+
+        if !open.is_empty() {
+            return Err(DescriptorError::UnclosedCollection);
+        }
+        Ok(ReportDescriptor { collections })
+    }
+
+    /// Find the top-level collection with the given usage.
+    pub fn find_collection(&self, usage_page: u16, usage: u16) -> Option<&Collection> {
+        self.collections
+            .iter()
+            .find(|collection| collection.usage_page == usage_page && collection.usage == usage)
+    }
+}
+
+/// Decode the little-endian data of a short item, which is between zero and
+/// four bytes long.
+fn item_value(data: &[u8]) -> u32 {
+    match data.len() {
+        0 => 0,
+        1 => u32::from(data[0]),
+        2 => u32::from(LittleEndian::read_u16(data)),
+        _ => LittleEndian::read_u32(data),
+    }
+}
@@ -4,85 +4,521 @@
 // http://apache.org/licenses/LICENSE-2.0> or the MIT license <LICENSE-MIT or
 // http://opensource.org/licenses/MIT>, at your option. This file may not be
 // copied, modified, or distributed except according to those terms.
-use std::io;
+use std::cmp;
+use std::collections::{HashSet, VecDeque};
+use std::ffi::{CString, OsStr};
+use std::io::{self, Read, Write};
 use std::fs;
-use std::path::PathBuf;
-use byteorder::{ByteOrder, LittleEndian};
+use std::os::unix::ffi::OsStrExt;
+#[cfg(feature = "async")]
+use std::os::unix::fs::OpenOptionsExt;
+use std::os::unix::io::{AsRawFd, FromRawFd};
+use std::path::{Path, PathBuf};
+#[cfg(feature = "async")]
+use std::task::{Context, Poll};
+use std::time::Duration;
+use byteorder::{ByteOrder, NativeEndian};
+use failure::Fail;
+#[cfg(feature = "async")]
+use tokio::io::unix::AsyncFd;
+use super::hid_descriptor::{ReportDescriptor, ReportKind};
+#[cfg(feature = "async")]
+use super::transport::AsyncTransport;
+use super::transport::{ReportWriter, Transport};
 pub use super::hid_common::*;
 
-static REPORT_DESCRIPTOR_KEY_MASK: u8 = 0xfc;
-static LONG_ITEM_ENCODING: u8 = 0xfe;
-static USAGE_PAGE: u8 = 0x04;
-static USAGE: u8 = 0x08;
-static REPORT_SIZE: u8 = 0x74;
+static SYSFS_ROOT: &str = "/sys";
+static DEV_ROOT: &str = "/dev";
+static INOTIFY_EVENT_SIZE: usize = 16;
 
-pub fn enumerate() -> io::Result<impl Iterator<Item = DeviceInfo>> {
-    fs::read_dir("/sys/class/hidraw").map(|entries| {
-        entries.filter_map(|entry| entry.ok()).filter_map(|entry| {
-            path_to_device(&entry.path()).ok()
+/// Finds hidraw devices through sysfs. By default sysfs is expected at `/sys`
+/// and device nodes at `/dev`, but other locations can be used, for example in
+/// containers.
+#[derive(Debug, Clone)]
+pub struct DeviceEnumerator {
+    sysfs_root: PathBuf,
+    dev_root: PathBuf,
+}
+
+impl Default for DeviceEnumerator {
+    fn default() -> Self {
+        DeviceEnumerator::new(SYSFS_ROOT, DEV_ROOT)
+    }
+}
+
+impl DeviceEnumerator {
+    /// Create an enumerator that reads sysfs from `sysfs_root` and returns
+    /// device paths in `dev_root`.
+    pub fn new<P: Into<PathBuf>, Q: Into<PathBuf>>(sysfs_root: P, dev_root: Q) -> Self {
+        DeviceEnumerator {
+            sysfs_root: sysfs_root.into(),
+            dev_root: dev_root.into(),
+        }
+    }
+
+    /// Returns all connected HID devices, whether they support FIDO or not.
+    /// Devices whose information can't be read are skipped.
+    pub fn enumerate(&self) -> io::Result<impl Iterator<Item = DeviceInfo>> {
+        let enumerator = self.clone();
+        fs::read_dir(self.hidraw_class()).map(move |entries| {
+            entries.filter_map(|entry| entry.ok()).filter_map(move |entry| {
+                enumerator.device(&entry.file_name()).ok()
+            })
         })
-    })
-}
-
-fn path_to_device(path: &PathBuf) -> io::Result<DeviceInfo> {
-    let mut rd_path = path.clone();
-    rd_path.push("device/report_descriptor");
-    let rd = fs::read(rd_path)?;
-    let mut usage_page: u16 = 0;
-    let mut usage: u16 = 0;
-    let mut report_size: u16 = 0;
-    let mut pos: usize = 0;
-
-    while pos < rd.len() {
-        let key = rd[pos];
-        let mut key_size: usize = 1;
-        let mut size: u8;
-
-        if key == LONG_ITEM_ENCODING {
-            key_size = 3;
-            size = rd[pos + 1];
-        } else {
-            size = key & 0x03;
-
-            if size == 0x03 {
-                size = 0x04
+    }
+
+    fn hidraw_class(&self) -> PathBuf {
+        self.sysfs_root.join("class/hidraw")
+    }
+
+    /// Read the information of the hidraw device with the given name, such as
+    /// `hidraw0`.
+    fn device(&self, name: &OsStr) -> io::Result<DeviceInfo> {
+        path_to_device(&self.hidraw_class().join(name), self.dev_root.join(name))
+    }
+}
+
+/// Watches `/dev` for hidraw device nodes being created and removed, and turns
+/// those into events for FIDO devices.
+pub struct DeviceMonitor {
+    enumerator: DeviceEnumerator,
+    inotify: fs::File,
+    known: HashSet<PathBuf>,
+    events: VecDeque<DeviceEvent>,
+}
+
+impl DeviceMonitor {
+    pub fn new(enumerator: DeviceEnumerator) -> io::Result<Self> {
+        let fd = unsafe { libc::inotify_init1(libc::IN_CLOEXEC) };
+        if fd < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        let inotify = unsafe { fs::File::from_raw_fd(fd) };
+        let dev = CString::new(enumerator.dev_root.as_os_str().as_bytes())
+            .map_err(|err| io::Error::new(io::ErrorKind::InvalidInput, err))?;
+        let mask = libc::IN_CREATE | libc::IN_DELETE;
+        if unsafe { libc::inotify_add_watch(fd, dev.as_ptr(), mask) } < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        // Only take stock of the connected devices once the watch is in place,
+        // so no device can go unnoticed in between.
+        let known = enumerator
+            .enumerate()?
+            .filter(DeviceInfo::is_fido)
+            .map(|device| device.path)
+            .collect();
+        Ok(DeviceMonitor {
+            enumerator,
+            inotify,
+            known,
+            events: VecDeque::new(),
+        })
+    }
+
+    fn read_events(&mut self) -> io::Result<()> {
+        let mut buf = [0u8; 4096];
+        let len = loop {
+            match self.inotify.read(&mut buf) {
+                Err(ref err) if err.kind() == io::ErrorKind::Interrupted => continue,
+                result => break result?,
             }
+        };
+        let mut pos = 0;
+        while pos + INOTIFY_EVENT_SIZE <= len {
+            let mask = NativeEndian::read_u32(&buf[(pos + 4)..(pos + 8)]);
+            let name_len = NativeEndian::read_u32(&buf[(pos + 12)..(pos + 16)]) as usize;
+            let name_start = pos + INOTIFY_EVENT_SIZE;
+            let name_end = cmp::min(name_start + name_len, len);
+            // The name is padded with NUL bytes.
+            let name = buf[name_start..name_end].split(|byte| *byte == 0).next().unwrap_or(&[]);
+            self.handle_event(mask, OsStr::from_bytes(name));
+            pos = name_start + name_len;
         }
+        Ok(())
+    }
 
-        if key & REPORT_DESCRIPTOR_KEY_MASK == USAGE_PAGE {
-            if size != 2 {
-                usage_page = u16::from(rd[pos + 1])
-            } else {
-                usage_page = LittleEndian::read_u16(&rd[(pos + 1)..(pos + 1 + (size as usize))]);
+    fn handle_event(&mut self, mask: u32, name: &OsStr) {
+        if !name.as_bytes().starts_with(b"hidraw") {
+            return;
+        }
+        if mask & libc::IN_CREATE != 0 {
+            if let Ok(device) = self.enumerator.device(name) {
+                if device.is_fido() {
+                    self.known.insert(device.path.clone());
+                    self.events.push_back(DeviceEvent::Added(device));
+                }
+            }
+        } else if mask & libc::IN_DELETE != 0 {
+            let path = self.enumerator.dev_root.join(name);
+            if self.known.remove(&path) {
+                self.events.push_back(DeviceEvent::Removed(path));
             }
         }
+    }
+}
+
+impl Iterator for DeviceMonitor {
+    type Item = io::Result<DeviceEvent>;
 
-        if key & REPORT_DESCRIPTOR_KEY_MASK == USAGE {
-            if size != 2 {
-                usage = u16::from(rd[pos + 1])
-            } else {
-                usage = LittleEndian::read_u16(&rd[(pos + 1)..(pos + 1 + (size as usize))]);
+    fn next(&mut self) -> Option<io::Result<DeviceEvent>> {
+        while self.events.is_empty() {
+            if let Err(err) = self.read_events() {
+                return Some(Err(err));
             }
         }
+        self.events.pop_front().map(Ok)
+    }
+}
+
+/// A hidraw device node, opened for exchanging reports with an authenticator.
+pub struct HidrawDevice {
+    file: fs::File,
+    input_report_size: usize,
+    output_report_size: usize,
+    input_report_id: Option<u8>,
+    output_report_id: Option<u8>,
+}
+
+impl HidrawDevice {
+    pub fn open(device: &DeviceInfo) -> io::Result<Self> {
+        let mut options = fs::OpenOptions::new();
+        options.read(true).write(true);
+        Ok(HidrawDevice {
+            file: options.open(&device.path)?,
+            input_report_size: device.input_report_size as usize,
+            output_report_size: device.output_report_size as usize,
+            input_report_id: device.input_report_id,
+            output_report_id: device.output_report_id,
+        })
+    }
+}
 
-        if key & REPORT_DESCRIPTOR_KEY_MASK == REPORT_SIZE {
-            if size != 2 {
-                report_size = u16::from(rd[pos + 1])
-            } else {
-                report_size = LittleEndian::read_u16(&rd[(pos + 1)..(pos + 1 + (size as usize))]);
+impl HidrawDevice {
+    /// Wait until a report is ready to be read, or the timeout expires.
+    fn poll(&self, timeout: Duration) -> io::Result<()> {
+        let millis = cmp::min((timeout.as_nanos() + 999_999) / 1_000_000, i32::MAX as u128) as i32;
+        let mut fds = libc::pollfd {
+            fd: self.file.as_raw_fd(),
+            events: libc::POLLIN,
+            revents: 0,
+        };
+        loop {
+            let ready = unsafe { libc::poll(&mut fds, 1, millis) };
+            if ready > 0 {
+                return Ok(());
+            }
+            if ready == 0 {
+                return Err(io::Error::new(io::ErrorKind::TimedOut, "no report received"));
+            }
+            let err = io::Error::last_os_error();
+            if err.kind() != io::ErrorKind::Interrupted {
+                return Err(err);
             }
         }
+    }
+}
 
-        pos = pos + key_size + size as usize;
+impl Transport for HidrawDevice {
+    fn write_report(&mut self, report: &[u8]) -> io::Result<()> {
+        self.file.write_all(report)?;
+        self.file.flush()
     }
 
-    let mut device_path = PathBuf::from("/dev");
-    device_path.push(path.file_name().unwrap());
+    fn read_report(&mut self, buf: &mut [u8], timeout: Option<Duration>) -> io::Result<usize> {
+        if let Some(timeout) = timeout {
+            self.poll(timeout)?;
+        }
+        self.file.read(buf)
+    }
+
+    fn input_report_size(&self) -> usize {
+        self.input_report_size
+    }
 
-    Ok(DeviceInfo {
+    fn output_report_size(&self) -> usize {
+        self.output_report_size
+    }
+
+    fn input_report_id(&self) -> Option<u8> {
+        self.input_report_id
+    }
+
+    fn output_report_id(&self) -> Option<u8> {
+        self.output_report_id
+    }
+
+    fn report_writer(&self) -> Option<Box<dyn ReportWriter>> {
+        let file = self.file.try_clone().ok()?;
+        Some(Box::new(HidrawWriter(file)))
+    }
+}
+
+/// A second handle to a hidraw device node, which only writes reports.
+struct HidrawWriter(fs::File);
+
+impl ReportWriter for HidrawWriter {
+    fn write_report(&mut self, report: &[u8]) -> io::Result<()> {
+        self.0.write_all(report)?;
+        self.0.flush()
+    }
+}
+
+/// A hidraw device opened in non-blocking mode, for use with the async API.
+/// This must be created from within a tokio runtime.
+#[cfg(feature = "async")]
+pub struct AsyncHidrawDevice {
+    file: AsyncFd<fs::File>,
+    input_report_size: usize,
+    output_report_size: usize,
+    input_report_id: Option<u8>,
+    output_report_id: Option<u8>,
+}
+
+#[cfg(feature = "async")]
+impl AsyncHidrawDevice {
+    pub fn open(device: &DeviceInfo) -> io::Result<Self> {
+        let mut options = fs::OpenOptions::new();
+        options.read(true).write(true).custom_flags(libc::O_NONBLOCK);
+        Ok(AsyncHidrawDevice {
+            file: AsyncFd::new(options.open(&device.path)?)?,
+            input_report_size: device.input_report_size as usize,
+            output_report_size: device.output_report_size as usize,
+            input_report_id: device.input_report_id,
+            output_report_id: device.output_report_id,
+        })
+    }
+}
+
+#[cfg(feature = "async")]
+impl AsyncTransport for AsyncHidrawDevice {
+    fn poll_write_report(&mut self, cx: &mut Context<'_>, report: &[u8]) -> Poll<io::Result<()>> {
+        loop {
+            let mut guard = match self.file.poll_write_ready(cx) {
+                Poll::Ready(guard) => guard?,
+                Poll::Pending => return Poll::Pending,
+            };
+            if let Ok(result) = guard.try_io(|file| file.get_ref().write(report)) {
+                return Poll::Ready(result.map(|_| ()));
+            }
+        }
+    }
+
+    fn poll_read_report(
+        &mut self,
+        cx: &mut Context<'_>,
+        buf: &mut [u8],
+    ) -> Poll<io::Result<usize>> {
+        loop {
+            let mut guard = match self.file.poll_read_ready(cx) {
+                Poll::Ready(guard) => guard?,
+                Poll::Pending => return Poll::Pending,
+            };
+            if let Ok(result) = guard.try_io(|file| file.get_ref().read(buf)) {
+                return Poll::Ready(result);
+            }
+        }
+    }
+
+    fn input_report_size(&self) -> usize {
+        self.input_report_size
+    }
+
+    fn output_report_size(&self) -> usize {
+        self.output_report_size
+    }
+
+    fn input_report_id(&self) -> Option<u8> {
+        self.input_report_id
+    }
+
+    fn output_report_id(&self) -> Option<u8> {
+        self.output_report_id
+    }
+
+    fn report_writer(&self) -> Option<Box<dyn ReportWriter>> {
+        // The clone shares the non-blocking mode, so writes through it fail
+        // instead of waiting when the device isn't ready.
+        let file = self.file.get_ref().try_clone().ok()?;
+        Some(Box::new(HidrawWriter(file)))
+    }
+}
+
+fn path_to_device(path: &Path, device_path: PathBuf) -> io::Result<DeviceInfo> {
+    let rd = fs::read(path.join("device/report_descriptor"))?;
+    let descriptor = ReportDescriptor::parse(&rd)
+        .map_err(|err| io::Error::new(io::ErrorKind::InvalidData, err.compat()))?;
+    // Composite devices have a top-level collection for each function, prefer
+    // the FIDO one if there is one.
+    let collection = descriptor
+        .find_collection(FIDO_USAGE_PAGE, FIDO_USAGE)
+        .or_else(|| descriptor.collections.first())
+        .cloned()
+        .unwrap_or_default();
+    let input_report_id = collection.report_ids(ReportKind::Input).first().cloned();
+    let output_report_id = collection.report_ids(ReportKind::Output).first().cloned();
+
+    let mut info = DeviceInfo {
         path: device_path,
-        usage_page,
-        usage,
-        report_size,
-    })
+        usage_page: collection.usage_page,
+        usage: collection.usage,
+        input_report_size: collection.report_length(ReportKind::Input, input_report_id) as u16,
+        output_report_size: collection.report_length(ReportKind::Output, output_report_id) as u16,
+        input_report_id,
+        output_report_id,
+        vendor_id: 0,
+        product_id: 0,
+        manufacturer: None,
+        product: None,
+        serial_number: None,
+        bus_type: BusType::Unknown,
+    };
+    read_metadata(&path.join("device"), &mut info);
+    Ok(info)
+}
+
+/// Fill in the vendor, product and bus information of a device. This is read
+/// from the HID device's `uevent`, and from the attributes of the USB device
+/// it belongs to if there is one. Missing information is left empty.
+fn read_metadata(hid_device: &Path, info: &mut DeviceInfo) {
+    if let Ok(uevent) = fs::read_to_string(hid_device.join("uevent")) {
+        for line in uevent.lines() {
+            let mut parts = line.splitn(2, '=');
+            match (parts.next(), parts.next()) {
+                (Some("HID_ID"), Some(value)) => {
+                    // The bus type, vendor ID and product ID in hexadecimal,
+                    // for example 0003:00001050:00000407.
+                    let ids: Vec<_> = value
+                        .split(':')
+                        .map(|id| u32::from_str_radix(id, 16).ok())
+                        .collect();
+                    if let [Some(bus), Some(vendor), Some(product)] = ids[..] {
+                        info.bus_type = BusType::from_u16(bus as u16);
+                        info.vendor_id = vendor as u16;
+                        info.product_id = product as u16;
+                    }
+                }
+                (Some("HID_NAME"), Some(value)) => info.product = non_empty(value),
+                (Some("HID_UNIQ"), Some(value)) => info.serial_number = non_empty(value),
+                _ => (),
+            }
+        }
+    }
+    // HID devices sit below the USB interface, which sits below the USB device.
+    let usb_device = fs::canonicalize(hid_device).ok().and_then(|hid_device| {
+        hid_device
+            .ancestors()
+            .skip(1)
+            .take(2)
+            .find(|dir| dir.join("idVendor").is_file())
+            .map(Path::to_path_buf)
+    });
+    if let Some(usb_device) = usb_device {
+        let attribute = |name| {
+            fs::read_to_string(usb_device.join(name)).ok().and_then(|value| non_empty(&value))
+        };
+        if let Some(manufacturer) = attribute("manufacturer") {
+            info.manufacturer = Some(manufacturer);
+        }
+        if let Some(product) = attribute("product") {
+            info.product = Some(product);
+        }
+        if let Some(serial_number) = attribute("serial") {
+            info.serial_number = Some(serial_number);
+        }
+    }
+}
+
+fn non_empty(value: &str) -> Option<String> {
+    let value = value.trim_end();
+    if value.is_empty() {
+        None
+    } else {
+        Some(value.to_string())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::process;
+    use std::sync::mpsc;
+    use std::thread;
+
+    const EVENT_TIMEOUT: Duration = Duration::from_secs(5);
+
+    /// Watch a device directory holding the `existing` nodes, with the devices
+    /// of the sysfs fixture. Returns the directory and a channel receiving the
+    /// events.
+    fn watch(name: &str, existing: &[&str]) -> (PathBuf, mpsc::Receiver<DeviceEvent>) {
+        let sysfs_root = Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/fixtures/sys");
+        let dev_root = std::env::temp_dir().join(format!("ctap-{}-{}", name, process::id()));
+        let _ = fs::remove_dir_all(&dev_root);
+        fs::create_dir(&dev_root).unwrap();
+        for node in existing {
+            fs::File::create(dev_root.join(node)).unwrap();
+        }
+        let monitor = DeviceMonitor::new(DeviceEnumerator::new(sysfs_root, &dev_root)).unwrap();
+        let (sender, receiver) = mpsc::channel();
+        // The monitor blocks until the next event, so it runs until the test
+        // ends and the receiver is dropped.
+        thread::spawn(move || {
+            for event in monitor {
+                if sender.send(event.unwrap()).is_err() {
+                    break;
+                }
+            }
+        });
+        (dev_root, receiver)
+    }
+
+    fn next_event(receiver: &mpsc::Receiver<DeviceEvent>) -> DeviceEvent {
+        receiver.recv_timeout(EVENT_TIMEOUT).unwrap()
+    }
+
+    #[test]
+    fn device_nodes_are_watched() {
+        let (dev_root, events) = watch("watched", &[]);
+        let path = dev_root.join("hidraw0");
+        fs::File::create(&path).unwrap();
+        match next_event(&events) {
+            DeviceEvent::Added(device) => {
+                assert_eq!(device.path, path);
+                assert!(device.is_fido());
+            }
+            event => panic!("unexpected event {:?}", event),
+        }
+        fs::remove_file(&path).unwrap();
+        match next_event(&events) {
+            DeviceEvent::Removed(removed) => assert_eq!(removed, path),
+            event => panic!("unexpected event {:?}", event),
+        }
+        fs::remove_dir_all(&dev_root).unwrap();
+    }
+
+    #[test]
+    fn other_devices_are_ignored() {
+        let (dev_root, events) = watch("ignored", &[]);
+        // A keyboard, and a node that isn't a hidraw device at all.
+        fs::File::create(dev_root.join("hidraw3")).unwrap();
+        fs::File::create(dev_root.join("tty0")).unwrap();
+        fs::remove_file(dev_root.join("hidraw3")).unwrap();
+        fs::File::create(dev_root.join("hidraw1")).unwrap();
+        match next_event(&events) {
+            DeviceEvent::Added(device) => assert_eq!(device.path, dev_root.join("hidraw1")),
+            event => panic!("unexpected event {:?}", event),
+        }
+        fs::remove_dir_all(&dev_root).unwrap();
+    }
+
+    #[test]
+    fn devices_connected_before_watching_are_removed() {
+        let (dev_root, events) = watch("connected", &["hidraw2"]);
+        let path = dev_root.join("hidraw2");
+        fs::remove_file(&path).unwrap();
+        match next_event(&events) {
+            DeviceEvent::Removed(removed) => assert_eq!(removed, path),
+            event => panic!("unexpected event {:?}", event),
+        }
+        fs::remove_dir_all(&dev_root).unwrap();
+    }
 }
@@ -34,6 +34,34 @@
 //! let result = device.get_assertion(&cred, &client_data_hash);
 //! # Ok(())
 //! # }
+//! ```
+//!
+//! With the `async` feature enabled, `AsyncFidoDevice` offers the same
+//! operations as async functions for use with tokio. It talks to hidraw
+//! devices through `AsyncHidrawDevice`, or to anything else implementing
+//! `AsyncTransport`.
+//!
+//! With the `trace` feature enabled, the messages exchanged with authenticators
+//! are logged through the `log` crate. CTAPHID messages are logged at the trace
+//! level, CTAP2 requests and responses at the debug level in CBOR diagnostic
+//! notation. The PIN hash, PIN token and pinAuth values are never logged.
+//!
+//! For tests, the `virtual-authenticator` feature adds `VirtualAuthenticator`,
+//! an authenticator implemented in software. Wrap it in a `MemoryTransport` and
+//! pass that to `FidoDevice::with_transport` to use it like a physical one.
+//! `ChannelManager` implements the authenticator's side of CTAPHID, to connect
+//! it to other transports. To test the whole path through the kernel instead,
+//! the `uhid` feature adds `UhidDevice`, which creates a hidraw device answered
+//! by it.
+//!
+//! To reproduce problems with a particular authenticator, open it with
+//! `FidoDevice::new_recording` to save every report exchanged with it to a
+//! trace file. Passing a `ReplayTransport` for that file to
+//! `FidoDevice::with_transport` plays the session back without the
+//! authenticator.
+//!
+//! Authenticator firmware running as a desktop simulator can be reached with
+//! `UdpTransport`.
 
 #![allow(dead_code)]
 
@@ -49,39 +77,210 @@ extern crate cbor as cbor_codec;
 extern crate ring;
 extern crate untrusted;
 extern crate crypto as rust_crypto;
+extern crate libc;
+#[cfg(feature = "async")]
+extern crate tokio;
+#[cfg(feature = "trace")]
+extern crate log;
+
+/// Log a message about the protocol at the given level, if the `trace` feature
+/// is enabled. Otherwise the arguments aren't evaluated.
+#[cfg(feature = "trace")]
+macro_rules! protocol_log {
+    ($level:ident, $($arg:tt)+) => {
+        log::$level!($($arg)+)
+    };
+}
+
+#[cfg(not(feature = "trace"))]
+macro_rules! protocol_log {
+    ($level:ident, $($arg:tt)+) => {};
+}
 
 mod packet;
+mod transport;
 mod hid_common;
+mod hid_descriptor;
 mod hid_linux;
 mod error;
 mod crypto;
 mod cbor;
+mod u2f;
+mod retry;
+#[cfg(feature = "virtual-authenticator")]
+mod authenticator;
+#[cfg(feature = "virtual-authenticator")]
+mod channels;
+#[cfg(feature = "virtual-authenticator")]
+mod memory_transport;
+mod recording;
+mod udp_transport;
+#[cfg(feature = "uhid")]
+mod uhid;
+#[cfg(feature = "trace")]
+mod protocol_log;
+#[cfg(feature = "async")]
+mod async_device;
 
 use std::cmp;
+use std::fmt;
+use std::fs::File;
+use std::path::Path;
 use std::u8;
-use std::u16;
-use std::fs;
-use std::io::{Write, Cursor};
+use std::io::Cursor;
+use std::ops::{Deref, DerefMut};
+use std::sync::{mpsc, Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
 
 use failure::{Fail, ResultExt};
 use rand::prelude::*;
-use num_traits::FromPrimitive;
 use self::hid_linux as hid;
-use self::packet::CtapCommand;
+#[cfg(feature = "async")]
+pub use self::async_device::AsyncFidoDevice;
+#[cfg(feature = "async")]
+pub use self::hid::AsyncHidrawDevice;
+#[cfg(feature = "virtual-authenticator")]
+pub use self::authenticator::{AuthenticatorConfig, VirtualAuthenticator};
+#[cfg(feature = "virtual-authenticator")]
+pub use self::channels::{ChannelManager, Message};
 pub use self::error::*;
+pub use self::hid::{BusType, DeviceEnumerator, DeviceEvent, DeviceInfo, HidrawDevice};
+#[cfg(feature = "virtual-authenticator")]
+pub use self::memory_transport::MemoryTransport;
+pub use self::recording::{Direction, RecordedReport, RecordingTransport, ReplayTransport, Trace};
+pub use self::hid_descriptor::{Collection, DescriptorError, ReportDescriptor, ReportField,
+                               ReportKind};
+pub use self::packet::{CtapCommand, CtapError, DeviceCapabilities, InitResponse,
+                       KeepaliveStatus};
+pub use self::retry::RetryPolicy;
+pub use self::transport::{ReportWriter, Transport};
+#[cfg(feature = "async")]
+pub use self::transport::AsyncTransport;
+pub use self::udp_transport::UdpTransport;
+#[cfg(feature = "uhid")]
+pub use self::uhid::UhidDevice;
 
-static BROADCAST_CID: [u8; 4] = [0xff, 0xff, 0xff, 0xff];
+pub(crate) static BROADCAST_CID: [u8; 4] = [0xff, 0xff, 0xff, 0xff];
+static CTAP2_ERR_KEEPALIVE_CANCEL: u8 = 0x2d;
+// The longest a channel lock may be held for, in seconds.
+static MAX_LOCK_SECONDS: u64 = 10;
+// How long to wait before asking a U2F authenticator again whether the user
+// has touched it.
+const U2F_POLL_INTERVAL: Duration = Duration::from_millis(100);
+// How long to wait for the response to a request that was cancelled after
+// timing out, so it doesn't get mistaken for the response to the next one.
+const CANCEL_TIMEOUT: Duration = Duration::from_millis(500);
+// How long `select_from` waits for the user on devices without a timeout, so
+// the threads of devices that never answer don't live forever.
+const SELECT_TIMEOUT: Duration = Duration::from_secs(60);
+// Errors a CTAP2.0 authenticator returns after the user touched it in response
+// to a MakeCredential request with an empty pinAuth.
+static CTAP2_ERR_PIN_INVALID: u8 = 0x31;
+static CTAP2_ERR_PIN_AUTH_INVALID: u8 = 0x33;
+static CTAP2_ERR_PIN_NOT_SET: u8 = 0x35;
 
 /// Looks for any connected HID devices and returns those that support FIDO.
 pub fn get_devices() -> FidoResult<impl Iterator<Item = hid::DeviceInfo>> {
-    hid::enumerate()
+    hid::DeviceEnumerator::default()
+        .enumerate()
+        .context(FidoErrorKind::Io)
+        .map(|devices| devices.filter(hid::DeviceInfo::is_fido))
+        .map_err(From::from)
+}
+
+/// Watches for FIDO devices being connected or disconnected. The returned
+/// iterator blocks until the next device event. Devices that are already
+/// connected when this is called are not reported, use `get_devices` to find
+/// those.
+///
+/// ```
+/// # fn do_fido() -> ctap::FidoResult<()> {
+/// for event in ctap::watch_devices()? {
+///     match event? {
+///         ctap::DeviceEvent::Added(device) => println!("added {:?}", device.path),
+///         ctap::DeviceEvent::Removed(path) => println!("removed {:?}", path),
+///     }
+/// }
+/// # Ok(())
+/// # }
+/// ```
+pub fn watch_devices() -> FidoResult<impl Iterator<Item = FidoResult<DeviceEvent>>> {
+    hid::DeviceMonitor::new(hid::DeviceEnumerator::default())
         .context(FidoErrorKind::Io)
-        .map(|devices| {
-            devices.filter(|dev| dev.usage_page == 0xf1d0 && dev.usage == 0x21)
+        .map(|monitor| {
+            monitor.map(|event| event.context(FidoErrorKind::Io).map_err(From::from))
         })
         .map_err(From::from)
 }
 
+/// Opens every connected FIDO device and waits for the user to touch one of
+/// them, which is then returned. The other devices are closed. This is how a
+/// user picks which authenticator to use when several are plugged in.
+///
+/// Devices that can't be opened are ignored. This method will fail if no
+/// device could be opened, if every device failed before it was touched, or if
+/// none was touched within a minute.
+///
+/// ```
+/// # fn do_fido() -> ctap::FidoResult<()> {
+/// let mut device = ctap::select_device()?;
+/// let cred = device.make_credential("rp_id", &[0], "user_name", &[0; 32])?;
+/// # Ok(())
+/// # }
+/// ```
+pub fn select_device() -> FidoResult<FidoDevice> {
+    let devices = get_devices()?.filter_map(|device| FidoDevice::new(&device).ok());
+    select_from(devices)
+}
+
+/// Waits for the user to touch one of the given devices and returns it, like
+/// `select_device`. Once a device is touched the requests to the others are
+/// cancelled, and they are dropped as soon as the authenticator has responded.
+///
+/// Each device is waited on for at most its timeout, or a minute if it has
+/// none, so a device that never answers is dropped eventually as well. The
+/// returned device keeps its own timeout.
+pub fn select_from<T, I>(devices: I) -> FidoResult<FidoDevice<T>>
+where
+    T: Transport + Send + 'static,
+    I: IntoIterator<Item = FidoDevice<T>>,
+{
+    let (sender, receiver) = mpsc::channel();
+    let mut handles = Vec::new();
+    for (index, mut device) in devices.into_iter().enumerate() {
+        handles.push(device.cancel_handle());
+        let sender = sender.clone();
+        thread::spawn(move || {
+            let timeout = device.timeout().unwrap_or(SELECT_TIMEOUT);
+            let result = device.with_timeout(Some(timeout), |device| device.select());
+            let result = result.map(|()| device);
+            let _ = sender.send((index, result));
+        });
+    }
+    drop(sender);
+    if handles.is_empty() {
+        Err(FidoErrorKind::NoDevices)?
+    }
+    let mut error = None;
+    for (index, result) in receiver {
+        match result {
+            Ok(device) => {
+                for (other, handle) in handles.iter().enumerate() {
+                    if other != index {
+                        handle.cancel();
+                    }
+                }
+                return Ok(device);
+            }
+            Err(err) => error = Some(err),
+        }
+    }
+    // Without an error every thread panicked before it could send a result,
+    // and no device is left to return.
+    Err(error.unwrap_or_else(|| FidoErrorKind::NoDevices.into()))
+}
+
 /// A credential created by a FIDO2 authenticator.
 #[derive(Debug)]
 pub struct FidoCredential {
@@ -93,35 +292,131 @@ pub struct FidoCredential {
     pub rp_id: String,
 }
 
+/// A handle to cancel the operation a `FidoDevice` is currently performing.
+/// Handles can be cloned and sent to other threads.
+#[derive(Clone)]
+pub struct CancelHandle(Arc<Mutex<CancelState>>);
+
+impl CancelHandle {
+    /// Request cancellation of the pending operation, which then fails with
+    /// `FidoErrorKind::Cancelled`. If no operation is pending, the next one is
+    /// cancelled before it starts.
+    ///
+    /// CTAPHID_CANCEL is sent to the authenticator right away if the transport
+    /// provides a `ReportWriter`, otherwise the next time the authenticator
+    /// reports that it is still busy.
+    pub fn cancel(&self) {
+        let mut state = self.0.lock().unwrap();
+        state.requested = true;
+        if let Some(report) = state.report.take() {
+            let sent = match state.writer {
+                Some(ref mut writer) => writer.write_report(&report).is_ok(),
+                None => false,
+            };
+            if !sent {
+                state.report = Some(report);
+            }
+        }
+    }
+}
+
+impl fmt::Debug for CancelHandle {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let state = self.0.lock().unwrap();
+        f.debug_struct("CancelHandle")
+            .field("requested", &state.requested)
+            .finish()
+    }
+}
+
+struct CancelState {
+    // Cancellation was requested for the pending operation, or the next one
+    // if none is pending.
+    requested: bool,
+    // The CTAPHID_CANCEL report for the request in flight, until it is sent.
+    report: Option<Vec<u8>>,
+    writer: Option<Box<dyn ReportWriter>>,
+}
+
 /// An opened FIDO authenticator.
-pub struct FidoDevice {
-    device: fs::File,
-    packet_size: u16,
+pub struct FidoDevice<T: Transport = hid::HidrawDevice> {
+    device: T,
     channel_id: [u8; 4],
+    init_response: InitResponse,
+    u2f: bool,
+    // Whether the authenticator implements authenticatorSelection from CTAP2.1.
+    selection: bool,
     needs_pin: bool,
     shared_secret: Option<crypto::SharedSecret>,
     pin_token: Option<crypto::PinToken>,
     aaguid: [u8; 16],
+    keepalive: Option<Box<dyn FnMut(KeepaliveStatus) + Send>>,
+    cancel: Arc<Mutex<CancelState>>,
+    timeout: Option<Duration>,
+    retry_policy: RetryPolicy,
 }
 
 impl FidoDevice {
     /// Open and initialize a given device. DeviceInfo is provided by the `get_devices`
     /// function. This method will allocate a channel for this application, verify that
-    /// it supports FIDO2, and checks if a PIN is set.
+    /// it supports FIDO2, and checks if a PIN is set. Devices that only support U2F
+    /// are used through CTAP1 instead, which does not support PINs.
     ///
     /// This method will fail if the device can't be opened, if the device returns
     /// malformed data or if the device is not supported.
     pub fn new(device: &hid::DeviceInfo) -> error::FidoResult<Self> {
-        let mut options = fs::OpenOptions::new();
-        options.read(true).write(true);
+        let device = hid::HidrawDevice::open(device).context(FidoErrorKind::Io)?;
+        FidoDevice::with_transport(device)
+    }
+
+    /// Open and initialize a given device like `new`, saving every report exchanged
+    /// with it to a trace file at `path`. The trace can be attached to bug reports,
+    /// and replayed with `ReplayTransport`.
+    ///
+    /// Traces contain everything sent to the device, including encrypted PINs and
+    /// the messages protected by them.
+    pub fn new_recording<P: AsRef<Path>>(
+        device: &hid::DeviceInfo,
+        path: P,
+    ) -> error::FidoResult<FidoDevice<RecordingTransport<hid::HidrawDevice, File>>> {
+        let device = hid::HidrawDevice::open(device).context(FidoErrorKind::Io)?;
+        let device = RecordingTransport::create(device, path).context(FidoErrorKind::Io)?;
+        FidoDevice::with_transport(device)
+    }
+}
+
+impl<T: Transport> FidoDevice<T> {
+    /// Initialize a device reachable over the given transport. This performs the
+    /// same initialization as `new`, but allows CTAPHID to be spoken over something
+    /// other than a hidraw device node.
+    ///
+    /// This method will fail if the device returns malformed data or if the device
+    /// is not supported.
+    pub fn with_transport(transport: T) -> error::FidoResult<Self> {
+        // Reports must at least fit an initialization packet header and one byte
+        // of payload.
+        if transport.input_report_size() < 8 || transport.output_report_size() < 8 {
+            Err(FidoErrorKind::DeviceUnsupported)?
+        }
+        let cancel = CancelState {
+            requested: false,
+            report: None,
+            writer: transport.report_writer(),
+        };
         let mut dev = FidoDevice {
-            device: options.open(&device.path).context(FidoErrorKind::Io)?,
-            packet_size: 64,
+            device: transport,
             channel_id: BROADCAST_CID,
+            init_response: InitResponse::default(),
+            u2f: false,
+            selection: false,
             needs_pin: false,
             shared_secret: None,
             pin_token: None,
             aaguid: [0; 16],
+            keepalive: None,
+            cancel: Arc::new(Mutex::new(cancel)),
+            timeout: None,
+            retry_policy: RetryPolicy::default(),
         };
         dev.init()?;
         Ok(dev)
@@ -131,51 +426,201 @@ impl FidoDevice {
         let mut nonce = [0u8; 8];
         thread_rng().fill_bytes(&mut nonce);
         let response = self.exchange(CtapCommand::Init, &nonce)?;
-        if response.len() < 17 || response[0..8] != nonce {
-            Err(FidoErrorKind::ParseCtap)?
-        }
-        let flags = response[16];
-        if flags & 0x04 == 0 {
-            Err(FidoErrorKind::DeviceUnsupported)?
-        }
-        self.channel_id.copy_from_slice(&response[8..12]);
-        let response = match self.cbor(cbor::Request::GetInfo)? {
-            cbor::Response::GetInfo(resp) => resp,
-            _ => Err(FidoErrorKind::CborDecode)?,
-        };
-        if !response.versions.iter().any(|ver| ver == "FIDO_2_0") {
-            Err(FidoErrorKind::DeviceUnsupported)?
+        let response = InitResponse::decode(&nonce, &response)?;
+        self.channel_id = response.channel_id;
+        self.init_response = response;
+        if self.init_response.capabilities.cbor {
+            let response = get_info_from_response(self.cbor(cbor::Request::GetInfo)?)?;
+            if supports_ctap2(&response)? {
+                self.selection = response.versions.iter().any(|ver| ver == "FIDO_2_1");
+                self.needs_pin = response.options.client_pin == Some(true);
+                self.aaguid = response.aaguid;
+                return Ok(());
+            }
         }
-        if !response.pin_protocols.iter().any(|ver| *ver == 1) {
+        // Fall back to CTAP1 for authenticators that only support U2F.
+        if self.init_response.capabilities.nmsg {
             Err(FidoErrorKind::DeviceUnsupported)?
         }
-        self.needs_pin = response.options.client_pin == Some(true);
-        self.aaguid = response.aaguid;
+        check_u2f_version(&self.u2f(&u2f::encode_version()?)?)?;
+        self.u2f = true;
         Ok(())
     }
 
+    /// Get the authenticator's response to CTAPHID_INIT, which includes its
+    /// firmware version and the capabilities it advertises.
+    pub fn init_response(&self) -> &InitResponse {
+        &self.init_response
+    }
+
+    /// Get the capabilities the authenticator advertises.
+    pub fn capabilities(&self) -> DeviceCapabilities {
+        self.init_response.capabilities
+    }
+
     /// Get the authenticator's AAGUID. This is not unique to an authenticator,
     /// but it is unique to the specific brand and model.
     pub fn aaguid(&self) -> &[u8] {
         &self.aaguid
     }
 
-    fn init_shared_secret(&mut self) -> FidoResult<()> {
-        let mut request = cbor::ClientPinRequest::default();
-        request.pin_protocol = 1;
-        request.sub_command = 0x02; // getKeyAgreement
-        let response = match self.cbor(cbor::Request::ClientPin(request))? {
-            cbor::Response::ClientPin(resp) => resp,
-            _ => Err(FidoErrorKind::CborDecode)?,
+    /// Register a callback that is invoked whenever the authenticator sends a
+    /// keepalive message while processing a request. This can be used to prompt
+    /// the user to touch the authenticator only once it asks for it.
+    pub fn set_keepalive_callback<F>(&mut self, callback: F)
+    where
+        F: FnMut(KeepaliveStatus) + Send + 'static,
+    {
+        self.keepalive = Some(Box::new(callback));
+    }
+
+    /// Send a CTAPHID_PING with the given payload and wait for the authenticator
+    /// to echo it back. Returns the round-trip time.
+    ///
+    /// The payload may be empty or span multiple packets. This method will fail
+    /// if the authenticator does not return exactly the same payload.
+    pub fn ping(&mut self, payload: &[u8]) -> FidoResult<Duration> {
+        let start = Instant::now();
+        let response = self.exchange(CtapCommand::Ping, payload)?;
+        let elapsed = start.elapsed();
+        if response != payload {
+            Err(FidoErrorKind::PingMismatch)?
+        }
+        Ok(elapsed)
+    }
+
+    /// Ask the authenticator to identify itself to the user, usually by blinking
+    /// an LED. This is useful to tell apart multiple connected authenticators.
+    ///
+    /// This method will fail if the authenticator does not support winking.
+    pub fn wink(&mut self) -> FidoResult<()> {
+        if !self.init_response.capabilities.wink {
+            Err(FidoErrorKind::CapabilityUnsupported)?
+        }
+        self.exchange(CtapCommand::Wink, &[])?;
+        Ok(())
+    }
+
+    /// Lock the authenticator to this application's channel for the given
+    /// duration, so that other applications can't interleave their requests
+    /// with a sequence of requests made through the returned guard. The lock is
+    /// released when the guard is dropped.
+    ///
+    /// The duration is rounded up to whole seconds and may be at most ten
+    /// seconds, longer durations are shortened.
+    pub fn lock(&mut self, duration: Duration) -> FidoResult<DeviceLock<'_, T>> {
+        let mut seconds = duration.as_secs();
+        if duration.subsec_nanos() > 0 {
+            seconds += 1;
+        }
+        let seconds = cmp::max(1, cmp::min(seconds, MAX_LOCK_SECONDS)) as u8;
+        self.exchange(CtapCommand::Lock, &[seconds])?;
+        Ok(DeviceLock { device: self })
+    }
+
+    /// Set how long any single request to the authenticator may take, including
+    /// waiting for the user to touch it. `None`, the default, waits forever.
+    ///
+    /// When a request times out, CTAPHID_CANCEL is sent to the authenticator and
+    /// the call fails with `FidoErrorKind::Timeout`.
+    pub fn set_timeout(&mut self, timeout: Option<Duration>) {
+        self.timeout = timeout;
+    }
+
+    /// Get the timeout set with `set_timeout`.
+    pub fn timeout(&self) -> Option<Duration> {
+        self.timeout
+    }
+
+    /// Run `f` with a different timeout for the requests it makes, restoring the
+    /// device's timeout afterwards.
+    ///
+    /// ```
+    /// # use std::time::Duration;
+    /// # fn do_fido(device: &mut ctap::FidoDevice) -> ctap::FidoResult<()> {
+    /// # let cred = device.make_credential("rp_id", &[0], "user_name", &[0; 32])?;
+    /// let result = device.with_timeout(Some(Duration::from_secs(30)), |device| {
+    ///     device.get_assertion(&cred, &[0; 32])
+    /// })?;
+    /// # Ok(())
+    /// # }
+    /// ```
+    pub fn with_timeout<F, R>(&mut self, timeout: Option<Duration>, f: F) -> R
+    where
+        F: FnOnce(&mut Self) -> R,
+    {
+        let previous = self.timeout;
+        self.timeout = timeout;
+        let result = f(self);
+        self.timeout = previous;
+        result
+    }
+
+    /// Set how requests are repeated when the authenticator is busy with another
+    /// application, or reports that a message timed out.
+    pub fn set_retry_policy(&mut self, policy: RetryPolicy) {
+        self.retry_policy = policy;
+    }
+
+    /// Get a handle that can be used to cancel a pending `make_credential` or
+    /// `get_assertion` call, for example one that is waiting for the user to
+    /// touch the authenticator.
+    pub fn cancel_handle(&self) -> CancelHandle {
+        CancelHandle(self.cancel.clone())
+    }
+
+    /// Wait for the user to touch the authenticator, without doing anything
+    /// else. This is used by `select_device` to find out which of several
+    /// authenticators the user wants to use.
+    ///
+    /// Authenticators that predate CTAP2.1 are sent a request that can't
+    /// succeed but still waits for the user, no credential is created.
+    pub fn select(&mut self) -> FidoResult<()> {
+        if self.u2f {
+            self.u2f_with_presence(&u2f_register_request(".dummy", &[0; 32])?)?;
+            return Ok(());
+        }
+        if self.selection {
+            self.cbor(cbor::Request::Selection)?;
+            return Ok(());
+        }
+        // An empty pinAuth makes the authenticator wait for the user and then
+        // report that the PIN is invalid, or that no PIN is set.
+        let request = cbor::MakeCredentialRequest {
+            client_data_hash: &[0; 32],
+            rp: cbor::PublicKeyCredentialRpEntity {
+                id: ".dummy",
+                name: None,
+                icon: None,
+            },
+            user: cbor::PublicKeyCredentialUserEntity {
+                id: &[0],
+                name: "dummy",
+                icon: None,
+                display_name: None,
+            },
+            pub_key_cred_params: &[("public-key", -7)],
+            pin_auth: Some(&[]),
+            pin_protocol: Some(0x01),
+            ..Default::default()
         };
-        if let Some(key_agreement) = response.key_agreement {
-            self.shared_secret = Some(crypto::SharedSecret::new(&key_agreement)?);
-            Ok(())
-        } else {
-            Err(FidoErrorKind::CborDecode)?
+        match self.cbor(cbor::Request::MakeCredential(request)) {
+            Ok(_) => Ok(()),
+            Err(ref err)
+                if cbor_status(err).map_or(false, |status| {
+                    status == CTAP2_ERR_PIN_INVALID || status == CTAP2_ERR_PIN_AUTH_INVALID ||
+                        status == CTAP2_ERR_PIN_NOT_SET
+                }) => Ok(()),
+            Err(err) => Err(err),
         }
     }
 
+    fn init_shared_secret(&mut self) -> FidoResult<()> {
+        let response = self.cbor(cbor::Request::ClientPin(key_agreement_request()))?;
+        self.shared_secret = Some(shared_secret_from_response(response)?);
+        Ok(())
+    }
+
     /// Unlock the device with the provided PIN. Internally this will generate
     /// an ECDH keypair, send the encrypted PIN to the device and store the PIN
     /// token that the device generates on every power cycle. The PIN itself is
@@ -184,28 +629,20 @@ impl FidoDevice {
     /// This method will fail if the device returns malformed data or the PIN is
     /// incorrect.
     pub fn unlock(&mut self, pin: &str) -> FidoResult<()> {
+        if self.u2f {
+            Err(FidoErrorKind::CapabilityUnsupported)?
+        }
         while self.shared_secret.is_none() {
             self.init_shared_secret()?;
         }
         // If the PIN is invalid the device should create a new agreementKey,
         // so we only replace shared_secret on success.
         let shared_secret = self.shared_secret.take().unwrap();
-        let mut request = cbor::ClientPinRequest::default();
-        request.pin_protocol = 1;
-        request.sub_command = 0x05; // getPINToken
-        request.key_agreement = Some(&shared_secret.public_key);
-        request.pin_hash_enc = Some(shared_secret.encrypt_pin(pin)?);
-        let response = match self.cbor(cbor::Request::ClientPin(request))? {
-            cbor::Response::ClientPin(resp) => resp,
-            _ => Err(FidoErrorKind::CborDecode)?,
-        };
-        if let Some(mut pin_token) = response.pin_token {
-            self.pin_token = Some(shared_secret.decrypt_token(&mut pin_token)?);
-            self.shared_secret = Some(shared_secret);
-            Ok(())
-        } else {
-            Err(FidoErrorKind::CborDecode)?
-        }
+        let request = pin_token_request(&shared_secret, pin)?;
+        let response = self.cbor(cbor::Request::ClientPin(request))?;
+        self.pin_token = Some(pin_token_from_response(&shared_secret, response)?);
+        self.shared_secret = Some(shared_secret);
+        Ok(())
     }
 
     /// Request a new credential from the authenticator. The `rp_id` should be
@@ -217,7 +654,7 @@ impl FidoDevice {
     /// `client_data_hash` SHOULD be a SHA256 hash of provided `client_data`,
     /// this is only used to verify the attestation provided by the
     /// authenticator. When not implementing WebAuthN this can be any random
-    /// 32-byte array.
+    /// 32-byte array. U2F-only devices ignore `user_id` and `user_name`.
     ///
     /// This method will fail if a PIN is required but the device is not
     /// unlocked or if the device returns malformed data.
@@ -228,57 +665,22 @@ impl FidoDevice {
         user_name: &str,
         client_data_hash: &[u8],
     ) -> FidoResult<FidoCredential> {
-        if self.needs_pin && self.pin_token.is_none() {
-            Err(FidoErrorKind::PinRequired)?
-        }
-        if client_data_hash.len() != 32 {
-            Err(FidoErrorKind::CborEncode)?
+        check_request(self.needs_pin, &self.pin_token, client_data_hash)?;
+        if self.u2f {
+            let request = u2f_register_request(rp_id, client_data_hash)?;
+            let response = self.u2f_with_presence(&request)?;
+            return u2f_credential_from_response(rp_id, &response);
         }
-        let pin_auth = self.pin_token.as_ref().map(
-            |token| token.auth(&client_data_hash),
-        );
-        let rp = cbor::PublicKeyCredentialRpEntity {
-            id: rp_id,
-            name: None,
-            icon: None,
-        };
-        let user = cbor::PublicKeyCredentialUserEntity {
-            id: user_id,
-            name: user_name,
-            icon: None,
-            display_name: None,
-        };
-        let pub_key_cred_params = [("public-key", -7)];
-        let request = cbor::MakeCredentialRequest {
+        let pin_auth = pin_auth(&self.pin_token, client_data_hash);
+        let request = make_credential_request(
+            rp_id,
+            user_id,
+            user_name,
             client_data_hash,
-            rp,
-            user,
-            pub_key_cred_params: &pub_key_cred_params,
-            exclude_list: Default::default(),
-            extensions: Default::default(),
-            options: Some(cbor::AuthenticatorOptions {
-                rk: false,
-                uv: true,
-            }),
-            pin_auth,
-            pin_protocol: pin_auth.and(Some(0x01)),
-        };
-        let response = match self.cbor(cbor::Request::MakeCredential(request))? {
-            cbor::Response::MakeCredential(resp) => resp,
-            _ => Err(FidoErrorKind::CborDecode)?,
-        };
-        let public_key = cbor::P256Key::from_cose(
-            &response
-                .auth_data
-                .attested_credential_data
-                .credential_public_key,
-        )?
-            .bytes();
-        Ok(FidoCredential {
-            id: response.auth_data.attested_credential_data.credential_id,
-            rp_id: String::from(rp_id),
-            public_key: Vec::from(&public_key[..]),
-        })
+            pin_auth.as_ref().map(|pin_auth| &pin_auth[..]),
+        );
+        let response = self.cbor(cbor::Request::MakeCredential(request))?;
+        credential_from_response(rp_id, response)
     }
 
     /// Request an assertion from the authenticator for a given credential.
@@ -294,110 +696,474 @@ impl FidoDevice {
         credential: &FidoCredential,
         client_data_hash: &[u8],
     ) -> FidoResult<bool> {
-        if self.needs_pin && self.pin_token.is_none() {
-            Err(FidoErrorKind::PinRequired)?
+        check_request(self.needs_pin, &self.pin_token, client_data_hash)?;
+        if self.u2f {
+            let request = u2f_authenticate_request(credential, client_data_hash)?;
+            let response = self.u2f_with_presence(&request)?;
+            return u2f_verify_response(credential, client_data_hash, &response);
         }
-        if client_data_hash.len() != 32 {
-            Err(FidoErrorKind::CborEncode)?
+        let pin_auth = pin_auth(&self.pin_token, client_data_hash);
+        let allow_list = allow_list(credential);
+        let request = get_assertion_request(credential, client_data_hash, &allow_list, pin_auth);
+        let response = self.cbor(cbor::Request::GetAssertion(request))?;
+        verify_assertion(credential, client_data_hash, response)
+    }
+
+    /// Send a U2F request that requires user presence. U2F authenticators reject
+    /// these until the user touches them, so the request is repeated until it
+    /// succeeds, is cancelled or times out.
+    fn u2f_with_presence(&mut self, apdu: &[u8]) -> FidoResult<Vec<u8>> {
+        let deadline = self.timeout.map(|timeout| Instant::now() + timeout);
+        let waiting = FidoErrorKind::U2fError(u2f::SW_CONDITIONS_NOT_SATISFIED);
+        loop {
+            self.check_cancelled()?;
+            match self.u2f(apdu) {
+                Err(ref err) if err.kind() == waiting => (),
+                result => {
+                    self.cancel.lock().unwrap().requested = false;
+                    return result;
+                }
+            }
+            if remaining(deadline) == Some(Duration::from_secs(0)) {
+                Err(FidoErrorKind::Timeout)?
+            }
+            thread::sleep(U2F_POLL_INTERVAL);
         }
-        let pin_auth = self.pin_token.as_ref().map(
-            |token| token.auth(&client_data_hash),
-        );
-        let allow_list = [
-            cbor::PublicKeyCredentialDescriptor {
-                cred_type: String::from("public-key"),
-                id: credential.id.clone(),
-            },
-        ];
-        let request = cbor::GetAssertionRequest {
-            rp_id: &credential.rp_id,
-            client_data_hash: client_data_hash,
-            allow_list: &allow_list,
-            extensions: Default::default(),
-            options: Some(cbor::AuthenticatorOptions {
-                rk: false,
-                uv: true,
-            }),
-            pin_auth,
-            pin_protocol: pin_auth.and(Some(0x01)),
-        };
-        let response = match self.cbor(cbor::Request::GetAssertion(request))? {
-            cbor::Response::GetAssertion(resp) => resp,
-            _ => Err(FidoErrorKind::CborDecode)?,
-        };
-        Ok(crypto::verify_signature(
-            &credential.public_key,
-            &client_data_hash,
-            &response.auth_data_bytes,
-            &response.signature,
-        ))
+    }
+
+    /// Fail with `FidoErrorKind::Cancelled` if cancellation was requested
+    /// through a `CancelHandle` since the last operation ended.
+    fn check_cancelled(&mut self) -> FidoResult<()> {
+        let mut state = self.cancel.lock().unwrap();
+        if state.requested {
+            state.requested = false;
+            Err(FidoErrorKind::Cancelled)?
+        }
+        Ok(())
+    }
+
+    fn u2f(&mut self, apdu: &[u8]) -> FidoResult<Vec<u8>> {
+        let response = self.exchange(CtapCommand::Msg, apdu)?;
+        u2f::response_data(response)
     }
 
     fn cbor(&mut self, request: cbor::Request) -> FidoResult<cbor::Response> {
-        let mut buf = Cursor::new(Vec::new());
-        request.encode(&mut buf).context(FidoErrorKind::CborEncode)?;
-        let response = self.exchange(CtapCommand::Cbor, &buf.into_inner())?;
-        request
-            .decode(Cursor::new(response))
-            .context(FidoErrorKind::CborDecode)
-            .map_err(From::from)
+        let buf = encode_cbor(&request)?;
+        self.check_cancelled()?;
+        let response = self.exchange(CtapCommand::Cbor, &buf);
+        // Whatever the outcome, a cancellation requested in the meantime
+        // applied to this request.
+        self.cancel.lock().unwrap().requested = false;
+        let response = response?;
+        protocol_log!(debug, "response: {}", protocol_log::response(buf[0], &response));
+        decode_cbor(&request, response)
     }
 
     fn exchange(&mut self, cmd: CtapCommand, payload: &[u8]) -> FidoResult<Vec<u8>> {
-        self.send(&cmd, payload)?;
-        self.receive(&cmd)
+        let cancel = packet::encode_init_packet(
+            self.device.output_report_id(),
+            self.device.output_report_size(),
+            &self.channel_id,
+            &CtapCommand::Cancel,
+            0,
+            &[],
+        )?;
+        self.cancel.lock().unwrap().report = Some(cancel);
+        let result = self.exchange_with_retries(cmd, payload);
+        self.cancel.lock().unwrap().report = None;
+        result
     }
 
-    fn send(&mut self, cmd: &CtapCommand, payload: &[u8]) -> FidoResult<()> {
-        if payload.is_empty() || payload.len() > u16::MAX as usize {
-            Err(FidoErrorKind::WritePacket)?
-        }
-        let to_send = payload.len() as u16;
-        let max_payload = (self.packet_size - 7) as usize;
-        let (frame, payload) = payload.split_at(cmp::min(payload.len(), max_payload));
-        packet::write_init_packet(&mut self.device, 64, &self.channel_id, cmd, to_send, frame)?;
-        if payload.is_empty() {
-            return Ok(());
+    fn exchange_with_retries(&mut self, cmd: CtapCommand, payload: &[u8]) -> FidoResult<Vec<u8>> {
+        let deadline = self.timeout.map(|timeout| Instant::now() + timeout);
+        let mut attempt = 0;
+        let result = loop {
+            self.send(&cmd, payload)?;
+            let result = self.receive(&cmd, deadline);
+            match result {
+                Err(ref err) if attempt < self.retry_policy.attempts && is_transient(err) => {
+                    let delay = self.retry_policy.delay(attempt);
+                    if remaining(deadline).map_or(false, |remaining| remaining < delay) {
+                        break result;
+                    }
+                    thread::sleep(delay);
+                    attempt += 1;
+                }
+                _ => break result,
+            }
+        };
+        if let Err(ref err) = result {
+            if err.kind() == FidoErrorKind::Timeout {
+                // Give the authenticator a chance to abort and answer the request,
+                // the original timeout is reported regardless.
+                if self.send(&CtapCommand::Cancel, &[]).is_ok() {
+                    let _ = self.receive(&cmd, Some(Instant::now() + CANCEL_TIMEOUT));
+                }
+            }
         }
-        let max_payload = (self.packet_size - 5) as usize;
-        for (seq, frame) in (0..u8::MAX).zip(payload.chunks(max_payload)) {
-            packet::write_cont_packet(&mut self.device, 64, &self.channel_id, seq, frame)?;
+        result
+    }
+
+    fn send(&mut self, cmd: &CtapCommand, payload: &[u8]) -> FidoResult<()> {
+        let packets = packet::encode_message(
+            self.device.output_report_id(),
+            self.device.output_report_size(),
+            &self.channel_id,
+            cmd,
+            payload,
+        )?;
+        protocol_log!(
+            trace,
+            "sending {:?} on channel {:02x?}, {} bytes",
+            cmd,
+            self.channel_id,
+            payload.len()
+        );
+        for packet in packets {
+            self.device.write_report(&packet).context(FidoErrorKind::WritePacket)?;
         }
-        self.device.flush().context(FidoErrorKind::WritePacket)?;
         Ok(())
     }
 
-    fn receive(&mut self, cmd: &CtapCommand) -> FidoResult<Vec<u8>> {
-        let mut first_packet: Option<packet::InitPacket> = None;
-        while first_packet.is_none() {
-            let packet = packet::InitPacket::from_reader(&mut self.device, 64)?;
-            if packet.cmd == CtapCommand::Error {
-                Err(
-                    packet::CtapError::from_u8(packet.payload[0])
-                        .unwrap_or(packet::CtapError::Other)
-                        .context(FidoErrorKind::ParseCtap),
-                )?
-            }
-            if packet.cid == self.channel_id && &packet.cmd == cmd {
-                first_packet = Some(packet);
+    fn receive(&mut self, cmd: &CtapCommand, deadline: Option<Instant>) -> FidoResult<Vec<u8>> {
+        let report_size = self.device.input_report_size();
+        let mut reassembler = packet::Reassembler::new(self.channel_id, cmd.clone());
+        loop {
+            let report = packet::read_packet(&mut self.device, report_size, remaining(deadline))?;
+            match reassembler.push(report)? {
+                packet::Received::Message(data) => return Ok(data),
+                packet::Received::Keepalive(status) => {
+                    if let (Some(status), Some(callback)) = (status, self.keepalive.as_mut()) {
+                        callback(status);
+                    }
+                    let cancel = {
+                        let mut state = self.cancel.lock().unwrap();
+                        if state.requested {
+                            state.report.take()
+                        } else {
+                            None
+                        }
+                    };
+                    if let Some(cancel) = cancel {
+                        self.device.write_report(&cancel).context(FidoErrorKind::WritePacket)?;
+                    }
+                }
+                packet::Received::Nothing => (),
             }
         }
-        let first_packet = first_packet.unwrap();
-        let mut data = first_packet.payload;
-        let mut to_read = (first_packet.size as isize) - data.len() as isize;
-        let mut seq = 0;
-        while to_read > 0 {
-            let packet = packet::ContPacket::from_reader(&mut self.device, 64, to_read as usize)?;
-            if packet.cid != self.channel_id {
-                continue;
-            }
-            if packet.seq != seq {
-                Err(FidoErrorKind::InvalidSequence)?
-            }
-            to_read -= packet.payload.len() as isize;
-            data.extend(&packet.payload);
-            seq += 1;
-        }
-        Ok(data)
     }
 }
+
+/// A lock on an authenticator's channel, created by `FidoDevice::lock`. Requests
+/// made through this guard can't be interleaved with those of other
+/// applications. The lock is released when the guard is dropped.
+pub struct DeviceLock<'a, T: Transport> {
+    device: &'a mut FidoDevice<T>,
+}
+
+impl<'a, T: Transport> Deref for DeviceLock<'a, T> {
+    type Target = FidoDevice<T>;
+
+    fn deref(&self) -> &FidoDevice<T> {
+        self.device
+    }
+}
+
+impl<'a, T: Transport> DerefMut for DeviceLock<'a, T> {
+    fn deref_mut(&mut self) -> &mut FidoDevice<T> {
+        self.device
+    }
+}
+
+impl<'a, T: Transport> Drop for DeviceLock<'a, T> {
+    fn drop(&mut self) {
+        // A lock time of zero releases the lock. If this fails the lock will
+        // expire by itself.
+        let _ = self.device.exchange(CtapCommand::Lock, &[0]);
+    }
+}
+
+/// Encode a CTAP2 request as the payload of a CTAPHID_CBOR message.
+fn encode_cbor(request: &cbor::Request) -> FidoResult<Vec<u8>> {
+    let mut buf = Cursor::new(Vec::new());
+    request.encode(&mut buf).context(FidoErrorKind::CborEncode)?;
+    let buf = buf.into_inner();
+    protocol_log!(debug, "request: {}", protocol_log::request(&buf));
+    Ok(buf)
+}
+
+/// Decode the response to a CTAP2 request. Requests the authenticator aborted
+/// because they were cancelled fail with `FidoErrorKind::Cancelled`.
+fn decode_cbor(request: &cbor::Request, response: Vec<u8>) -> FidoResult<cbor::Response> {
+    if response.first() == Some(&CTAP2_ERR_KEEPALIVE_CANCEL) {
+        Err(FidoErrorKind::CborError(CTAP2_ERR_KEEPALIVE_CANCEL).context(
+            FidoErrorKind::Cancelled,
+        ))?
+    }
+    request
+        .decode(Cursor::new(response))
+        .context(FidoErrorKind::CborDecode)
+        .map_err(From::from)
+}
+
+// The helpers below build the requests and handle the responses of the
+// operations of both `FidoDevice` and `AsyncFidoDevice`, which only differ in
+// how they wait for the authenticator.
+
+fn get_info_from_response(response: cbor::Response) -> FidoResult<cbor::GetInfoResponse> {
+    match response {
+        cbor::Response::GetInfo(response) => Ok(response),
+        _ => Err(FidoErrorKind::CborDecode.into()),
+    }
+}
+
+/// Whether a GetInfo response describes an authenticator that can be used
+/// through CTAP2. Fails if it supports CTAP2, but no PIN protocol we support.
+fn supports_ctap2(response: &cbor::GetInfoResponse) -> FidoResult<bool> {
+    if !response.versions.iter().any(|ver| ver == "FIDO_2_0") {
+        return Ok(false);
+    }
+    if !response.pin_protocols.iter().any(|ver| *ver == 1) {
+        Err(FidoErrorKind::DeviceUnsupported)?
+    }
+    Ok(true)
+}
+
+fn check_u2f_version(response: &[u8]) -> FidoResult<()> {
+    if u2f::decode_version(response)? != "U2F_V2" {
+        Err(FidoErrorKind::DeviceUnsupported)?
+    }
+    Ok(())
+}
+
+/// Check the arguments shared by `make_credential` and `get_assertion`.
+fn check_request(
+    needs_pin: bool,
+    pin_token: &Option<crypto::PinToken>,
+    client_data_hash: &[u8],
+) -> FidoResult<()> {
+    if needs_pin && pin_token.is_none() {
+        Err(FidoErrorKind::PinRequired)?
+    }
+    if client_data_hash.len() != 32 {
+        Err(FidoErrorKind::CborEncode)?
+    }
+    Ok(())
+}
+
+fn key_agreement_request() -> cbor::ClientPinRequest<'static> {
+    let mut request = cbor::ClientPinRequest::default();
+    request.pin_protocol = 1;
+    request.sub_command = 0x02; // getKeyAgreement
+    request
+}
+
+fn client_pin_from_response(response: cbor::Response) -> FidoResult<cbor::ClientPinResponse> {
+    match response {
+        cbor::Response::ClientPin(response) => Ok(response),
+        _ => Err(FidoErrorKind::CborDecode.into()),
+    }
+}
+
+fn shared_secret_from_response(response: cbor::Response) -> FidoResult<crypto::SharedSecret> {
+    match client_pin_from_response(response)?.key_agreement {
+        Some(key_agreement) => crypto::SharedSecret::new(&key_agreement),
+        None => Err(FidoErrorKind::CborDecode)?,
+    }
+}
+
+fn pin_token_request<'a>(
+    shared_secret: &'a crypto::SharedSecret,
+    pin: &str,
+) -> FidoResult<cbor::ClientPinRequest<'a>> {
+    let mut request = cbor::ClientPinRequest::default();
+    request.pin_protocol = 1;
+    request.sub_command = 0x05; // getPINToken
+    request.key_agreement = Some(&shared_secret.public_key);
+    request.pin_hash_enc = Some(shared_secret.encrypt_pin(pin)?);
+    Ok(request)
+}
+
+fn pin_token_from_response(
+    shared_secret: &crypto::SharedSecret,
+    response: cbor::Response,
+) -> FidoResult<crypto::PinToken> {
+    match client_pin_from_response(response)?.pin_token {
+        Some(mut pin_token) => shared_secret.decrypt_token(&mut pin_token),
+        None => Err(FidoErrorKind::CborDecode)?,
+    }
+}
+
+/// Authenticate a request with the PIN token, if the device was unlocked.
+fn pin_auth(pin_token: &Option<crypto::PinToken>, client_data_hash: &[u8]) -> Option<[u8; 16]> {
+    pin_token.as_ref().map(|token| token.auth(client_data_hash))
+}
+
+fn make_credential_request<'a>(
+    rp_id: &'a str,
+    user_id: &'a [u8],
+    user_name: &'a str,
+    client_data_hash: &'a [u8],
+    pin_auth: Option<&'a [u8]>,
+) -> cbor::MakeCredentialRequest<'a> {
+    let rp = cbor::PublicKeyCredentialRpEntity {
+        id: rp_id,
+        name: None,
+        icon: None,
+    };
+    let user = cbor::PublicKeyCredentialUserEntity {
+        id: user_id,
+        name: user_name,
+        icon: None,
+        display_name: None,
+    };
+    cbor::MakeCredentialRequest {
+        client_data_hash,
+        rp,
+        user,
+        pub_key_cred_params: &[("public-key", -7)],
+        exclude_list: Default::default(),
+        extensions: Default::default(),
+        options: Some(cbor::AuthenticatorOptions {
+            rk: false,
+            uv: true,
+        }),
+        pin_auth,
+        pin_protocol: pin_auth.and(Some(0x01)),
+    }
+}
+
+fn credential_from_response(rp_id: &str, response: cbor::Response) -> FidoResult<FidoCredential> {
+    let response = match response {
+        cbor::Response::MakeCredential(response) => response,
+        _ => Err(FidoErrorKind::CborDecode)?,
+    };
+    let public_key = cbor::P256Key::from_cose(
+        &response
+            .auth_data
+            .attested_credential_data
+            .credential_public_key,
+    )?
+        .bytes();
+    Ok(FidoCredential {
+        id: response.auth_data.attested_credential_data.credential_id,
+        rp_id: String::from(rp_id),
+        public_key: Vec::from(&public_key[..]),
+    })
+}
+
+fn allow_list(credential: &FidoCredential) -> [cbor::PublicKeyCredentialDescriptor; 1] {
+    [
+        cbor::PublicKeyCredentialDescriptor {
+            cred_type: String::from("public-key"),
+            id: credential.id.clone(),
+        },
+    ]
+}
+
+fn get_assertion_request<'a>(
+    credential: &'a FidoCredential,
+    client_data_hash: &'a [u8],
+    allow_list: &'a [cbor::PublicKeyCredentialDescriptor],
+    pin_auth: Option<[u8; 16]>,
+) -> cbor::GetAssertionRequest<'a> {
+    cbor::GetAssertionRequest {
+        rp_id: &credential.rp_id,
+        client_data_hash,
+        allow_list,
+        extensions: Default::default(),
+        options: Some(cbor::AuthenticatorOptions {
+            rk: false,
+            uv: true,
+        }),
+        pin_auth,
+        pin_protocol: pin_auth.and(Some(0x01)),
+    }
+}
+
+fn verify_assertion(
+    credential: &FidoCredential,
+    client_data_hash: &[u8],
+    response: cbor::Response,
+) -> FidoResult<bool> {
+    let response = match response {
+        cbor::Response::GetAssertion(response) => response,
+        _ => Err(FidoErrorKind::CborDecode)?,
+    };
+    Ok(crypto::verify_signature(
+        &credential.public_key,
+        client_data_hash,
+        &response.auth_data_bytes,
+        &response.signature,
+    ))
+}
+
+fn u2f_register_request(rp_id: &str, client_data_hash: &[u8]) -> FidoResult<Vec<u8>> {
+    let application = u2f::application_parameter(rp_id);
+    let request = u2f::RegisterRequest {
+        challenge: client_data_hash,
+        application: &application,
+    };
+    request.encode()
+}
+
+fn u2f_credential_from_response(rp_id: &str, response: &[u8]) -> FidoResult<FidoCredential> {
+    let response = u2f::RegisterResponse::decode(response)?;
+    Ok(FidoCredential {
+        id: response.key_handle,
+        rp_id: String::from(rp_id),
+        public_key: response.public_key,
+    })
+}
+
+fn u2f_authenticate_request(
+    credential: &FidoCredential,
+    client_data_hash: &[u8],
+) -> FidoResult<Vec<u8>> {
+    let application = u2f::application_parameter(&credential.rp_id);
+    let request = u2f::AuthenticateRequest {
+        challenge: client_data_hash,
+        application: &application,
+        key_handle: &credential.id,
+    };
+    request.encode()
+}
+
+fn u2f_verify_response(
+    credential: &FidoCredential,
+    client_data_hash: &[u8],
+    response: &[u8],
+) -> FidoResult<bool> {
+    let application = u2f::application_parameter(&credential.rp_id);
+    let response = u2f::AuthenticateResponse::decode(response)?;
+    Ok(crypto::verify_signature(
+        &credential.public_key,
+        client_data_hash,
+        &response.signed_data(&application),
+        &response.signature,
+    ))
+}
+
+/// Whether the authenticator rejected a request for a reason that may go away
+/// when the request is repeated.
+fn is_transient(err: &FidoError) -> bool {
+    matches!(
+        err.cause().and_then(|cause| cause.downcast_ref::<packet::CtapError>()),
+        Some(&packet::CtapError::ChannelBusy) | Some(&packet::CtapError::MsgTimeout)
+    )
+}
+
+/// The status code of a CTAP2 error response, which may be wrapped in the
+/// context of a decoding error.
+fn cbor_status(err: &FidoError) -> Option<u8> {
+    match err.kind() {
+        FidoErrorKind::CborError(status) => Some(status),
+        _ => err.cause()
+            .and_then(|cause| cause.downcast_ref::<FidoError>())
+            .and_then(cbor_status),
+    }
+}
+
+fn remaining(deadline: Option<Instant>) -> Option<Duration> {
+    deadline.map(|deadline| deadline.saturating_duration_since(Instant::now()))
+}
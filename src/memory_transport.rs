@@ -0,0 +1,143 @@
+// This file is part of ctap, a Rust implementation of the FIDO2 protocol.
+// Copyright (c) Ariën Holthuizen <contact@ardaxi.com>
+// Licensed under the Apache License, Version 2.0, <LICENSE-APACHE or
+// http://apache.org/licenses/LICENSE-2.0> or the MIT license <LICENSE-MIT or
+// http://opensource.org/licenses/MIT>, at your option. This file may not be
+// copied, modified, or distributed except according to those terms.
+use std::cmp;
+use std::collections::VecDeque;
+use std::io;
+use std::sync::{Arc, Mutex};
+#[cfg(feature = "async")]
+use std::task::{Context, Poll};
+use std::time::Duration;
+
+use super::authenticator::VirtualAuthenticator;
+use super::channels::{ChannelManager, Message};
+use super::error::FidoResult;
+use super::packet::{CtapCommand, CtapError, DeviceCapabilities};
+#[cfg(feature = "async")]
+use super::transport::AsyncTransport;
+use super::transport::Transport;
+
+static REPORT_SIZE: usize = 64;
+
+/// A transport that connects `FidoDevice` to a `VirtualAuthenticator` in the
+/// same process, without any hardware involved. With the `async` feature it
+/// can be used with `AsyncFidoDevice` as well.
+///
+/// Requests are handled as soon as their last packet is written, so the
+/// authenticator never sends keepalive messages and can't be cancelled.
+pub struct MemoryTransport {
+    authenticator: Arc<Mutex<VirtualAuthenticator>>,
+    channels: Arc<Mutex<ChannelManager>>,
+    responses: VecDeque<Vec<u8>>,
+}
+
+impl MemoryTransport {
+    pub fn new(authenticator: VirtualAuthenticator) -> Self {
+        // The authenticator doesn't implement CTAP1.
+        let capabilities = DeviceCapabilities {
+            wink: true,
+            cbor: true,
+            nmsg: true,
+        };
+        MemoryTransport {
+            authenticator: Arc::new(Mutex::new(authenticator)),
+            channels: Arc::new(Mutex::new(ChannelManager::new(REPORT_SIZE, capabilities))),
+            responses: VecDeque::new(),
+        }
+    }
+
+    /// Open another connection to the same authenticator, as a second
+    /// application would. Each connection only receives the responses to the
+    /// reports written through it.
+    pub fn connect(&self) -> Self {
+        MemoryTransport {
+            authenticator: self.authenticator.clone(),
+            channels: self.channels.clone(),
+            responses: VecDeque::new(),
+        }
+    }
+
+    /// Get the authenticator behind this transport, to inspect or change its
+    /// state while a `FidoDevice` is using it.
+    pub fn authenticator(&self) -> Arc<Mutex<VirtualAuthenticator>> {
+        self.authenticator.clone()
+    }
+}
+
+impl Transport for MemoryTransport {
+    fn write_report(&mut self, report: &[u8]) -> io::Result<()> {
+        if report.len() != REPORT_SIZE + 1 || report[0] != 0 {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "invalid report",
+            ));
+        }
+        let mut channels = self.channels.lock().unwrap();
+        if let Some(message) = channels.handle_report(&report[1..]) {
+            let mut authenticator = self.authenticator.lock().unwrap();
+            handle_message(&mut channels, &mut authenticator, message)
+                .map_err(|err| io::Error::new(io::ErrorKind::InvalidData, err.to_string()))?;
+        }
+        while let Some(report) = channels.next_report() {
+            self.responses.push_back(report);
+        }
+        Ok(())
+    }
+
+    fn read_report(&mut self, buf: &mut [u8], _timeout: Option<Duration>) -> io::Result<usize> {
+        let report = self.responses.pop_front().ok_or_else(|| {
+            io::Error::new(
+                io::ErrorKind::TimedOut,
+                "no response from the authenticator",
+            )
+        })?;
+        let len = cmp::min(buf.len(), report.len());
+        buf[..len].copy_from_slice(&report[..len]);
+        Ok(len)
+    }
+}
+
+// Responses are ready as soon as the request is written, so polling never has
+// to wait.
+#[cfg(feature = "async")]
+impl AsyncTransport for MemoryTransport {
+    fn poll_write_report(
+        &mut self,
+        _cx: &mut Context<'_>,
+        report: &[u8],
+    ) -> Poll<io::Result<()>> {
+        Poll::Ready(self.write_report(report))
+    }
+
+    fn poll_read_report(
+        &mut self,
+        _cx: &mut Context<'_>,
+        buf: &mut [u8],
+    ) -> Poll<io::Result<usize>> {
+        Poll::Ready(self.read_report(buf, None))
+    }
+}
+
+/// Answer a message received by `channels` with `authenticator`. Requests are
+/// handled right away, so there is never one left to cancel.
+pub fn handle_message(
+    channels: &mut ChannelManager,
+    authenticator: &mut VirtualAuthenticator,
+    message: Message,
+) -> FidoResult<()> {
+    match message.cmd {
+        CtapCommand::Cbor => {
+            let response = authenticator.handle_request(&message.payload);
+            channels.respond(message.cid, CtapCommand::Cbor, &response)
+        }
+        CtapCommand::Wink => channels.respond(message.cid, CtapCommand::Wink, &[]),
+        CtapCommand::Cancel => Ok(()),
+        _ => {
+            channels.error(message.cid, CtapError::InvalidCmd);
+            Ok(())
+        }
+    }
+}
@@ -5,15 +5,21 @@
 // http://opensource.org/licenses/MIT>, at your option. This file may not be
 // copied, modified, or distributed except according to those terms.
 use num_traits::{FromPrimitive, ToPrimitive};
-use failure::ResultExt;
+use failure::{Fail, ResultExt};
 use super::error::*;
+use super::transport::Transport;
 
-use std::io::{Read, Write};
+use std::cmp;
+use std::io;
+use std::time::{Duration, Instant};
 
-static FRAME_INIT: u8 = 0x80;
+pub(crate) static FRAME_INIT: u8 = 0x80;
+static CAPABILITY_WINK: u8 = 0x01;
+static CAPABILITY_CBOR: u8 = 0x04;
+static CAPABILITY_NMSG: u8 = 0x08;
 
 #[repr(u8)]
-#[derive(FromPrimitive, ToPrimitive, PartialEq)]
+#[derive(FromPrimitive, ToPrimitive, Clone, Debug, PartialEq)]
 pub enum CtapCommand {
     Invalid = 0x00,
     Ping = 0x01,
@@ -36,8 +42,89 @@ impl CtapCommand {
     }
 }
 
+/// The status reported by an authenticator in a keepalive message while it is
+/// still working on a request.
 #[repr(u8)]
-#[derive(FromPrimitive, Fail, Debug)]
+#[derive(FromPrimitive, Clone, Copy, PartialEq, Eq, Debug)]
+pub enum KeepaliveStatus {
+    /// The authenticator is still processing the current request.
+    Processing = 0x01,
+    /// The authenticator is waiting for the user to touch it.
+    UpNeeded = 0x02,
+}
+
+/// The capabilities an authenticator advertises in its CTAPHID_INIT response.
+#[derive(Clone, Copy, Default, PartialEq, Eq, Debug)]
+pub struct DeviceCapabilities {
+    /// The authenticator implements CTAPHID_WINK.
+    pub wink: bool,
+    /// The authenticator implements CTAPHID_CBOR, and thereby CTAP2.
+    pub cbor: bool,
+    /// The authenticator does *not* implement CTAPHID_MSG, and thereby CTAP1.
+    pub nmsg: bool,
+}
+
+impl DeviceCapabilities {
+    pub fn from_flags(flags: u8) -> Self {
+        DeviceCapabilities {
+            wink: flags & CAPABILITY_WINK != 0,
+            cbor: flags & CAPABILITY_CBOR != 0,
+            nmsg: flags & CAPABILITY_NMSG != 0,
+        }
+    }
+
+    pub fn to_flags(&self) -> u8 {
+        let mut flags = 0;
+        if self.wink {
+            flags |= CAPABILITY_WINK;
+        }
+        if self.cbor {
+            flags |= CAPABILITY_CBOR;
+        }
+        if self.nmsg {
+            flags |= CAPABILITY_NMSG;
+        }
+        flags
+    }
+}
+
+/// The response of an authenticator to CTAPHID_INIT.
+#[derive(Clone, Default, Debug)]
+pub struct InitResponse {
+    /// The channel allocated to this application.
+    pub channel_id: [u8; 4],
+    /// The version of the CTAPHID protocol implemented by the authenticator.
+    pub protocol_version: u8,
+    /// The major version of the authenticator's firmware.
+    pub major_version: u8,
+    /// The minor version of the authenticator's firmware.
+    pub minor_version: u8,
+    /// The build version of the authenticator's firmware.
+    pub build_version: u8,
+    /// The capabilities advertised by the authenticator.
+    pub capabilities: DeviceCapabilities,
+}
+
+impl InitResponse {
+    pub fn decode(nonce: &[u8], response: &[u8]) -> FidoResult<Self> {
+        if response.len() < 17 || response[0..8] != *nonce {
+            Err(FidoErrorKind::ParseCtap)?
+        }
+        let mut channel_id = [0; 4];
+        channel_id.copy_from_slice(&response[8..12]);
+        Ok(InitResponse {
+            channel_id,
+            protocol_version: response[12],
+            major_version: response[13],
+            minor_version: response[14],
+            build_version: response[15],
+            capabilities: DeviceCapabilities::from_flags(response[16]),
+        })
+    }
+}
+
+#[repr(u8)]
+#[derive(FromPrimitive, Fail, Debug, Clone, Copy, PartialEq, Eq)]
 pub enum CtapError {
     #[fail(display = "The command in the request is invalid")]
     InvalidCmd = 0x01,
@@ -53,25 +140,27 @@ pub enum CtapError {
     ChannelBusy = 0x06,
     #[fail(display = "Command requires channel lock ")]
     LockRequired = 0x0A,
-    #[fail(display = "Reserved error")]
-    NA = 0x0B,
+    #[fail(display = "The channel is not allocated")]
+    InvalidChannel = 0x0B,
     #[fail(display = "Unspecified error")]
     Other = 0x7F,
 }
 
-pub fn write_init_packet<W: Write>(
-    mut writer: W,
+/// Encode an initialization packet as an output report, including the report
+/// ID the device expects.
+pub fn encode_init_packet(
+    report_id: Option<u8>,
     report_size: usize,
     cid: &[u8],
     cmd: &CtapCommand,
     size: u16,
     payload: &[u8],
-) -> FidoResult<()> {
+) -> FidoResult<Vec<u8>> {
     if cid.len() != 4 {
         Err(FidoErrorKind::WritePacket)?
     }
-    let mut packet = Vec::with_capacity(report_size);
-    packet.push(0);
+    let mut packet = Vec::with_capacity(report_size + 1);
+    packet.push(report_id.unwrap_or(0));
     packet.extend_from_slice(cid);
     packet.push(FRAME_INIT | cmd.to_wire_format());
     packet.push(((size >> 8) & 0xff) as u8);
@@ -81,10 +170,7 @@ pub fn write_init_packet<W: Write>(
         Err(FidoErrorKind::WritePacket)?
     }
     packet.resize(report_size + 1, 0);
-    writer.write_all(&packet).context(
-        FidoErrorKind::WritePacket,
-    )?;
-    Ok(())
+    Ok(packet)
 }
 
 pub struct InitPacket {
@@ -95,12 +181,10 @@ pub struct InitPacket {
 }
 
 impl InitPacket {
-    pub fn from_reader<R: Read>(mut reader: R, report_size: usize) -> FidoResult<InitPacket> {
-        let mut buf = Vec::with_capacity(report_size);
-        buf.resize(report_size, 0);
-        reader.read_exact(&mut buf[0..report_size]).context(
-            FidoErrorKind::ReadPacket,
-        )?;
+    /// Decode an initialization packet from the data of an input report, which
+    /// must be at least 7 bytes long.
+    pub fn from_report(mut buf: Vec<u8>) -> InitPacket {
+        let report_size = buf.len();
         let mut cid = [0; 4];
         cid.copy_from_slice(&buf[0..4]);
         let cmd = match CtapCommand::from_u8(buf[4] ^ FRAME_INIT) {
@@ -114,27 +198,29 @@ impl InitPacket {
             size as usize + 7
         };
         let payload = buf.drain(7..payload_end).collect();
-        Ok(InitPacket {
+        InitPacket {
             cid,
             cmd,
             size,
             payload,
-        })
+        }
     }
 }
 
-pub fn write_cont_packet<W: Write>(
-    mut writer: W,
+/// Encode a continuation packet as an output report, including the report ID
+/// the device expects.
+pub fn encode_cont_packet(
+    report_id: Option<u8>,
     report_size: usize,
     cid: &[u8],
     seq: u8,
     payload: &[u8],
-) -> FidoResult<()> {
+) -> FidoResult<Vec<u8>> {
     if cid.len() != 4 {
         Err(FidoErrorKind::WritePacket)?
     }
-    let mut packet = Vec::with_capacity(report_size);
-    packet.push(0);
+    let mut packet = Vec::with_capacity(report_size + 1);
+    packet.push(report_id.unwrap_or(0));
     packet.extend_from_slice(cid);
     packet.push(seq);
     packet.extend_from_slice(payload);
@@ -142,10 +228,34 @@ pub fn write_cont_packet<W: Write>(
         Err(FidoErrorKind::WritePacket)?
     }
     packet.resize(report_size + 1, 0);
-    writer.write_all(&packet).context(
-        FidoErrorKind::WritePacket,
-    )?;
-    Ok(())
+    Ok(packet)
+}
+
+/// The size of the largest message that fits in an initialization packet and
+/// 128 continuation packets, the most a 7-bit sequence number can count.
+pub fn max_message_size(report_size: usize) -> usize {
+    cmp::min((report_size - 7) + 0x80 * (report_size - 5), u16::MAX as usize)
+}
+
+/// Encode a message as the output reports carrying it, an initialization
+/// packet followed by as many continuation packets as needed.
+pub fn encode_message(
+    report_id: Option<u8>,
+    report_size: usize,
+    cid: &[u8],
+    cmd: &CtapCommand,
+    payload: &[u8],
+) -> FidoResult<Vec<Vec<u8>>> {
+    if payload.len() > max_message_size(report_size) {
+        Err(FidoErrorKind::WritePacket)?
+    }
+    let to_send = payload.len() as u16;
+    let (frame, payload) = payload.split_at(cmp::min(payload.len(), report_size - 7));
+    let mut packets = vec![encode_init_packet(report_id, report_size, cid, cmd, to_send, frame)?];
+    for (seq, frame) in (0..0x80).zip(payload.chunks(report_size - 5)) {
+        packets.push(encode_cont_packet(report_id, report_size, cid, seq, frame)?);
+    }
+    Ok(packets)
 }
 
 pub struct ContPacket {
@@ -155,16 +265,10 @@ pub struct ContPacket {
 }
 
 impl ContPacket {
-    pub fn from_reader<R: Read>(
-        mut reader: R,
-        report_size: usize,
-        expected_data: usize,
-    ) -> FidoResult<ContPacket> {
-        let mut buf = Vec::with_capacity(report_size);
-        buf.resize(report_size, 0);
-        reader.read_exact(&mut buf[0..report_size]).context(
-            FidoErrorKind::ReadPacket,
-        )?;
+    /// Decode a continuation packet from the data of an input report, which
+    /// must be at least 5 bytes long.
+    pub fn from_report(mut buf: Vec<u8>, expected_data: usize) -> ContPacket {
+        let report_size = buf.len();
         let mut cid = [0; 4];
         cid.copy_from_slice(&buf[0..4]);
         let seq = buf[4];
@@ -174,6 +278,283 @@ impl ContPacket {
             expected_data + 5
         };
         let payload = buf.drain(5..payload_end).collect();
-        Ok(ContPacket { cid, seq, payload })
+        ContPacket { cid, seq, payload }
+    }
+}
+
+/// What a packet received by a `Reassembler` amounted to.
+#[derive(Debug, PartialEq)]
+pub enum Received {
+    /// The packet didn't complete the message, or didn't belong to it.
+    Nothing,
+    /// The authenticator is still processing the request.
+    Keepalive(Option<KeepaliveStatus>),
+    /// The packet completed the message, which is returned.
+    Message(Vec<u8>),
+}
+
+/// Puts together the response to a command from the packets received on a
+/// channel.
+///
+/// Initialization packets are told apart from continuation packets by the high
+/// bit of their fifth byte, so stray packets don't derail the response. Packets
+/// for other channels or commands are skipped, as are continuation packets
+/// received before the response started. An ERROR packet on the channel fails
+/// the response at any point.
+pub struct Reassembler {
+    cid: [u8; 4],
+    cmd: CtapCommand,
+    // The size of the message and the sequence number of the next continuation
+    // packet, once the initialization packet has been received.
+    started: Option<(usize, u8)>,
+    data: Vec<u8>,
+}
+
+impl Reassembler {
+    pub fn new(cid: [u8; 4], cmd: CtapCommand) -> Self {
+        Reassembler {
+            cid,
+            cmd,
+            started: None,
+            data: Vec::new(),
+        }
+    }
+
+    /// Handle the data of an input report, which must be at least 5 bytes long.
+    pub fn push(&mut self, report: Vec<u8>) -> FidoResult<Received> {
+        if report[4] & FRAME_INIT != 0 {
+            if report.len() < 7 {
+                Err(FidoErrorKind::ReadPacket)?
+            }
+            self.push_init(InitPacket::from_report(report))
+        } else {
+            let missing = match self.started {
+                Some((size, _)) => size - self.data.len(),
+                None => 0,
+            };
+            self.push_cont(ContPacket::from_report(report, missing))
+        }
+    }
+
+    fn push_init(&mut self, packet: InitPacket) -> FidoResult<Received> {
+        if packet.cid != self.cid {
+            return Ok(Received::Nothing);
+        }
+        if packet.cmd == CtapCommand::Error {
+            let code = packet.payload.first().cloned().unwrap_or(0);
+            protocol_log!(trace, "received error {:#04x} on channel {:02x?}", code, packet.cid);
+            Err(CtapError::from_u8(code)
+                .unwrap_or(CtapError::Other)
+                .context(FidoErrorKind::ParseCtap))?
+        }
+        if packet.cmd == CtapCommand::Keepalive {
+            let status = packet.payload.first().cloned().and_then(KeepaliveStatus::from_u8);
+            protocol_log!(trace, "received keepalive {:?} on channel {:02x?}", status, packet.cid);
+            return Ok(Received::Keepalive(status));
+        }
+        if packet.cmd != self.cmd {
+            return Ok(Received::Nothing);
+        }
+        // A new initialization packet starts the response over.
+        self.started = Some((packet.size as usize, 0));
+        self.data = packet.payload;
+        Ok(self.check_complete())
+    }
+
+    fn push_cont(&mut self, packet: ContPacket) -> FidoResult<Received> {
+        let seq = match self.started {
+            Some((_, seq)) if packet.cid == self.cid => seq,
+            _ => return Ok(Received::Nothing),
+        };
+        if packet.seq != seq {
+            Err(FidoErrorKind::InvalidSequence)?
+        }
+        self.data.extend(&packet.payload);
+        if let Some((_, ref mut seq)) = self.started {
+            *seq += 1;
+        }
+        Ok(self.check_complete())
+    }
+
+    fn check_complete(&mut self) -> Received {
+        match self.started {
+            Some((size, _)) if self.data.len() >= size => {
+                protocol_log!(
+                    trace,
+                    "received {:?} on channel {:02x?}, {} bytes",
+                    self.cmd,
+                    self.cid,
+                    size
+                );
+                self.started = None;
+                Received::Message(std::mem::take(&mut self.data))
+            }
+            _ => Received::Nothing,
+        }
+    }
+}
+
+/// Read the data of an input report carrying a CTAPHID packet, waiting at most
+/// `timeout` for it.
+pub fn read_packet<T: Transport + ?Sized>(
+    transport: &mut T,
+    report_size: usize,
+    timeout: Option<Duration>,
+) -> FidoResult<Vec<u8>> {
+    read_report(transport, report_size, 5, timeout)
+}
+
+/// Check an input report of which `read` bytes were read into `buf`, and strip
+/// its report ID if the device uses numbered reports. Returns `false` if the
+/// report has a different ID, which happens on composite devices where reports
+/// from other collections arrive on the same device.
+pub fn check_report(
+    report_id: Option<u8>,
+    buf: &mut Vec<u8>,
+    read: usize,
+    header_size: usize,
+) -> FidoResult<bool> {
+    let report_id = match report_id {
+        Some(report_id) => report_id,
+        None if read < header_size => Err(FidoErrorKind::ReadPacket)?,
+        None => return Ok(true),
+    };
+    if read == 0 || buf[0] != report_id {
+        return Ok(false);
+    }
+    if read < header_size + 1 {
+        Err(FidoErrorKind::ReadPacket)?
+    }
+    buf.remove(0);
+    Ok(true)
+}
+
+fn read_report<T: Transport + ?Sized>(
+    transport: &mut T,
+    report_size: usize,
+    header_size: usize,
+    timeout: Option<Duration>,
+) -> FidoResult<Vec<u8>> {
+    let report_id = transport.input_report_id();
+    let deadline = timeout.map(|timeout| Instant::now() + timeout);
+    let mut buf = vec![0; report_size + report_id.is_some() as usize];
+    loop {
+        let timeout = deadline.map(|deadline| deadline.saturating_duration_since(Instant::now()));
+        let read = match transport.read_report(&mut buf, timeout) {
+            Err(ref err) if err.kind() == io::ErrorKind::TimedOut => Err(FidoErrorKind::Timeout)?,
+            result => result.context(FidoErrorKind::ReadPacket)?,
+        };
+        if check_report(report_id, &mut buf, read, header_size)? {
+            return Ok(buf);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    static NONCE: [u8; 8] = [1, 2, 3, 4, 5, 6, 7, 8];
+
+    fn init_response(nonce: &[u8]) -> Vec<u8> {
+        let mut response = nonce.to_vec();
+        response.extend_from_slice(&[0xca, 0xfe, 0xba, 0xbe, 2, 5, 1, 9, 0x0d]);
+        response
+    }
+
+    #[test]
+    fn decode_init_response() {
+        let response = InitResponse::decode(&NONCE, &init_response(&NONCE)).unwrap();
+        assert_eq!(response.channel_id, [0xca, 0xfe, 0xba, 0xbe]);
+        assert_eq!(response.protocol_version, 2);
+        assert_eq!(
+            (response.major_version, response.minor_version, response.build_version),
+            (5, 1, 9)
+        );
+        assert_eq!(
+            response.capabilities,
+            DeviceCapabilities {
+                wink: true,
+                cbor: true,
+                nmsg: true,
+            }
+        );
+    }
+
+    #[test]
+    fn init_response_for_another_nonce() {
+        let response = init_response(&[8, 7, 6, 5, 4, 3, 2, 1]);
+        let err = InitResponse::decode(&NONCE, &response).unwrap_err();
+        assert_eq!(err.kind(), FidoErrorKind::ParseCtap);
+    }
+
+    #[test]
+    fn short_init_response() {
+        let response = init_response(&NONCE);
+        let err = InitResponse::decode(&NONCE, &response[..16]).unwrap_err();
+        assert_eq!(err.kind(), FidoErrorKind::ParseCtap);
+    }
+
+    #[test]
+    fn capability_flags_round_trip() {
+        for flags in 0..0x10 {
+            // Bit 1 isn't a capability.
+            let flags = flags & !0x02;
+            assert_eq!(DeviceCapabilities::from_flags(flags).to_flags(), flags);
+        }
+        // Unknown flags are ignored.
+        assert_eq!(DeviceCapabilities::from_flags(0xf2), DeviceCapabilities::default());
+    }
+
+    #[test]
+    fn report_id_is_prepended() {
+        let cid = [1, 2, 3, 4];
+        let packet = encode_init_packet(Some(5), 16, &cid, &CtapCommand::Ping, 3, &[7; 3]).unwrap();
+        assert_eq!(packet, [5, 1, 2, 3, 4, 0x81, 0, 3, 7, 7, 7, 0, 0, 0, 0, 0, 0]);
+        let packet = encode_cont_packet(Some(5), 16, &cid, 0, &[7; 11]).unwrap();
+        assert_eq!(packet, [5, 1, 2, 3, 4, 0, 7, 7, 7, 7, 7, 7, 7, 7, 7, 7, 7]);
+        // Unnumbered reports start with a zero byte that isn't sent.
+        let packet = encode_cont_packet(None, 16, &cid, 1, &[]).unwrap();
+        assert_eq!(packet, [0, 1, 2, 3, 4, 1, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0]);
+    }
+
+    #[test]
+    fn payload_must_fit_in_the_report() {
+        let cid = [1, 2, 3, 4];
+        let err = encode_init_packet(Some(5), 16, &cid, &CtapCommand::Ping, 10, &[7; 10]);
+        assert_eq!(err.unwrap_err().kind(), FidoErrorKind::WritePacket);
+        let err = encode_cont_packet(Some(5), 16, &cid, 0, &[7; 12]);
+        assert_eq!(err.unwrap_err().kind(), FidoErrorKind::WritePacket);
+    }
+
+    #[test]
+    fn report_id_is_stripped() {
+        let mut buf = vec![5, 1, 2, 3, 4, 0x81, 0, 0];
+        assert!(check_report(Some(5), &mut buf, 8, 7).unwrap());
+        assert_eq!(buf, [1, 2, 3, 4, 0x81, 0, 0]);
+    }
+
+    #[test]
+    fn reports_with_other_ids_are_skipped() {
+        let mut buf = vec![6, 1, 2, 3, 4, 0x81, 0, 0];
+        assert!(!check_report(Some(5), &mut buf, 8, 7).unwrap());
+        assert_eq!(buf, [6, 1, 2, 3, 4, 0x81, 0, 0]);
+        assert!(!check_report(Some(5), &mut buf, 0, 7).unwrap());
+    }
+
+    #[test]
+    fn unnumbered_reports_are_kept() {
+        let mut buf = vec![5, 1, 2, 3, 4, 0x81, 0];
+        assert!(check_report(None, &mut buf, 7, 7).unwrap());
+        assert_eq!(buf, [5, 1, 2, 3, 4, 0x81, 0]);
+    }
+
+    #[test]
+    fn short_reports() {
+        let mut buf = vec![5, 1, 2, 3, 4, 0x81, 0, 0];
+        let err = check_report(Some(5), &mut buf, 7, 7).unwrap_err();
+        assert_eq!(err.kind(), FidoErrorKind::ReadPacket);
+        let err = check_report(None, &mut buf, 6, 7).unwrap_err();
+        assert_eq!(err.kind(), FidoErrorKind::ReadPacket);
     }
 }
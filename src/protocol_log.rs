@@ -0,0 +1,193 @@
+// This file is part of ctap, a Rust implementation of the FIDO2 protocol.
+// Copyright (c) Ariën Holthuizen <contact@ardaxi.com>
+// Licensed under the Apache License, Version 2.0, <LICENSE-APACHE or
+// http://apache.org/licenses/LICENSE-2.0> or the MIT license <LICENSE-MIT or
+// http://opensource.org/licenses/MIT>, at your option. This file may not be
+// copied, modified, or distributed except according to those terms.
+//! Descriptions of CTAP2 requests and responses for the protocol log, in CBOR
+//! diagnostic notation. Values that would allow someone reading the log to use
+//! the authenticator, such as the PIN hash and PIN token, are left out.
+use cbor_codec::value::{Bytes, Int, Key, Text, Value};
+use cbor_codec::{Config, GenericDecoder};
+use std::collections::BTreeMap;
+use std::fmt::Write;
+use std::io::Cursor;
+
+/// The parameters of each command that must not be logged.
+fn secret_parameters(command: u8) -> &'static [u64] {
+    match command {
+        0x01 => &[0x08],             // pinAuth
+        0x02 => &[0x06],             // pinAuth
+        0x06 => &[0x04, 0x05, 0x06], // pinAuth, newPinEnc, pinHashEnc
+        _ => &[],
+    }
+}
+
+/// The members of each command's response that must not be logged.
+fn secret_members(command: u8) -> &'static [u64] {
+    match command {
+        0x06 => &[0x02], // pinToken
+        _ => &[],
+    }
+}
+
+fn command_name(command: u8) -> &'static str {
+    match command {
+        0x01 => "authenticatorMakeCredential",
+        0x02 => "authenticatorGetAssertion",
+        0x04 => "authenticatorGetInfo",
+        0x06 => "authenticatorClientPIN",
+        0x07 => "authenticatorReset",
+        0x08 => "authenticatorGetNextAssertion",
+        0x0b => "authenticatorSelection",
+        _ => "unknown command",
+    }
+}
+
+/// Describe a CTAP2 request, a command byte followed by its CBOR parameters.
+pub fn request(request: &[u8]) -> String {
+    match request.split_first() {
+        Some((&command, parameters)) => {
+            let mut out = format!("{} ({:#04x})", command_name(command), command);
+            write_diagnostic(&mut out, parameters, secret_parameters(command));
+            out
+        }
+        None => "empty request".to_string(),
+    }
+}
+
+/// Describe the response to a CTAP2 request for `command`, a status byte
+/// followed by CBOR.
+pub fn response(command: u8, response: &[u8]) -> String {
+    match response.split_first() {
+        Some((&status, members)) => {
+            let mut out = format!("status {:#04x}", status);
+            write_diagnostic(&mut out, members, secret_members(command));
+            out
+        }
+        None => "empty response".to_string(),
+    }
+}
+
+/// Decode `data` and write it in diagnostic notation, after a space. Members of
+/// a top-level map whose keys are in `secret` are replaced by a comment.
+fn write_diagnostic(out: &mut String, data: &[u8], secret: &[u64]) {
+    if data.is_empty() {
+        return;
+    }
+    out.push(' ');
+    match GenericDecoder::new(Config::default(), Cursor::new(data)).value() {
+        Ok(Value::Map(ref map)) => write_map(out, map, secret),
+        Ok(ref value) => write_value(out, value),
+        // The data isn't shown, as it might contain secrets in a form we don't
+        // recognize.
+        Err(_) => {
+            let _ = write!(out, "/ {} bytes of invalid CBOR /", data.len());
+        }
+    }
+}
+
+/// Write a map, replacing the values of the members whose keys are in
+/// `secret` by a comment.
+fn write_map(out: &mut String, map: &BTreeMap<Key, Value>, secret: &[u64]) {
+    out.push('{');
+    for (i, (key, value)) in map.iter().enumerate() {
+        if i > 0 {
+            out.push_str(", ");
+        }
+        write_key(out, key);
+        out.push_str(": ");
+        match *key {
+            Key::Int(Int::Pos(key)) if secret.contains(&key) => out.push_str("/ redacted /"),
+            _ => write_value(out, value),
+        }
+    }
+    out.push('}');
+}
+
+fn write_key(out: &mut String, key: &Key) {
+    match *key {
+        Key::Bool(value) => write_value(out, &Value::Bool(value)),
+        Key::Bytes(ref value) => write_bytes(out, value),
+        Key::Int(ref value) => write_int(out, value),
+        Key::Text(ref value) => write_text(out, value),
+    }
+}
+
+fn write_value(out: &mut String, value: &Value) {
+    let _ = match *value {
+        Value::Array(ref values) => {
+            out.push('[');
+            for (i, value) in values.iter().enumerate() {
+                if i > 0 {
+                    out.push_str(", ");
+                }
+                write_value(out, value);
+            }
+            out.push(']');
+            Ok(())
+        }
+        Value::Map(ref map) => {
+            write_map(out, map, &[]);
+            Ok(())
+        }
+        Value::Bool(value) => write!(out, "{}", value),
+        Value::Break => Ok(()),
+        Value::Bytes(ref value) => {
+            write_bytes(out, value);
+            Ok(())
+        }
+        Value::Text(ref value) => {
+            write_text(out, value);
+            Ok(())
+        }
+        Value::Int(ref value) => {
+            write_int(out, value);
+            Ok(())
+        }
+        Value::F32(value) => write!(out, "{:?}", value),
+        Value::F64(value) => write!(out, "{:?}", value),
+        Value::I8(value) => write!(out, "{}", value),
+        Value::I16(value) => write!(out, "{}", value),
+        Value::I32(value) => write!(out, "{}", value),
+        Value::I64(value) => write!(out, "{}", value),
+        Value::U8(value) => write!(out, "{}", value),
+        Value::U16(value) => write!(out, "{}", value),
+        Value::U32(value) => write!(out, "{}", value),
+        Value::U64(value) => write!(out, "{}", value),
+        Value::Null => write!(out, "null"),
+        Value::Simple(ref value) => write!(out, "simple({:?})", value),
+        Value::Tagged(ref tag, ref value) => {
+            let _ = write!(out, "{}(", tag.to());
+            write_value(out, value);
+            write!(out, ")")
+        }
+        Value::Undefined => write!(out, "undefined"),
+    };
+}
+
+fn write_int(out: &mut String, value: &Int) {
+    let _ = match *value {
+        Int::Pos(value) => write!(out, "{}", value),
+        Int::Neg(value) => write!(out, "-{}", u128::from(value) + 1),
+    };
+}
+
+fn write_bytes(out: &mut String, value: &Bytes) {
+    let chunks = match *value {
+        Bytes::Bytes(ref bytes) => vec![bytes],
+        Bytes::Chunks(ref chunks) => chunks.iter().collect(),
+    };
+    out.push_str("h'");
+    for byte in chunks.into_iter().flatten() {
+        let _ = write!(out, "{:02x}", byte);
+    }
+    out.push('\'');
+}
+
+fn write_text(out: &mut String, value: &Text) {
+    let _ = match *value {
+        Text::Text(ref text) => write!(out, "{:?}", text),
+        Text::Chunks(ref chunks) => write!(out, "{:?}", chunks.iter().cloned().collect::<String>()),
+    };
+}
@@ -0,0 +1,402 @@
+// This file is part of ctap, a Rust implementation of the FIDO2 protocol.
+// Copyright (c) Ariën Holthuizen <contact@ardaxi.com>
+// Licensed under the Apache License, Version 2.0, <LICENSE-APACHE or
+// http://apache.org/licenses/LICENSE-2.0> or the MIT license <LICENSE-MIT or
+// http://opensource.org/licenses/MIT>, at your option. This file may not be
+// copied, modified, or distributed except according to those terms.
+//! Recording the reports exchanged with an authenticator, and replaying them
+//! later without the authenticator.
+//!
+//! Traces are text files, so they can be attached to bug reports and annotated
+//! with comments starting with `#`. The first line is `ctap-trace 1`, followed
+//! by the size and ID of input and output reports, and a line per report:
+//!
+//! ```text
+//! ctap-trace 1
+//! input 64 none
+//! output 64 none
+//! out 0.000012 00ffffffff8600088a2f...
+//! in 0.001873 ffffffff860011...
+//! ```
+//!
+//! Output reports are those written to the authenticator, and include the
+//! report ID. Input reports are stored as they were read, so a read that
+//! returned no data leaves the last field empty.
+use std::collections::VecDeque;
+use std::fmt::Write as FmtWrite;
+use std::fs::File;
+use std::io::{self, BufRead, BufReader, Write};
+use std::path::Path;
+use std::time::{Duration, Instant};
+
+use super::transport::{ReportWriter, Transport};
+
+static TRACE_HEADER: &str = "ctap-trace 1";
+// Offsets in the data of a CTAPHID initialization packet.
+static CMD_OFFSET: usize = 4;
+static NONCE_OFFSET: usize = 7;
+static INIT_PACKET: u8 = 0x86;
+
+/// Whether a report was sent to or received from the authenticator.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Direction {
+    /// An output report, written to the authenticator.
+    Output,
+    /// An input report, read from the authenticator.
+    Input,
+}
+
+/// A single report in a trace.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct RecordedReport {
+    pub direction: Direction,
+    /// The time since the recording started.
+    pub timestamp: Duration,
+    pub data: Vec<u8>,
+}
+
+/// A recorded session with an authenticator.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Trace {
+    pub input_report_size: usize,
+    pub output_report_size: usize,
+    pub input_report_id: Option<u8>,
+    pub output_report_id: Option<u8>,
+    pub reports: Vec<RecordedReport>,
+}
+
+impl Trace {
+    /// Read a trace from the file at `path`.
+    pub fn open<P: AsRef<Path>>(path: P) -> io::Result<Self> {
+        Trace::read(BufReader::new(File::open(path)?))
+    }
+
+    /// Read a trace in the format written by `RecordingTransport`.
+    pub fn read<R: BufRead>(reader: R) -> io::Result<Self> {
+        let mut lines = reader.lines().filter(|line| {
+            line.as_ref()
+                .map(|line| !line.trim().is_empty() && !line.starts_with('#'))
+                .unwrap_or(true)
+        });
+        let mut next_line = || {
+            lines
+                .next()
+                .unwrap_or_else(|| Err(invalid_trace("unexpected end of trace")))
+        };
+        if next_line()?.trim() != TRACE_HEADER {
+            return Err(invalid_trace("not a ctap trace"));
+        }
+        let (input_report_size, input_report_id) = parse_report_info(&next_line()?, "input")?;
+        let (output_report_size, output_report_id) = parse_report_info(&next_line()?, "output")?;
+        let mut reports = Vec::new();
+        for line in lines {
+            reports.push(parse_report(&line?)?);
+        }
+        Ok(Trace {
+            input_report_size,
+            output_report_size,
+            input_report_id,
+            output_report_id,
+            reports,
+        })
+    }
+}
+
+/// A transport that saves every report exchanged through another transport.
+///
+/// Each report is written out as soon as it has been exchanged, so the trace is
+/// complete up to the point where something went wrong. Reads that time out
+/// are not recorded, and neither are reports written out of band through the
+/// `ReportWriter` of the inner transport, such as CTAPHID_CANCEL.
+pub struct RecordingTransport<T: Transport, W: Write> {
+    transport: T,
+    writer: W,
+    start: Instant,
+}
+
+impl<T: Transport> RecordingTransport<T, File> {
+    /// Record the reports exchanged through `transport` to a new file at
+    /// `path`, replacing any existing file.
+    pub fn create<P: AsRef<Path>>(transport: T, path: P) -> io::Result<Self> {
+        RecordingTransport::new(transport, File::create(path)?)
+    }
+}
+
+impl<T: Transport, W: Write> RecordingTransport<T, W> {
+    pub fn new(transport: T, mut writer: W) -> io::Result<Self> {
+        writeln!(writer, "{}", TRACE_HEADER)?;
+        writeln!(
+            writer,
+            "input {} {}",
+            transport.input_report_size(),
+            format_report_id(transport.input_report_id())
+        )?;
+        writeln!(
+            writer,
+            "output {} {}",
+            transport.output_report_size(),
+            format_report_id(transport.output_report_id())
+        )?;
+        writer.flush()?;
+        Ok(RecordingTransport {
+            transport,
+            writer,
+            start: Instant::now(),
+        })
+    }
+
+    /// Stop recording, returning the transport and the writer.
+    pub fn into_inner(self) -> (T, W) {
+        (self.transport, self.writer)
+    }
+
+    fn record(&mut self, direction: Direction, data: &[u8]) -> io::Result<()> {
+        let timestamp = self.start.elapsed();
+        let mut line = String::with_capacity(data.len() * 2 + 20);
+        let _ = write!(
+            line,
+            "{} {}.{:06} ",
+            match direction {
+                Direction::Output => "out",
+                Direction::Input => "in",
+            },
+            timestamp.as_secs(),
+            timestamp.subsec_micros()
+        );
+        for byte in data {
+            let _ = write!(line, "{:02x}", byte);
+        }
+        line.push('\n');
+        self.writer.write_all(line.as_bytes())?;
+        self.writer.flush()
+    }
+}
+
+impl<T: Transport, W: Write> Transport for RecordingTransport<T, W> {
+    fn write_report(&mut self, report: &[u8]) -> io::Result<()> {
+        self.transport.write_report(report)?;
+        self.record(Direction::Output, report)
+    }
+
+    fn read_report(&mut self, buf: &mut [u8], timeout: Option<Duration>) -> io::Result<usize> {
+        let read = self.transport.read_report(buf, timeout)?;
+        self.record(Direction::Input, &buf[..read])?;
+        Ok(read)
+    }
+
+    fn input_report_size(&self) -> usize {
+        self.transport.input_report_size()
+    }
+
+    fn output_report_size(&self) -> usize {
+        self.transport.output_report_size()
+    }
+
+    fn input_report_id(&self) -> Option<u8> {
+        self.transport.input_report_id()
+    }
+
+    fn output_report_id(&self) -> Option<u8> {
+        self.transport.output_report_id()
+    }
+
+    fn report_writer(&self) -> Option<Box<dyn ReportWriter>> {
+        self.transport.report_writer()
+    }
+}
+
+/// A transport that plays back a recorded session, in place of the
+/// authenticator it was recorded with.
+///
+/// Input reports are returned in the order they were recorded, regardless of
+/// their timestamps. When the host should have written a report first, or at
+/// the end of the trace, reads time out as if the authenticator didn't answer.
+///
+/// The nonce of CTAPHID_INIT is random, so it is replaced in the recorded
+/// response by the one the host actually sent. Other random values, such as
+/// key agreement keys, are not replaced. Hosts that depend on those won't see
+/// the same results as when the trace was recorded.
+pub struct ReplayTransport {
+    trace: Trace,
+    reports: VecDeque<RecordedReport>,
+    verify_output: bool,
+    // The nonce of the last recorded INIT request, and the one sent instead.
+    nonce: Option<(Vec<u8>, Vec<u8>)>,
+}
+
+impl ReplayTransport {
+    pub fn new(mut trace: Trace) -> Self {
+        let reports = trace.reports.drain(..).collect();
+        ReplayTransport {
+            trace,
+            reports,
+            verify_output: false,
+            nonce: None,
+        }
+    }
+
+    /// Replay the trace in the file at `path`.
+    pub fn open<P: AsRef<Path>>(path: P) -> io::Result<Self> {
+        Ok(ReplayTransport::new(Trace::open(path)?))
+    }
+
+    /// Check that the reports written by the host are the ones that were
+    /// recorded, except for the nonce of CTAPHID_INIT. Writing a different
+    /// report fails with an error of kind `io::ErrorKind::InvalidData`.
+    /// Disabled by default.
+    pub fn set_verify_output(&mut self, verify: bool) {
+        self.verify_output = verify;
+    }
+
+    /// The number of recorded reports that have not been replayed yet.
+    pub fn remaining(&self) -> usize {
+        self.reports.len()
+    }
+}
+
+impl Transport for ReplayTransport {
+    fn write_report(&mut self, report: &[u8]) -> io::Result<()> {
+        let mut recorded = match self.reports.front() {
+            Some(recorded) if recorded.direction == Direction::Output => {
+                self.reports.pop_front().unwrap().data
+            }
+            _ => {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    "the trace doesn't continue with an output report",
+                ))
+            }
+        };
+        // Output reports start with the report ID.
+        if is_init_request(&recorded, 1) && is_init_request(report, 1) {
+            let nonce = (1 + NONCE_OFFSET)..(1 + NONCE_OFFSET + 8);
+            let actual = report[nonce.clone()].to_vec();
+            self.nonce = Some((recorded[nonce.clone()].to_vec(), actual.clone()));
+            recorded[nonce].copy_from_slice(&actual);
+        }
+        if self.verify_output && recorded[..] != *report {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "the report differs from the trace",
+            ));
+        }
+        Ok(())
+    }
+
+    fn read_report(&mut self, buf: &mut [u8], _timeout: Option<Duration>) -> io::Result<usize> {
+        let mut data = match self.reports.front() {
+            Some(recorded) if recorded.direction == Direction::Input => {
+                self.reports.pop_front().unwrap().data
+            }
+            _ => {
+                return Err(io::Error::new(
+                    io::ErrorKind::TimedOut,
+                    "the trace doesn't continue with an input report",
+                ))
+            }
+        };
+        let offset = self.trace.input_report_id.is_some() as usize;
+        if let Some((ref recorded, ref actual)) = self.nonce {
+            let nonce = (offset + NONCE_OFFSET)..(offset + NONCE_OFFSET + 8);
+            if is_init_request(&data, offset) && data[nonce.clone()] == recorded[..] {
+                data[nonce].copy_from_slice(actual);
+            }
+        }
+        let len = data.len().min(buf.len());
+        buf[..len].copy_from_slice(&data[..len]);
+        Ok(len)
+    }
+
+    fn input_report_size(&self) -> usize {
+        self.trace.input_report_size
+    }
+
+    fn output_report_size(&self) -> usize {
+        self.trace.output_report_size
+    }
+
+    fn input_report_id(&self) -> Option<u8> {
+        self.trace.input_report_id
+    }
+
+    fn output_report_id(&self) -> Option<u8> {
+        self.trace.output_report_id
+    }
+}
+
+/// Whether `report` holds the initialization packet of a CTAPHID_INIT message,
+/// with the packet starting at `offset`.
+fn is_init_request(report: &[u8], offset: usize) -> bool {
+    report.len() >= offset + NONCE_OFFSET + 8 && report[offset + CMD_OFFSET] == INIT_PACKET
+}
+
+fn format_report_id(report_id: Option<u8>) -> String {
+    match report_id {
+        Some(report_id) => report_id.to_string(),
+        None => "none".to_string(),
+    }
+}
+
+fn invalid_trace(message: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message.to_string())
+}
+
+fn parse_report_info(line: &str, kind: &str) -> io::Result<(usize, Option<u8>)> {
+    let fields: Vec<_> = line.split_whitespace().collect();
+    if fields.len() != 3 || fields[0] != kind {
+        return Err(invalid_trace(&format!(
+            "expected {} report information",
+            kind
+        )));
+    }
+    let size = fields[1]
+        .parse()
+        .map_err(|_| invalid_trace("invalid report size"))?;
+    let report_id = match fields[2] {
+        "none" => None,
+        report_id => Some(
+            report_id
+                .parse()
+                .map_err(|_| invalid_trace("invalid report ID"))?,
+        ),
+    };
+    Ok((size, report_id))
+}
+
+fn parse_report(line: &str) -> io::Result<RecordedReport> {
+    let fields: Vec<_> = line.split_whitespace().collect();
+    // Zero-length reads are recorded without data.
+    if fields.len() != 2 && fields.len() != 3 {
+        return Err(invalid_trace("expected a direction, timestamp and report"));
+    }
+    let direction = match fields[0] {
+        "out" => Direction::Output,
+        "in" => Direction::Input,
+        _ => return Err(invalid_trace("invalid direction")),
+    };
+    let mut timestamp = fields[1].splitn(2, '.');
+    let secs = timestamp.next().and_then(|secs| secs.parse().ok());
+    let micros = timestamp.next().and_then(|micros| micros.parse().ok());
+    let timestamp = match (secs, micros) {
+        (Some(secs), Some(micros)) => Duration::new(secs, 0) + Duration::from_micros(micros),
+        _ => return Err(invalid_trace("invalid timestamp")),
+    };
+    let hex = fields.get(2).map_or(&b""[..], |hex| hex.as_bytes());
+    if hex.len() % 2 != 0 {
+        return Err(invalid_trace("invalid report data"));
+    }
+    let data = hex
+        .chunks(2)
+        .map(|byte| {
+            std::str::from_utf8(byte)
+                .ok()
+                .and_then(|byte| u8::from_str_radix(byte, 16).ok())
+                .ok_or_else(|| invalid_trace("invalid report data"))
+        })
+        .collect::<io::Result<_>>()?;
+    Ok(RecordedReport {
+        direction,
+        timestamp,
+        data,
+    })
+}
@@ -0,0 +1,105 @@
+// This file is part of ctap, a Rust implementation of the FIDO2 protocol.
+// Copyright (c) Ariën Holthuizen <contact@ardaxi.com>
+// Licensed under the Apache License, Version 2.0, <LICENSE-APACHE or
+// http://apache.org/licenses/LICENSE-2.0> or the MIT license <LICENSE-MIT or
+// http://opensource.org/licenses/MIT>, at your option. This file may not be
+// copied, modified, or distributed except according to those terms.
+use std::cmp;
+use std::time::Duration;
+
+use rand::prelude::*;
+
+/// Controls how requests are repeated when the authenticator reports that it is
+/// busy with another channel, or that a message timed out.
+///
+/// The delay before each retry doubles, starting at `backoff` and never exceeding
+/// `max_backoff`, and a random delay of up to `jitter` is added to it so that
+/// competing applications don't keep retrying in lockstep.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct RetryPolicy {
+    /// How many times a request is repeated before giving up.
+    pub attempts: u32,
+    /// The delay before the first retry.
+    pub backoff: Duration,
+    /// The longest delay between two retries, not counting jitter.
+    pub max_backoff: Duration,
+    /// The maximum random delay added to each retry.
+    pub jitter: Duration,
+}
+
+impl RetryPolicy {
+    /// A policy that never repeats a request.
+    pub fn never() -> Self {
+        RetryPolicy {
+            attempts: 0,
+            backoff: Duration::from_secs(0),
+            max_backoff: Duration::from_secs(0),
+            jitter: Duration::from_secs(0),
+        }
+    }
+
+    /// The delay before the given retry, counting from zero.
+    pub fn delay(&self, attempt: u32) -> Duration {
+        let backoff = 2u32
+            .checked_pow(attempt)
+            .and_then(|factor| self.backoff.checked_mul(factor))
+            .unwrap_or(self.max_backoff);
+        let backoff = cmp::min(backoff, self.max_backoff);
+        let jitter = self.jitter.as_nanos() as u64;
+        if jitter == 0 {
+            return backoff;
+        }
+        backoff + Duration::from_nanos(thread_rng().gen_range(0, jitter + 1))
+    }
+}
+
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        RetryPolicy {
+            attempts: 5,
+            backoff: Duration::from_millis(50),
+            max_backoff: Duration::from_secs(1),
+            jitter: Duration::from_millis(50),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn delay_doubles_up_to_max_backoff() {
+        let policy = RetryPolicy {
+            jitter: Duration::from_secs(0),
+            ..RetryPolicy::default()
+        };
+        let delays: Vec<_> = (0..7).map(|attempt| policy.delay(attempt).as_millis()).collect();
+        assert_eq!(delays, [50, 100, 200, 400, 800, 1000, 1000]);
+        // The backoff would overflow.
+        assert_eq!(policy.delay(100), policy.max_backoff);
+    }
+
+    #[test]
+    fn delay_stays_within_jitter() {
+        let policy = RetryPolicy::default();
+        let without_jitter = RetryPolicy {
+            jitter: Duration::from_secs(0),
+            ..policy.clone()
+        };
+        for attempt in 0..100 {
+            let delay = policy.delay(attempt);
+            let backoff = without_jitter.delay(attempt);
+            assert!(delay >= backoff);
+            assert!(delay <= backoff + policy.jitter);
+            assert!(delay <= policy.max_backoff + policy.jitter);
+        }
+    }
+
+    #[test]
+    fn never_has_no_delay() {
+        let policy = RetryPolicy::never();
+        assert_eq!(policy.attempts, 0);
+        assert_eq!(policy.delay(0), Duration::from_secs(0));
+    }
+}
@@ -0,0 +1,151 @@
+// This file is part of ctap, a Rust implementation of the FIDO2 protocol.
+// Copyright (c) Ariën Holthuizen <contact@ardaxi.com>
+// Licensed under the Apache License, Version 2.0, <LICENSE-APACHE or
+// http://apache.org/licenses/LICENSE-2.0> or the MIT license <LICENSE-MIT or
+// http://opensource.org/licenses/MIT>, at your option. This file may not be
+// copied, modified, or distributed except according to those terms.
+use std::io;
+#[cfg(feature = "async")]
+use std::task::{Context, Poll};
+use std::time::Duration;
+
+/// A channel over which HID reports can be exchanged with an authenticator.
+///
+/// The CTAPHID framing in `FidoDevice` is built on top of this trait, so any
+/// type that can move whole reports back and forth can be used to talk to an
+/// authenticator, not just a hidraw device node.
+pub trait Transport {
+    /// Write a single output report. The first byte of `report` is the HID
+    /// report ID, or 0 if the device doesn't use numbered reports, the rest is
+    /// the report data.
+    fn write_report(&mut self, report: &[u8]) -> io::Result<()>;
+
+    /// Read a single input report into `buf`, returning the number of bytes
+    /// read. If the device uses numbered input reports the first byte is the
+    /// report ID, otherwise only the report data is returned.
+    ///
+    /// If `timeout` is given and no report arrives in time, this returns an
+    /// error of kind `io::ErrorKind::TimedOut`.
+    fn read_report(&mut self, buf: &mut [u8], timeout: Option<Duration>) -> io::Result<usize>;
+
+    /// The length of an input report in bytes, not counting the report ID.
+    /// Defaults to the 64 bytes used by full-speed USB authenticators.
+    fn input_report_size(&self) -> usize {
+        64
+    }
+
+    /// The length of an output report in bytes, not counting the report ID.
+    /// Defaults to the 64 bytes used by full-speed USB authenticators.
+    fn output_report_size(&self) -> usize {
+        64
+    }
+
+    /// The ID of the input reports carrying CTAPHID packets, or `None` if the
+    /// device doesn't use numbered reports.
+    fn input_report_id(&self) -> Option<u8> {
+        None
+    }
+
+    /// The ID of the output reports carrying CTAPHID packets, or `None` if the
+    /// device doesn't use numbered reports.
+    fn output_report_id(&self) -> Option<u8> {
+        None
+    }
+
+    /// Get a second handle for writing output reports, which can be used from
+    /// another thread while this transport waits for a response. `CancelHandle`
+    /// uses it to send CTAPHID_CANCEL as soon as it is asked to. Without one,
+    /// the default, the cancellation is only sent once the authenticator sends
+    /// a keepalive message.
+    fn report_writer(&self) -> Option<Box<dyn ReportWriter>> {
+        None
+    }
+}
+
+/// Writes output reports to an authenticator from outside of its `Transport`,
+/// created by `Transport::report_writer`.
+pub trait ReportWriter: Send {
+    /// Write a single output report, like `Transport::write_report`.
+    fn write_report(&mut self, report: &[u8]) -> io::Result<()>;
+}
+
+impl<T: Transport + ?Sized> Transport for Box<T> {
+    fn write_report(&mut self, report: &[u8]) -> io::Result<()> {
+        (**self).write_report(report)
+    }
+
+    fn read_report(&mut self, buf: &mut [u8], timeout: Option<Duration>) -> io::Result<usize> {
+        (**self).read_report(buf, timeout)
+    }
+
+    fn input_report_size(&self) -> usize {
+        (**self).input_report_size()
+    }
+
+    fn output_report_size(&self) -> usize {
+        (**self).output_report_size()
+    }
+
+    fn input_report_id(&self) -> Option<u8> {
+        (**self).input_report_id()
+    }
+
+    fn output_report_id(&self) -> Option<u8> {
+        (**self).output_report_id()
+    }
+
+    fn report_writer(&self) -> Option<Box<dyn ReportWriter>> {
+        (**self).report_writer()
+    }
+}
+
+/// A channel over which `AsyncFidoDevice` exchanges HID reports with an
+/// authenticator, without blocking while it waits for them. This is the async
+/// counterpart of `Transport`, with reads and writes that are polled like
+/// tokio's `AsyncRead` and `AsyncWrite`.
+#[cfg(feature = "async")]
+pub trait AsyncTransport: Send {
+    /// Attempt to write a single output report, like `Transport::write_report`.
+    /// If the report can't be written yet, this returns `Poll::Pending` and
+    /// arranges for the current task to be woken once it can.
+    fn poll_write_report(&mut self, cx: &mut Context<'_>, report: &[u8]) -> Poll<io::Result<()>>;
+
+    /// Attempt to read a single input report into `buf`, like
+    /// `Transport::read_report`. If no report has arrived, this returns
+    /// `Poll::Pending` and arranges for the current task to be woken once one
+    /// does.
+    fn poll_read_report(&mut self, cx: &mut Context<'_>, buf: &mut [u8]) -> Poll<io::Result<usize>>;
+
+    /// The length of an input report in bytes, like
+    /// `Transport::input_report_size`.
+    fn input_report_size(&self) -> usize {
+        64
+    }
+
+    /// The length of an output report in bytes, like
+    /// `Transport::output_report_size`.
+    fn output_report_size(&self) -> usize {
+        64
+    }
+
+    /// The ID of the input reports carrying CTAPHID packets, like
+    /// `Transport::input_report_id`.
+    fn input_report_id(&self) -> Option<u8> {
+        None
+    }
+
+    /// The ID of the output reports carrying CTAPHID packets, like
+    /// `Transport::output_report_id`.
+    fn output_report_id(&self) -> Option<u8> {
+        None
+    }
+
+    /// Get a second handle for writing output reports, which `AsyncFidoDevice`
+    /// uses to send CTAPHID_CANCEL when the future of a request is dropped.
+    /// Writes through it must not block. Without one, the default, dropped
+    /// requests aren't cancelled and the authenticator has to time out by
+    /// itself.
+    fn report_writer(&self) -> Option<Box<dyn ReportWriter>> {
+        None
+    }
+}
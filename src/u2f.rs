@@ -0,0 +1,191 @@
+// This file is part of ctap, a Rust implementation of the FIDO2 protocol.
+// Copyright (c) Ariën Holthuizen <contact@ardaxi.com>
+// Licensed under the Apache License, Version 2.0, <LICENSE-APACHE or
+// http://apache.org/licenses/LICENSE-2.0> or the MIT license <LICENSE-MIT or
+// http://opensource.org/licenses/MIT>, at your option. This file may not be
+// copied, modified, or distributed except according to those terms.
+use byteorder::{BigEndian, ByteOrder};
+use ring::digest;
+
+use super::error::*;
+
+static U2F_REGISTER: u8 = 0x01;
+static U2F_AUTHENTICATE: u8 = 0x02;
+static U2F_VERSION: u8 = 0x03;
+static ENFORCE_USER_PRESENCE_AND_SIGN: u8 = 0x03;
+static REGISTER_RESERVED: u8 = 0x05;
+pub static SW_NO_ERROR: u16 = 0x9000;
+pub static SW_CONDITIONS_NOT_SATISFIED: u16 = 0x6985;
+
+/// The U2F application parameter for a Relying Party ID, which is the same as
+/// the RP ID hash used by CTAP2.
+pub fn application_parameter(rp_id: &str) -> [u8; 32] {
+    let mut application = [0; 32];
+    application.copy_from_slice(digest::digest(&digest::SHA256, rp_id.as_bytes()).as_ref());
+    application
+}
+
+/// Encode a command APDU using extended length encoding.
+fn encode_apdu(ins: u8, p1: u8, data: &[u8]) -> FidoResult<Vec<u8>> {
+    if data.len() > 0xffff {
+        Err(FidoErrorKind::WritePacket)?
+    }
+    let mut apdu = Vec::with_capacity(data.len() + 9);
+    apdu.extend_from_slice(&[0x00, ins, p1, 0x00]);
+    if data.is_empty() {
+        apdu.extend_from_slice(&[0x00, 0x00, 0x00]);
+        return Ok(apdu);
+    }
+    apdu.push(0x00);
+    apdu.push((data.len() >> 8) as u8);
+    apdu.push(data.len() as u8);
+    apdu.extend_from_slice(data);
+    apdu.extend_from_slice(&[0x00, 0x00]);
+    Ok(apdu)
+}
+
+/// Split the status word off a response APDU, failing if it signals an error.
+pub fn response_data(mut response: Vec<u8>) -> FidoResult<Vec<u8>> {
+    if response.len() < 2 {
+        Err(FidoErrorKind::ParseU2f)?
+    }
+    let status_start = response.len() - 2;
+    let status = BigEndian::read_u16(&response[status_start..]);
+    if status != SW_NO_ERROR {
+        Err(FidoErrorKind::U2fError(status))?
+    }
+    response.truncate(status_start);
+    Ok(response)
+}
+
+pub struct RegisterRequest<'a> {
+    pub challenge: &'a [u8],
+    pub application: &'a [u8],
+}
+
+impl<'a> RegisterRequest<'a> {
+    pub fn encode(&self) -> FidoResult<Vec<u8>> {
+        if self.challenge.len() != 32 || self.application.len() != 32 {
+            Err(FidoErrorKind::WritePacket)?
+        }
+        let mut data = Vec::with_capacity(64);
+        data.extend_from_slice(self.challenge);
+        data.extend_from_slice(self.application);
+        encode_apdu(U2F_REGISTER, 0x00, &data)
+    }
+}
+
+#[derive(Debug, Default)]
+pub struct RegisterResponse {
+    pub public_key: Vec<u8>,
+    pub key_handle: Vec<u8>,
+    pub attestation_certificate: Vec<u8>,
+    pub signature: Vec<u8>,
+}
+
+impl RegisterResponse {
+    pub fn decode(data: &[u8]) -> FidoResult<Self> {
+        if data.len() < 67 || data[0] != REGISTER_RESERVED {
+            Err(FidoErrorKind::ParseU2f)?
+        }
+        let public_key = data[1..66].to_vec();
+        let handle_length = data[66] as usize;
+        let handle_end = 67 + handle_length;
+        if data.len() < handle_end {
+            Err(FidoErrorKind::ParseU2f)?
+        }
+        let key_handle = data[67..handle_end].to_vec();
+        let certificate_end = handle_end + der_length(&data[handle_end..])?;
+        if data.len() < certificate_end {
+            Err(FidoErrorKind::ParseU2f)?
+        }
+        Ok(RegisterResponse {
+            public_key,
+            key_handle,
+            attestation_certificate: data[handle_end..certificate_end].to_vec(),
+            signature: data[certificate_end..].to_vec(),
+        })
+    }
+}
+
+/// The total length of the DER element at the start of `data`, which is how
+/// the end of the attestation certificate is found.
+fn der_length(data: &[u8]) -> FidoResult<usize> {
+    if data.len() < 2 {
+        Err(FidoErrorKind::ParseU2f)?
+    }
+    let length = data[1] as usize;
+    if length < 0x80 {
+        return Ok(2 + length);
+    }
+    let length_bytes = length & 0x7f;
+    if length_bytes == 0 || length_bytes > 2 || data.len() < 2 + length_bytes {
+        Err(FidoErrorKind::ParseU2f)?
+    }
+    let length = data[2..(2 + length_bytes)].iter().fold(0, |acc, byte| {
+        (acc << 8) | *byte as usize
+    });
+    Ok(2 + length_bytes + length)
+}
+
+pub struct AuthenticateRequest<'a> {
+    pub challenge: &'a [u8],
+    pub application: &'a [u8],
+    pub key_handle: &'a [u8],
+}
+
+impl<'a> AuthenticateRequest<'a> {
+    pub fn encode(&self) -> FidoResult<Vec<u8>> {
+        if self.challenge.len() != 32 || self.application.len() != 32 ||
+            self.key_handle.len() > 0xff
+        {
+            Err(FidoErrorKind::WritePacket)?
+        }
+        let mut data = Vec::with_capacity(65 + self.key_handle.len());
+        data.extend_from_slice(self.challenge);
+        data.extend_from_slice(self.application);
+        data.push(self.key_handle.len() as u8);
+        data.extend_from_slice(self.key_handle);
+        encode_apdu(U2F_AUTHENTICATE, ENFORCE_USER_PRESENCE_AND_SIGN, &data)
+    }
+}
+
+#[derive(Debug, Default)]
+pub struct AuthenticateResponse {
+    pub user_presence: u8,
+    pub counter: u32,
+    pub signature: Vec<u8>,
+}
+
+impl AuthenticateResponse {
+    pub fn decode(data: &[u8]) -> FidoResult<Self> {
+        if data.len() < 5 {
+            Err(FidoErrorKind::ParseU2f)?
+        }
+        Ok(AuthenticateResponse {
+            user_presence: data[0],
+            counter: BigEndian::read_u32(&data[1..5]),
+            signature: data[5..].to_vec(),
+        })
+    }
+
+    /// The data signed by the authenticator, minus the challenge. This has the
+    /// same layout as CTAP2 authenticator data without attested credentials.
+    pub fn signed_data(&self, application: &[u8]) -> Vec<u8> {
+        let mut data = Vec::with_capacity(37);
+        data.extend_from_slice(application);
+        data.push(self.user_presence);
+        let mut counter = [0; 4];
+        BigEndian::write_u32(&mut counter, self.counter);
+        data.extend_from_slice(&counter);
+        data
+    }
+}
+
+pub fn encode_version() -> FidoResult<Vec<u8>> {
+    encode_apdu(U2F_VERSION, 0x00, &[])
+}
+
+pub fn decode_version(data: &[u8]) -> FidoResult<String> {
+    String::from_utf8(data.to_vec()).map_err(|_| FidoErrorKind::ParseU2f.into())
+}
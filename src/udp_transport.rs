@@ -0,0 +1,88 @@
+// This file is part of ctap, a Rust implementation of the FIDO2 protocol.
+// Copyright (c) Ariën Holthuizen <contact@ardaxi.com>
+// Licensed under the Apache License, Version 2.0, <LICENSE-APACHE or
+// http://apache.org/licenses/LICENSE-2.0> or the MIT license <LICENSE-MIT or
+// http://opensource.org/licenses/MIT>, at your option. This file may not be
+// copied, modified, or distributed except according to those terms.
+use std::cmp;
+use std::io;
+use std::net::{ToSocketAddrs, UdpSocket};
+use std::time::Duration;
+
+use super::transport::{ReportWriter, Transport};
+
+/// A transport that exchanges HID reports with an authenticator simulator over
+/// UDP, one report per datagram.
+///
+/// This is how the desktop builds of authenticator firmware such as SoloKeys
+/// are reached. Reports are sent without a report ID, as a full-speed USB
+/// authenticator would receive them. The Solo simulator for example listens
+/// on port 8111 and answers to port 7112:
+///
+/// ```no_run
+/// # fn connect() -> std::io::Result<()> {
+/// let transport = ctap::UdpTransport::connect("127.0.0.1:7112", "127.0.0.1:8111")?;
+/// let device = ctap::FidoDevice::with_transport(transport);
+/// # Ok(())
+/// # }
+/// ```
+pub struct UdpTransport {
+    socket: UdpSocket,
+}
+
+impl UdpTransport {
+    /// Bind to the `local` address and exchange reports with the simulator at
+    /// `remote`. Datagrams from other addresses are ignored.
+    pub fn connect<A: ToSocketAddrs, B: ToSocketAddrs>(local: A, remote: B) -> io::Result<Self> {
+        let socket = UdpSocket::bind(local)?;
+        socket.connect(remote)?;
+        Ok(UdpTransport::from_socket(socket))
+    }
+
+    /// Use a socket that is already connected to the simulator.
+    pub fn from_socket(socket: UdpSocket) -> Self {
+        UdpTransport { socket }
+    }
+}
+
+impl Transport for UdpTransport {
+    fn write_report(&mut self, report: &[u8]) -> io::Result<()> {
+        // The simulator doesn't use numbered reports, so the report ID is
+        // always 0 and not sent.
+        self.socket.send(&report[1..])?;
+        Ok(())
+    }
+
+    fn read_report(&mut self, buf: &mut [u8], timeout: Option<Duration>) -> io::Result<usize> {
+        // A zero timeout would make the socket block forever.
+        let timeout = timeout.map(|timeout| cmp::max(timeout, Duration::from_micros(1)));
+        self.socket.set_read_timeout(timeout)?;
+        match self.socket.recv(buf) {
+            Err(ref err)
+                if err.kind() == io::ErrorKind::WouldBlock
+                    || err.kind() == io::ErrorKind::TimedOut =>
+            {
+                Err(io::Error::new(
+                    io::ErrorKind::TimedOut,
+                    "no report received",
+                ))
+            }
+            result => result,
+        }
+    }
+
+    fn report_writer(&self) -> Option<Box<dyn ReportWriter>> {
+        let socket = self.socket.try_clone().ok()?;
+        Some(Box::new(UdpWriter(socket)))
+    }
+}
+
+/// A second handle to the socket of a `UdpTransport`, which only sends reports.
+struct UdpWriter(UdpSocket);
+
+impl ReportWriter for UdpWriter {
+    fn write_report(&mut self, report: &[u8]) -> io::Result<()> {
+        self.0.send(&report[1..])?;
+        Ok(())
+    }
+}
@@ -0,0 +1,253 @@
+// This file is part of ctap, a Rust implementation of the FIDO2 protocol.
+// Copyright (c) Ariën Holthuizen <contact@ardaxi.com>
+// Licensed under the Apache License, Version 2.0, <LICENSE-APACHE or
+// http://apache.org/licenses/LICENSE-2.0> or the MIT license <LICENSE-MIT or
+// http://opensource.org/licenses/MIT>, at your option. This file may not be
+// copied, modified, or distributed except according to those terms.
+//! Virtual HID devices created through the kernel's UHID driver.
+//!
+//! See `Documentation/hid/uhid.rst` in the kernel sources for the layout of
+//! the events exchanged with `/dev/uhid`.
+use std::cmp;
+use std::fs::{File, OpenOptions};
+use std::io::{self, Read, Write};
+use std::os::unix::io::AsRawFd;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use byteorder::{ByteOrder, NativeEndian};
+use rand::prelude::*;
+
+use super::authenticator::VirtualAuthenticator;
+use super::channels::ChannelManager;
+use super::hid::{DeviceEnumerator, DeviceInfo};
+use super::memory_transport::handle_message;
+use super::packet::DeviceCapabilities;
+
+static UHID_PATH: &str = "/dev/uhid";
+static DEVICE_NAME: &[u8] = b"ctap virtual authenticator";
+// The pid.codes vendor ID and product ID for testing.
+static VENDOR_ID: u32 = 0x1209;
+static PRODUCT_ID: u32 = 0x0001;
+static BUS_USB: u16 = 0x03;
+static REPORT_SIZE: usize = 64;
+// A single top-level FIDO collection with unnumbered 64-byte input and output
+// reports, as sent by most USB authenticators.
+static REPORT_DESCRIPTOR: &[u8] = &[
+    0x06, 0xd0, 0xf1, // Usage Page (FIDO Alliance)
+    0x09, 0x01, // Usage (CTAPHID)
+    0xa1, 0x01, // Collection (Application)
+    0x09, 0x20, //   Usage (Input Report Data)
+    0x15, 0x00, //   Logical Minimum (0)
+    0x26, 0xff, 0x00, //   Logical Maximum (255)
+    0x75, 0x08, //   Report Size (8)
+    0x95, 0x40, //   Report Count (64)
+    0x81, 0x02, //   Input (Data, Variable, Absolute)
+    0x09, 0x21, //   Usage (Output Report Data)
+    0x15, 0x00, //   Logical Minimum (0)
+    0x26, 0xff, 0x00, //   Logical Maximum (255)
+    0x75, 0x08, //   Report Size (8)
+    0x95, 0x40, //   Report Count (64)
+    0x91, 0x02, //   Output (Data, Variable, Absolute)
+    0xc0, // End Collection
+];
+
+// struct uhid_event is packed, with a 4-byte type followed by the largest
+// request, uhid_create2_req.
+static EVENT_SIZE: usize = 4376;
+static UHID_OUTPUT: u32 = 6;
+static UHID_GET_REPORT: u32 = 9;
+static UHID_GET_REPORT_REPLY: u32 = 10;
+static UHID_CREATE2: u32 = 11;
+static UHID_INPUT2: u32 = 12;
+static UHID_SET_REPORT: u32 = 13;
+static UHID_SET_REPORT_REPLY: u32 = 14;
+static UHID_DATA_MAX: usize = 4096;
+
+const POLL_INTERVAL: Duration = Duration::from_millis(10);
+
+/// A FIDO HID device created through `/dev/uhid` and answered by a
+/// `VirtualAuthenticator`.
+///
+/// The device shows up as a hidraw device node like a physical authenticator,
+/// so it can be found with `get_devices` and opened with `FidoDevice::new`.
+/// Creating it requires access to `/dev/uhid`, which is usually restricted to
+/// root. The device is removed when this is dropped.
+pub struct UhidDevice {
+    authenticator: Arc<Mutex<VirtualAuthenticator>>,
+    serial_number: String,
+    stop: Arc<AtomicBool>,
+    thread: Option<thread::JoinHandle<()>>,
+}
+
+impl UhidDevice {
+    /// Create a device answered by `authenticator`. It is given a random
+    /// serial number to tell it apart from other devices.
+    pub fn create(authenticator: VirtualAuthenticator) -> io::Result<Self> {
+        let mut file = OpenOptions::new().read(true).write(true).open(UHID_PATH)?;
+        let serial_number = format!("{:016x}", thread_rng().gen::<u64>());
+        file.write_all(&create_event(serial_number.as_bytes()))?;
+
+        let authenticator = Arc::new(Mutex::new(authenticator));
+        let stop = Arc::new(AtomicBool::new(false));
+        let thread = {
+            let authenticator = authenticator.clone();
+            let stop = stop.clone();
+            thread::spawn(move || run(file, authenticator, stop))
+        };
+        Ok(UhidDevice {
+            authenticator,
+            serial_number,
+            stop,
+            thread: Some(thread),
+        })
+    }
+
+    /// The serial number the device was created with.
+    pub fn serial_number(&self) -> &str {
+        &self.serial_number
+    }
+
+    /// Get the authenticator behind this device, to inspect or change its
+    /// state while it is in use.
+    pub fn authenticator(&self) -> Arc<Mutex<VirtualAuthenticator>> {
+        self.authenticator.clone()
+    }
+
+    /// Wait for the kernel to create the hidraw device node, and return its
+    /// information as `get_devices` does. This fails with an error of kind
+    /// `io::ErrorKind::TimedOut` if the device doesn't show up in time.
+    pub fn device_info(&self, timeout: Duration) -> io::Result<DeviceInfo> {
+        let deadline = Instant::now() + timeout;
+        loop {
+            let device = DeviceEnumerator::default()
+                .enumerate()?
+                .find(|device| device.serial_number.as_ref() == Some(&self.serial_number));
+            // udev may not have made the device node accessible yet.
+            if let Some(device) = device {
+                if device.path.exists() {
+                    return Ok(device);
+                }
+            }
+            if Instant::now() >= deadline {
+                return Err(io::Error::new(
+                    io::ErrorKind::TimedOut,
+                    "the device did not appear",
+                ));
+            }
+            thread::sleep(POLL_INTERVAL);
+        }
+    }
+}
+
+impl Drop for UhidDevice {
+    fn drop(&mut self) {
+        self.stop.store(true, Ordering::SeqCst);
+        if let Some(thread) = self.thread.take() {
+            let _ = thread.join();
+        }
+    }
+}
+
+fn create_event(serial_number: &[u8]) -> Vec<u8> {
+    let mut event = vec![0; EVENT_SIZE];
+    NativeEndian::write_u32(&mut event[0..4], UHID_CREATE2);
+    event[4..(4 + DEVICE_NAME.len())].copy_from_slice(DEVICE_NAME);
+    event[196..(196 + serial_number.len())].copy_from_slice(serial_number);
+    NativeEndian::write_u16(&mut event[260..262], REPORT_DESCRIPTOR.len() as u16);
+    NativeEndian::write_u16(&mut event[262..264], BUS_USB);
+    NativeEndian::write_u32(&mut event[264..268], VENDOR_ID);
+    NativeEndian::write_u32(&mut event[268..272], PRODUCT_ID);
+    event[280..(280 + REPORT_DESCRIPTOR.len())].copy_from_slice(REPORT_DESCRIPTOR);
+    event
+}
+
+fn input_event(report: &[u8]) -> Vec<u8> {
+    let mut event = vec![0; EVENT_SIZE];
+    NativeEndian::write_u32(&mut event[0..4], UHID_INPUT2);
+    NativeEndian::write_u16(&mut event[4..6], report.len() as u16);
+    event[6..(6 + report.len())].copy_from_slice(report);
+    event
+}
+
+/// Answer a GET_REPORT or SET_REPORT request with an error, as the device has
+/// no feature reports.
+fn report_reply(reply_type: u32, request: &[u8]) -> Vec<u8> {
+    let mut event = vec![0; EVENT_SIZE];
+    NativeEndian::write_u32(&mut event[0..4], reply_type);
+    event[4..8].copy_from_slice(&request[4..8]);
+    NativeEndian::write_u16(&mut event[8..10], libc::EIO as u16);
+    event
+}
+
+/// Wait until an event is ready to be read, for at most `POLL_INTERVAL`.
+fn poll(file: &File) -> io::Result<bool> {
+    let mut fds = libc::pollfd {
+        fd: file.as_raw_fd(),
+        events: libc::POLLIN,
+        revents: 0,
+    };
+    let ready = unsafe { libc::poll(&mut fds, 1, POLL_INTERVAL.as_millis() as i32) };
+    if ready < 0 {
+        let err = io::Error::last_os_error();
+        if err.kind() == io::ErrorKind::Interrupted {
+            return Ok(false);
+        }
+        return Err(err);
+    }
+    Ok(ready > 0)
+}
+
+/// Handle the events of the device until `stop` is set. Closing the file
+/// removes the device.
+fn run(mut file: File, authenticator: Arc<Mutex<VirtualAuthenticator>>, stop: Arc<AtomicBool>) {
+    let capabilities = DeviceCapabilities {
+        wink: true,
+        cbor: true,
+        nmsg: true,
+    };
+    let mut channels = ChannelManager::new(REPORT_SIZE, capabilities);
+    let mut event = vec![0; EVENT_SIZE];
+    while !stop.load(Ordering::SeqCst) {
+        channels.check_timeout();
+        while let Some(report) = channels.next_report() {
+            if file.write_all(&input_event(&report)).is_err() {
+                return;
+            }
+        }
+        match poll(&file) {
+            Ok(true) => (),
+            Ok(false) => continue,
+            Err(_) => return,
+        }
+        match file.read(&mut event) {
+            Ok(read) if read >= 4 => (),
+            Ok(_) => continue,
+            Err(ref err) if err.kind() == io::ErrorKind::Interrupted => continue,
+            Err(_) => return,
+        }
+        let event_type = NativeEndian::read_u32(&event[0..4]);
+        if event_type == UHID_OUTPUT {
+            let size = NativeEndian::read_u16(&event[4100..4102]) as usize;
+            let report = &event[4..(4 + cmp::min(size, UHID_DATA_MAX))];
+            // Output reports start with the report ID, which is always 0.
+            if report.len() == REPORT_SIZE + 1 {
+                if let Some(message) = channels.handle_report(&report[1..]) {
+                    let mut authenticator = authenticator.lock().unwrap();
+                    let _ = handle_message(&mut channels, &mut authenticator, message);
+                }
+            }
+        } else if event_type == UHID_GET_REPORT || event_type == UHID_SET_REPORT {
+            let reply_type = if event_type == UHID_GET_REPORT {
+                UHID_GET_REPORT_REPLY
+            } else {
+                UHID_SET_REPORT_REPLY
+            };
+            if file.write_all(&report_reply(reply_type, &event)).is_err() {
+                return;
+            }
+        }
+    }
+}
@@ -0,0 +1,99 @@
+// This file is part of ctap, a Rust implementation of the FIDO2 protocol.
+// Copyright (c) Ariën Holthuizen <contact@ardaxi.com>
+// Licensed under the Apache License, Version 2.0, <LICENSE-APACHE or
+// http://apache.org/licenses/LICENSE-2.0> or the MIT license <LICENSE-MIT or
+// http://opensource.org/licenses/MIT>, at your option. This file may not be
+// copied, modified, or distributed except according to those terms.
+#![cfg(all(feature = "async", feature = "virtual-authenticator"))]
+extern crate ctap;
+extern crate tokio;
+
+mod common;
+
+use std::time::Duration;
+
+use tokio::runtime::{Builder, Runtime};
+use tokio::time;
+
+use ctap::{AsyncFidoDevice, AuthenticatorConfig, FidoCredential, FidoErrorKind, MemoryTransport,
+           VirtualAuthenticator};
+
+use common::Simulator;
+
+fn assert_send<T: Send>(_: T) {}
+
+// The futures must be Send so they can be spawned on a multi-threaded runtime.
+// This only needs to compile, it is never run.
+#[allow(dead_code)]
+fn futures_are_send(device: &mut AsyncFidoDevice, credential: &FidoCredential) {
+    assert_send(device.ping(&[]));
+    assert_send(device.unlock("pin"));
+    assert_send(device.make_credential("rp_id", &[0], "user_name", &[0; 32]));
+    assert_send(device.get_assertion(credential, &[0; 32]));
+}
+
+fn runtime() -> Runtime {
+    Builder::new_current_thread().enable_time().build().unwrap()
+}
+
+#[test]
+fn pings() {
+    runtime().block_on(async {
+        let transport = MemoryTransport::new(VirtualAuthenticator::default());
+        let mut device = AsyncFidoDevice::with_transport(transport).await.unwrap();
+        device.ping(&[]).await.unwrap();
+        // This spans several packets.
+        device.ping(&[0x42; 1000]).await.unwrap();
+    });
+}
+
+#[test]
+fn makes_credentials_and_gets_assertions() {
+    runtime().block_on(async {
+        let config = AuthenticatorConfig {
+            pin: Some("1234".to_string()),
+            ..Default::default()
+        };
+        let transport = MemoryTransport::new(VirtualAuthenticator::new(config));
+        let authenticator = transport.authenticator();
+        let mut device = AsyncFidoDevice::with_transport(transport).await.unwrap();
+        let err = device
+            .make_credential("example.com", &[1], "user", &[0; 32])
+            .await
+            .err()
+            .unwrap();
+        assert_eq!(err.kind(), FidoErrorKind::PinRequired);
+
+        device.unlock("1234").await.unwrap();
+        let credential = device
+            .make_credential("example.com", &[1], "user", &[0; 32])
+            .await
+            .unwrap();
+        assert_eq!(credential.rp_id, "example.com");
+        assert_eq!(authenticator.lock().unwrap().credential_count(), 1);
+        assert!(device.get_assertion(&credential, &[1; 32]).await.unwrap());
+    });
+}
+
+#[test]
+fn dropping_the_future_cancels_the_request() {
+    runtime().block_on(async {
+        let simulator = Simulator::new();
+        simulator.state().hold = true;
+        let mut device = AsyncFidoDevice::with_transport(simulator.transport())
+            .await
+            .unwrap();
+        let result = time::timeout(
+            Duration::from_millis(100),
+            device.make_credential("example.com", &[1], "user", &[0; 32]),
+        )
+        .await;
+        assert!(result.is_err());
+        assert_eq!(simulator.state().cancels(), 1);
+        assert_eq!(simulator.state().held, None);
+
+        // The response to the cancelled request isn't taken for the response
+        // to the next one.
+        device.ping(&[1, 2, 3]).await.unwrap();
+    });
+}
@@ -0,0 +1,75 @@
+// This file is part of ctap, a Rust implementation of the FIDO2 protocol.
+// Copyright (c) Ariën Holthuizen <contact@ardaxi.com>
+// Licensed under the Apache License, Version 2.0, <LICENSE-APACHE or
+// http://apache.org/licenses/LICENSE-2.0> or the MIT license <LICENSE-MIT or
+// http://opensource.org/licenses/MIT>, at your option. This file may not be
+// copied, modified, or distributed except according to those terms.
+#![cfg(feature = "virtual-authenticator")]
+extern crate ctap;
+
+mod common;
+
+use std::thread;
+use std::time::Duration;
+
+use ctap::{FidoDevice, FidoErrorKind, KeepaliveStatus};
+
+use common::Simulator;
+
+#[test]
+fn cancel_is_sent_on_keepalive() {
+    let simulator = Simulator::new();
+    simulator.state().hold = true;
+    simulator.state().waiting = Some(KeepaliveStatus::UpNeeded);
+    // Without a way to write out of band, CANCEL has to wait for the next
+    // keepalive.
+    let mut transport = simulator.transport();
+    transport.out_of_band = false;
+    let mut device = FidoDevice::with_transport(transport).unwrap();
+    let handle = device.cancel_handle();
+    device.set_keepalive_callback(move |status| {
+        assert_eq!(status, KeepaliveStatus::UpNeeded);
+        handle.cancel();
+    });
+    let err = device
+        .make_credential("example.com", &[1], "user", &[0; 32])
+        .unwrap_err();
+    assert_eq!(err.kind(), FidoErrorKind::Cancelled);
+    assert_eq!(simulator.state().cancels(), 1);
+}
+
+#[test]
+fn cancel_is_sent_without_keepalives() {
+    let simulator = Simulator::new();
+    simulator.state().hold = true;
+    let mut device = FidoDevice::with_transport(simulator.transport()).unwrap();
+    device.set_timeout(Some(Duration::from_secs(5)));
+    let handle = device.cancel_handle();
+    let canceller = thread::spawn(move || {
+        thread::sleep(Duration::from_millis(100));
+        handle.cancel();
+    });
+    let err = device
+        .make_credential("example.com", &[1], "user", &[0; 32])
+        .unwrap_err();
+    assert_eq!(err.kind(), FidoErrorKind::Cancelled);
+    canceller.join().unwrap();
+    assert_eq!(simulator.state().out_of_band, 1);
+}
+
+#[test]
+fn cancel_before_request_is_kept() {
+    let simulator = Simulator::new();
+    let mut device = FidoDevice::with_transport(simulator.transport()).unwrap();
+    device.cancel_handle().cancel();
+    let err = device
+        .make_credential("example.com", &[1], "user", &[0; 32])
+        .unwrap_err();
+    assert_eq!(err.kind(), FidoErrorKind::Cancelled);
+    // Nothing was in flight, so nothing had to be sent.
+    assert_eq!(simulator.state().out_of_band, 0);
+    // Only the next operation is cancelled.
+    device
+        .make_credential("example.com", &[1], "user", &[0; 32])
+        .unwrap();
+}
@@ -0,0 +1,294 @@
+// This file is part of ctap, a Rust implementation of the FIDO2 protocol.
+// Copyright (c) Ariën Holthuizen <contact@ardaxi.com>
+// Licensed under the Apache License, Version 2.0, <LICENSE-APACHE or
+// http://apache.org/licenses/LICENSE-2.0> or the MIT license <LICENSE-MIT or
+// http://opensource.org/licenses/MIT>, at your option. This file may not be
+// copied, modified, or distributed except according to those terms.
+#![cfg(feature = "virtual-authenticator")]
+extern crate ctap;
+
+use std::thread;
+use std::time::Duration;
+
+use ctap::{ChannelManager, CtapCommand, CtapError, DeviceCapabilities, KeepaliveStatus, Message};
+
+static BROADCAST_CID: [u8; 4] = [0xff, 0xff, 0xff, 0xff];
+
+fn manager() -> ChannelManager {
+    let capabilities = DeviceCapabilities {
+        wink: true,
+        cbor: true,
+        nmsg: true,
+    };
+    ChannelManager::new(64, capabilities)
+}
+
+fn init_packet(cid: [u8; 4], cmd: u8, size: usize, payload: &[u8]) -> Vec<u8> {
+    let mut report = cid.to_vec();
+    report.push(0x80 | cmd);
+    report.push((size >> 8) as u8);
+    report.push(size as u8);
+    report.extend_from_slice(payload);
+    report.resize(64, 0);
+    report
+}
+
+fn cont_packet(cid: [u8; 4], seq: u8, payload: &[u8]) -> Vec<u8> {
+    let mut report = cid.to_vec();
+    report.push(seq);
+    report.extend_from_slice(payload);
+    report.resize(64, 0);
+    report
+}
+
+/// Take the next response, returning its channel, command and the payload in
+/// its initialization packet.
+fn response(manager: &mut ChannelManager) -> ([u8; 4], u8, Vec<u8>) {
+    let report = manager.next_report().expect("no response");
+    assert_eq!(report.len(), 64);
+    let mut cid = [0; 4];
+    cid.copy_from_slice(&report[0..4]);
+    let size = ((report[5] as usize) << 8) | report[6] as usize;
+    let end = std::cmp::min(64, 7 + size);
+    (cid, report[4] & 0x7f, report[7..end].to_vec())
+}
+
+fn allocate(manager: &mut ChannelManager) -> [u8; 4] {
+    let nonce = [1, 2, 3, 4, 5, 6, 7, 8];
+    assert_eq!(
+        manager.handle_report(&init_packet(BROADCAST_CID, 0x06, 8, &nonce)),
+        None
+    );
+    let (cid, cmd, payload) = response(manager);
+    assert_eq!(cid, BROADCAST_CID);
+    assert_eq!(cmd, 0x06);
+    assert_eq!(&payload[0..8], &nonce);
+    let mut channel = [0; 4];
+    channel.copy_from_slice(&payload[8..12]);
+    channel
+}
+
+fn assert_error(manager: &mut ChannelManager, cid: [u8; 4], error: CtapError) {
+    assert_eq!(response(manager), (cid, 0x3f, vec![error as u8]));
+}
+
+#[test]
+fn broadcast_init_allocates_channels() {
+    let mut manager = manager();
+    manager.set_version(1, 2, 3);
+    let nonce = [8; 8];
+    manager.handle_report(&init_packet(BROADCAST_CID, 0x06, 8, &nonce));
+    let (_, _, payload) = response(&mut manager);
+    assert_eq!(payload.len(), 17);
+    assert_eq!(&payload[12..], &[2, 1, 2, 3, 0x0d]);
+
+    let first = allocate(&mut manager);
+    let second = allocate(&mut manager);
+    assert!(first != second);
+    assert!(first != BROADCAST_CID && first != [0; 4]);
+}
+
+#[test]
+fn init_requires_nonce() {
+    let mut manager = manager();
+    manager.handle_report(&init_packet(BROADCAST_CID, 0x06, 4, &[0; 4]));
+    assert_error(&mut manager, BROADCAST_CID, CtapError::InvalidLen);
+}
+
+#[test]
+fn unallocated_channels_are_rejected() {
+    let mut manager = manager();
+    let cid = [1, 2, 3, 4];
+    assert_eq!(
+        manager.handle_report(&init_packet(cid, 0x10, 1, &[0x04])),
+        None
+    );
+    assert_error(&mut manager, cid, CtapError::InvalidChannel);
+    manager.handle_report(&init_packet(BROADCAST_CID, 0x10, 1, &[0x04]));
+    assert_error(&mut manager, BROADCAST_CID, CtapError::InvalidChannel);
+}
+
+#[test]
+fn reassembles_messages() {
+    let mut manager = manager();
+    let cid = allocate(&mut manager);
+    let payload: Vec<u8> = (0..150).map(|n| n as u8).collect();
+    assert_eq!(
+        manager.handle_report(&init_packet(cid, 0x10, 150, &payload[0..57])),
+        None
+    );
+    assert_eq!(
+        manager.handle_report(&cont_packet(cid, 0, &payload[57..116])),
+        None
+    );
+    let message = manager.handle_report(&cont_packet(cid, 1, &payload[116..]));
+    assert_eq!(
+        message,
+        Some(Message {
+            cid,
+            cmd: CtapCommand::Cbor,
+            payload,
+        })
+    );
+    assert_eq!(manager.busy_channel(), Some(cid));
+    assert!(manager.next_report().is_none());
+}
+
+#[test]
+fn responses_are_framed() {
+    let mut manager = manager();
+    let cid = allocate(&mut manager);
+    let payload = vec![0x42; 100];
+    manager
+        .handle_report(&init_packet(cid, 0x10, 1, &[0x04]))
+        .unwrap();
+    manager.respond(cid, CtapCommand::Cbor, &payload).unwrap();
+    assert_eq!(manager.busy_channel(), None);
+    assert_eq!(response(&mut manager), (cid, 0x10, vec![0x42; 57]));
+    let report = manager.next_report().unwrap();
+    assert_eq!(&report[0..5], &[cid[0], cid[1], cid[2], cid[3], 0]);
+    assert_eq!(&report[5..48], &[0x42; 43][..]);
+    assert!(manager.next_report().is_none());
+}
+
+#[test]
+fn wrong_sequence_aborts_message() {
+    let mut manager = manager();
+    let cid = allocate(&mut manager);
+    manager.handle_report(&init_packet(cid, 0x10, 100, &[0; 57]));
+    assert_eq!(manager.handle_report(&cont_packet(cid, 1, &[0; 43])), None);
+    assert_error(&mut manager, cid, CtapError::InvalidSeq);
+    assert_eq!(manager.busy_channel(), None);
+}
+
+#[test]
+fn other_channels_are_busy() {
+    let mut manager = manager();
+    let first = allocate(&mut manager);
+    let second = allocate(&mut manager);
+    manager.handle_report(&init_packet(first, 0x10, 100, &[0; 57]));
+    assert_eq!(
+        manager.handle_report(&init_packet(second, 0x10, 1, &[0x04])),
+        None
+    );
+    assert_error(&mut manager, second, CtapError::ChannelBusy);
+    assert_eq!(
+        manager.handle_report(&cont_packet(second, 0, &[0; 43])),
+        None
+    );
+    assert_error(&mut manager, second, CtapError::ChannelBusy);
+
+    // The first message is not affected.
+    let message = manager
+        .handle_report(&cont_packet(first, 0, &[0; 43]))
+        .unwrap();
+    assert_eq!(message.payload.len(), 100);
+    assert_eq!(
+        manager.handle_report(&init_packet(second, 0x10, 1, &[0x04])),
+        None
+    );
+    assert_error(&mut manager, second, CtapError::ChannelBusy);
+
+    manager.error(first, CtapError::Other);
+    assert_error(&mut manager, first, CtapError::Other);
+    assert!(manager
+        .handle_report(&init_packet(second, 0x10, 1, &[0x04]))
+        .is_some());
+}
+
+#[test]
+fn ping_is_answered() {
+    let mut manager = manager();
+    let cid = allocate(&mut manager);
+    assert_eq!(
+        manager.handle_report(&init_packet(cid, 0x01, 3, &[1, 2, 3])),
+        None
+    );
+    assert_eq!(response(&mut manager), (cid, 0x01, vec![1, 2, 3]));
+    assert_eq!(manager.busy_channel(), None);
+}
+
+#[test]
+fn keepalive_and_cancel() {
+    let mut manager = manager();
+    let cid = allocate(&mut manager);
+    manager.keepalive(KeepaliveStatus::Processing);
+    assert!(manager.next_report().is_none());
+
+    manager
+        .handle_report(&init_packet(cid, 0x10, 1, &[0x01]))
+        .unwrap();
+    manager.keepalive(KeepaliveStatus::UpNeeded);
+    assert_eq!(response(&mut manager), (cid, 0x3b, vec![0x02]));
+    let message = manager.handle_report(&init_packet(cid, 0x11, 0, &[]));
+    assert_eq!(
+        message,
+        Some(Message {
+            cid,
+            cmd: CtapCommand::Cancel,
+            payload: Vec::new(),
+        })
+    );
+    manager.respond(cid, CtapCommand::Cbor, &[0x2d]).unwrap();
+    assert_eq!(response(&mut manager), (cid, 0x10, vec![0x2d]));
+
+    // Cancelling without a request does nothing.
+    assert_eq!(manager.handle_report(&init_packet(cid, 0x11, 0, &[])), None);
+    assert!(manager.next_report().is_none());
+}
+
+#[test]
+fn incomplete_messages_time_out() {
+    let mut manager = manager();
+    manager.set_message_timeout(Duration::from_millis(1));
+    let cid = allocate(&mut manager);
+    manager.handle_report(&init_packet(cid, 0x10, 100, &[0; 57]));
+    thread::sleep(Duration::from_millis(5));
+    manager.check_timeout();
+    assert_error(&mut manager, cid, CtapError::MsgTimeout);
+    assert_eq!(manager.busy_channel(), None);
+}
+
+#[test]
+fn init_resynchronizes_channel() {
+    let mut manager = manager();
+    let cid = allocate(&mut manager);
+    manager.handle_report(&init_packet(cid, 0x10, 100, &[0; 57]));
+    manager.handle_report(&init_packet(cid, 0x06, 8, &[9; 8]));
+    let (response_cid, cmd, payload) = response(&mut manager);
+    assert_eq!((response_cid, cmd), (cid, 0x06));
+    assert_eq!(&payload[8..12], &cid);
+    assert_eq!(manager.busy_channel(), None);
+}
+
+#[test]
+fn lock_excludes_other_channels() {
+    let mut manager = manager();
+    let first = allocate(&mut manager);
+    let second = allocate(&mut manager);
+    manager.handle_report(&init_packet(first, 0x04, 1, &[5]));
+    assert_eq!(response(&mut manager), (first, 0x04, vec![]));
+    manager.handle_report(&init_packet(second, 0x01, 1, &[0]));
+    assert_error(&mut manager, second, CtapError::ChannelBusy);
+    manager.handle_report(&init_packet(first, 0x01, 1, &[0]));
+    assert_eq!(response(&mut manager), (first, 0x01, vec![0]));
+
+    manager.handle_report(&init_packet(first, 0x04, 1, &[0]));
+    assert_eq!(response(&mut manager), (first, 0x04, vec![]));
+    manager.handle_report(&init_packet(second, 0x01, 1, &[0]));
+    assert_eq!(response(&mut manager), (second, 0x01, vec![0]));
+
+    manager.handle_report(&init_packet(first, 0x04, 1, &[11]));
+    assert_error(&mut manager, first, CtapError::InvalidPar);
+}
+
+#[test]
+#[should_panic]
+fn rejects_reports_shorter_than_a_packet_header() {
+    let capabilities = DeviceCapabilities {
+        wink: true,
+        cbor: true,
+        nmsg: true,
+    };
+    ChannelManager::new(7, capabilities);
+}
@@ -0,0 +1,347 @@
+// This file is part of ctap, a Rust implementation of the FIDO2 protocol.
+// Copyright (c) Ariën Holthuizen <contact@ardaxi.com>
+// Licensed under the Apache License, Version 2.0, <LICENSE-APACHE or
+// http://apache.org/licenses/LICENSE-2.0> or the MIT license <LICENSE-MIT or
+// http://opensource.org/licenses/MIT>, at your option. This file may not be
+// copied, modified, or distributed except according to those terms.
+//! A simulated authenticator for the tests of how devices talk CTAPHID. It is
+//! built on `ChannelManager` and `VirtualAuthenticator`, and can be made to
+//! hold requests, send keepalives, fail requests or tamper with responses.
+//!
+//! Every test crate only uses part of this module.
+#![allow(dead_code)]
+
+use std::cmp;
+use std::collections::VecDeque;
+use std::io;
+use std::sync::{Arc, Condvar, Mutex, MutexGuard};
+#[cfg(feature = "async")]
+use std::task::{Context, Poll, Waker};
+use std::time::Duration;
+
+#[cfg(feature = "async")]
+use ctap::AsyncTransport;
+use ctap::{ChannelManager, CtapCommand, CtapError, DeviceCapabilities, KeepaliveStatus, Message,
+           ReportWriter, Transport, VirtualAuthenticator};
+
+pub static CTAP2_ERR_KEEPALIVE_CANCEL: u8 = 0x2d;
+// How long reads wait for a report when the device has no timeout, so a test
+// that goes wrong fails instead of hanging.
+const READ_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Answers a CTAPHID_MSG request with a response APDU.
+pub type MsgHandler = Box<dyn FnMut(&[u8]) -> Vec<u8> + Send>;
+
+/// Changes the reports of a response before they are read.
+pub type Tamper = Box<dyn FnMut(Vec<Vec<u8>>) -> Vec<Vec<u8>> + Send>;
+
+/// The state of a simulated authenticator. Tests change its behaviour through
+/// the public fields.
+pub struct State {
+    pub channels: ChannelManager,
+    pub authenticator: VirtualAuthenticator,
+    /// Whether MakeCredential requests are left unanswered until they are
+    /// cancelled, like an authenticator waiting for a touch that never comes.
+    pub hold: bool,
+    /// Whether a held request is answered with CTAP2_ERR_KEEPALIVE_CANCEL once
+    /// it is cancelled. Otherwise it is never answered at all.
+    pub answer_cancel: bool,
+    /// The channel of the request that is being held.
+    pub held: Option<[u8; 4]>,
+    /// The keepalive status sent whenever the host waits for a held request.
+    pub waiting: Option<KeepaliveStatus>,
+    /// Keepalive messages sent before the response to every CBOR request.
+    pub keepalives: Vec<KeepaliveStatus>,
+    /// Errors that the next requests are answered with.
+    pub errors: VecDeque<CtapError>,
+    /// Answers CTAPHID_MSG, for authenticators that implement U2F.
+    pub msg: Option<MsgHandler>,
+    /// Changes the reports of the next response.
+    pub tamper: Option<Tamper>,
+    /// The messages received, in order.
+    pub messages: Vec<Message>,
+    /// The output reports written, including their report ID.
+    pub reports: Vec<Vec<u8>>,
+    /// How many of those were written through a `ReportWriter`.
+    pub out_of_band: usize,
+    responses: VecDeque<Vec<u8>>,
+    // The sizes of the input and output reports of the transports.
+    report_sizes: (usize, usize),
+    #[cfg(feature = "async")]
+    waker: Option<Waker>,
+}
+
+impl State {
+    /// The number of CTAPHID_CANCEL messages received while a request was
+    /// being handled.
+    pub fn cancels(&self) -> usize {
+        self.messages
+            .iter()
+            .filter(|message| message.cmd == CtapCommand::Cancel)
+            .count()
+    }
+
+    fn handle(&mut self, message: Message) {
+        let cid = message.cid;
+        if message.cmd == CtapCommand::Cancel {
+            if self.answer_cancel && self.held == Some(cid) {
+                self.held = None;
+                self.respond(cid, CtapCommand::Cbor, &[CTAP2_ERR_KEEPALIVE_CANCEL]);
+            }
+            return;
+        }
+        if let Some(error) = self.errors.pop_front() {
+            self.channels.error(cid, error);
+            return;
+        }
+        match message.cmd {
+            CtapCommand::Cbor => {
+                for status in &self.keepalives {
+                    self.channels.keepalive(*status);
+                }
+                if self.hold && message.payload[0] == 0x01 {
+                    self.held = Some(cid);
+                    return;
+                }
+                let response = self.authenticator.handle_request(&message.payload);
+                self.respond(cid, CtapCommand::Cbor, &response);
+            }
+            CtapCommand::Msg if self.msg.is_some() => {
+                let response = (self.msg.as_mut().unwrap())(&message.payload);
+                self.respond(cid, CtapCommand::Msg, &response);
+            }
+            CtapCommand::Wink => self.respond(cid, CtapCommand::Wink, &[]),
+            _ => self.channels.error(cid, CtapError::InvalidCmd),
+        }
+    }
+
+    fn respond(&mut self, cid: [u8; 4], cmd: CtapCommand, payload: &[u8]) {
+        self.channels.respond(cid, cmd, payload).unwrap();
+    }
+
+    fn queue_responses(&mut self) {
+        let mut reports = Vec::new();
+        while let Some(report) = self.channels.next_report() {
+            reports.push(report);
+        }
+        if reports.is_empty() {
+            return;
+        }
+        if let Some(mut tamper) = self.tamper.take() {
+            reports = tamper(reports);
+        }
+        self.responses.extend(reports);
+    }
+
+    /// Whether there is an input report to read, sending a keepalive first if
+    /// the host is waiting for a held request.
+    fn has_response(&mut self) -> bool {
+        if self.responses.is_empty() && self.held.is_some() {
+            if let Some(status) = self.waiting {
+                self.channels.keepalive(status);
+                self.queue_responses();
+            }
+        }
+        !self.responses.is_empty()
+    }
+}
+
+/// A simulated authenticator, shared by its transports and their
+/// `ReportWriter`s.
+#[derive(Clone)]
+pub struct Simulator(Arc<(Mutex<State>, Condvar)>);
+
+impl Simulator {
+    /// An authenticator with 64-byte reports that implements CTAP2 but not
+    /// U2F.
+    pub fn new() -> Self {
+        Simulator::with_capabilities(DeviceCapabilities {
+            wink: true,
+            cbor: true,
+            nmsg: true,
+        })
+    }
+
+    pub fn with_capabilities(capabilities: DeviceCapabilities) -> Self {
+        Simulator::with_report_sizes(64, 64, capabilities)
+    }
+
+    /// An authenticator whose input and output reports differ in size.
+    pub fn with_report_sizes(
+        input_report_size: usize,
+        output_report_size: usize,
+        capabilities: DeviceCapabilities,
+    ) -> Self {
+        let channels =
+            ChannelManager::with_report_sizes(input_report_size, output_report_size, capabilities);
+        let simulator = Simulator::with_channels(channels);
+        simulator.state().report_sizes = (input_report_size, output_report_size);
+        simulator
+    }
+
+    /// An authenticator with 64-byte reports built on the given channels.
+    pub fn with_channels(channels: ChannelManager) -> Self {
+        let state = State {
+            channels,
+            authenticator: VirtualAuthenticator::default(),
+            hold: false,
+            answer_cancel: true,
+            held: None,
+            waiting: None,
+            keepalives: Vec::new(),
+            errors: VecDeque::new(),
+            msg: None,
+            tamper: None,
+            messages: Vec::new(),
+            reports: Vec::new(),
+            out_of_band: 0,
+            responses: VecDeque::new(),
+            report_sizes: (64, 64),
+            #[cfg(feature = "async")]
+            waker: None,
+        };
+        Simulator(Arc::new((Mutex::new(state), Condvar::new())))
+    }
+
+    pub fn state(&self) -> MutexGuard<'_, State> {
+        (self.0).0.lock().unwrap()
+    }
+
+    /// A transport to this authenticator that can write reports out of band.
+    pub fn transport(&self) -> SimulatedTransport {
+        let (input_report_size, output_report_size) = self.state().report_sizes;
+        SimulatedTransport {
+            simulator: self.clone(),
+            out_of_band: true,
+            input_report_size,
+            output_report_size,
+        }
+    }
+
+    /// Handle an output report, which starts with its report ID.
+    pub fn write(&self, report: &[u8]) {
+        let mut state = self.state();
+        state.reports.push(report.to_vec());
+        if let Some(message) = state.channels.handle_report(&report[1..]) {
+            state.messages.push(message.clone());
+            state.handle(message);
+        }
+        state.queue_responses();
+        (self.0).1.notify_all();
+        #[cfg(feature = "async")]
+        {
+            if let Some(waker) = state.waker.take() {
+                waker.wake();
+            }
+        }
+    }
+
+    /// Whether any transport or `ReportWriter` to this authenticator is left.
+    pub fn is_connected(&self) -> bool {
+        Arc::strong_count(&self.0) > 1
+    }
+
+    /// Take the input reports that are ready to be read.
+    pub fn take_responses(&self) -> Vec<Vec<u8>> {
+        self.state().responses.drain(..).collect()
+    }
+
+    fn read(&self, timeout: Option<Duration>) -> Option<Vec<u8>> {
+        let timeout = timeout.unwrap_or(READ_TIMEOUT);
+        let (ref state, ref condvar) = *self.0;
+        let (mut state, _) = condvar
+            .wait_timeout_while(state.lock().unwrap(), timeout, |state| {
+                !state.has_response()
+            })
+            .unwrap();
+        state.responses.pop_front()
+    }
+}
+
+fn copy_report(report: Option<Vec<u8>>, buf: &mut [u8]) -> io::Result<usize> {
+    let report =
+        report.ok_or_else(|| io::Error::new(io::ErrorKind::TimedOut, "no response"))?;
+    let len = cmp::min(buf.len(), report.len());
+    buf[..len].copy_from_slice(&report[..len]);
+    Ok(len)
+}
+
+/// A transport to a `Simulator`, with unnumbered reports.
+pub struct SimulatedTransport {
+    simulator: Simulator,
+    /// Whether `report_writer` returns a writer, like hidraw devices do.
+    pub out_of_band: bool,
+    pub input_report_size: usize,
+    pub output_report_size: usize,
+}
+
+impl Transport for SimulatedTransport {
+    fn input_report_size(&self) -> usize {
+        self.input_report_size
+    }
+
+    fn output_report_size(&self) -> usize {
+        self.output_report_size
+    }
+
+    fn write_report(&mut self, report: &[u8]) -> io::Result<()> {
+        self.simulator.write(report);
+        Ok(())
+    }
+
+    fn read_report(&mut self, buf: &mut [u8], timeout: Option<Duration>) -> io::Result<usize> {
+        copy_report(self.simulator.read(timeout), buf)
+    }
+
+    fn report_writer(&self) -> Option<Box<dyn ReportWriter>> {
+        if self.out_of_band {
+            Some(Box::new(SimulatedWriter(self.simulator.clone())))
+        } else {
+            None
+        }
+    }
+}
+
+#[cfg(feature = "async")]
+impl AsyncTransport for SimulatedTransport {
+    fn input_report_size(&self) -> usize {
+        self.input_report_size
+    }
+
+    fn output_report_size(&self) -> usize {
+        self.output_report_size
+    }
+
+    fn poll_write_report(&mut self, _cx: &mut Context<'_>, report: &[u8]) -> Poll<io::Result<()>> {
+        self.simulator.write(report);
+        Poll::Ready(Ok(()))
+    }
+
+    fn poll_read_report(
+        &mut self,
+        cx: &mut Context<'_>,
+        buf: &mut [u8],
+    ) -> Poll<io::Result<usize>> {
+        let mut state = self.simulator.state();
+        if state.has_response() {
+            Poll::Ready(copy_report(state.responses.pop_front(), buf))
+        } else {
+            state.waker = Some(cx.waker().clone());
+            Poll::Pending
+        }
+    }
+
+    fn report_writer(&self) -> Option<Box<dyn ReportWriter>> {
+        Transport::report_writer(self)
+    }
+}
+
+struct SimulatedWriter(Simulator);
+
+impl ReportWriter for SimulatedWriter {
+    fn write_report(&mut self, report: &[u8]) -> io::Result<()> {
+        self.0.state().out_of_band += 1;
+        self.0.write(report);
+        Ok(())
+    }
+}
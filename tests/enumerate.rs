@@ -0,0 +1,146 @@
+// This file is part of ctap, a Rust implementation of the FIDO2 protocol.
+// Copyright (c) Ariën Holthuizen <contact@ardaxi.com>
+// Licensed under the Apache License, Version 2.0, <LICENSE-APACHE or
+// http://apache.org/licenses/LICENSE-2.0> or the MIT license <LICENSE-MIT or
+// http://opensource.org/licenses/MIT>, at your option. This file may not be
+// copied, modified, or distributed except according to those terms.
+extern crate ctap;
+
+use std::path::{Path, PathBuf};
+
+use ctap::{BusType, DeviceEnumerator, DeviceInfo};
+
+fn fixture_devices() -> Vec<DeviceInfo> {
+    let sysfs_root = Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/fixtures/sys");
+    let mut devices: Vec<_> = DeviceEnumerator::new(sysfs_root, "/dev")
+        .enumerate()
+        .unwrap()
+        .collect();
+    devices.sort_by(|a, b| a.path.cmp(&b.path));
+    devices
+}
+
+fn fixture_device(name: &str) -> DeviceInfo {
+    let path = Path::new("/dev").join(name);
+    fixture_devices()
+        .into_iter()
+        .find(|device| device.path == path)
+        .unwrap()
+}
+
+#[test]
+fn enumerates_all_devices() {
+    let paths: Vec<_> = fixture_devices().into_iter().map(|device| device.path).collect();
+    let expected: Vec<_> = (0..6)
+        .map(|n| PathBuf::from(format!("/dev/hidraw{}", n)))
+        .collect();
+    assert_eq!(paths, expected);
+}
+
+#[test]
+fn uses_dev_root_for_device_paths() {
+    let sysfs_root = Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/fixtures/sys");
+    let device = DeviceEnumerator::new(sysfs_root, "/run/dev")
+        .enumerate()
+        .unwrap()
+        .next()
+        .unwrap();
+    assert!(device.path.starts_with("/run/dev"));
+}
+
+#[test]
+fn missing_sysfs_root_is_an_error() {
+    let sysfs_root = Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/fixtures/missing");
+    assert!(DeviceEnumerator::new(sysfs_root, "/dev").enumerate().is_err());
+}
+
+fn assert_fido(device: &DeviceInfo) {
+    assert!(device.is_fido());
+    assert_eq!(device.usage_page, 0xf1d0);
+    assert_eq!(device.usage, 0x01);
+    assert_eq!(device.input_report_size, 64);
+    assert_eq!(device.output_report_size, 64);
+}
+
+fn assert_unnumbered(device: &DeviceInfo) {
+    assert_eq!(device.input_report_id, None);
+    assert_eq!(device.output_report_id, None);
+}
+
+#[test]
+fn yubikey() {
+    let device = fixture_device("hidraw0");
+    assert_fido(&device);
+    assert_unnumbered(&device);
+    assert_eq!(device.bus_type, BusType::Usb);
+    assert_eq!(device.vendor_id, 0x1050);
+    assert_eq!(device.product_id, 0x0407);
+    assert_eq!(device.manufacturer.as_ref().unwrap(), "Yubico");
+    assert_eq!(device.product.as_ref().unwrap(), "YubiKey OTP+FIDO+CCID");
+    assert_eq!(device.serial_number, None);
+}
+
+#[test]
+fn solokey() {
+    let device = fixture_device("hidraw1");
+    assert_fido(&device);
+    assert_unnumbered(&device);
+    assert_eq!(device.bus_type, BusType::Usb);
+    assert_eq!(device.vendor_id, 0x0483);
+    assert_eq!(device.product_id, 0xa2ca);
+    assert_eq!(device.manufacturer.as_ref().unwrap(), "SoloKeys");
+    assert_eq!(device.product.as_ref().unwrap(), "Solo 4.1.5");
+    assert_eq!(device.serial_number.as_ref().unwrap(), "206A3292524B");
+}
+
+#[test]
+fn nitrokey() {
+    let device = fixture_device("hidraw2");
+    assert_fido(&device);
+    assert_unnumbered(&device);
+    assert_eq!(device.bus_type, BusType::Usb);
+    assert_eq!(device.vendor_id, 0x20a0);
+    assert_eq!(device.product_id, 0x42b1);
+    assert_eq!(device.manufacturer.as_ref().unwrap(), "Nitrokey");
+    assert_eq!(device.product.as_ref().unwrap(), "Nitrokey FIDO2 2.4.0");
+    assert_eq!(device.serial_number.as_ref().unwrap(), "2059374E3254");
+}
+
+#[test]
+fn boot_keyboard() {
+    let device = fixture_device("hidraw3");
+    assert!(!device.is_fido());
+    assert_eq!(device.usage_page, 0x01);
+    assert_eq!(device.usage, 0x06);
+    assert_eq!(device.input_report_size, 8);
+    assert_eq!(device.output_report_size, 1);
+    assert_eq!(device.bus_type, BusType::Bluetooth);
+    assert_eq!(device.vendor_id, 0x046d);
+    assert_eq!(device.product_id, 0xb342);
+    assert_eq!(device.manufacturer, None);
+    assert_eq!(device.product.as_ref().unwrap(), "Bluetooth Keyboard");
+    assert_eq!(device.serial_number.as_ref().unwrap(), "e8:9f:80:42:13:37");
+}
+
+#[test]
+fn wheel_mouse() {
+    let device = fixture_device("hidraw4");
+    assert!(!device.is_fido());
+    assert_eq!(device.usage_page, 0x01);
+    assert_eq!(device.usage, 0x02);
+    // Buttons, X, Y, the wheel and horizontal scrolling.
+    assert_eq!(device.input_report_size, 5);
+    assert_eq!(device.output_report_size, 0);
+    assert_eq!(device.bus_type, BusType::Unknown);
+    assert_eq!(device.vendor_id, 0);
+    assert_eq!(device.product_id, 0);
+    assert_eq!(device.product, None);
+}
+
+#[test]
+fn composite_device() {
+    let device = fixture_device("hidraw5");
+    assert_fido(&device);
+    assert_eq!(device.input_report_id, Some(2));
+    assert_eq!(device.output_report_id, Some(2));
+}
@@ -0,0 +1,53 @@
+// This file is part of ctap, a Rust implementation of the FIDO2 protocol.
+// Copyright (c) Ariën Holthuizen <contact@ardaxi.com>
+// Licensed under the Apache License, Version 2.0, <LICENSE-APACHE or
+// http://apache.org/licenses/LICENSE-2.0> or the MIT license <LICENSE-MIT or
+// http://opensource.org/licenses/MIT>, at your option. This file may not be
+// copied, modified, or distributed except according to those terms.
+#![cfg(feature = "virtual-authenticator")]
+extern crate ctap;
+
+mod common;
+
+use std::sync::{Arc, Mutex};
+
+use ctap::{FidoDevice, KeepaliveStatus};
+
+use common::Simulator;
+
+/// Open a device whose keepalive callback records every status it is given.
+fn recording_device(
+    simulator: &Simulator,
+) -> (FidoDevice<common::SimulatedTransport>, Arc<Mutex<Vec<KeepaliveStatus>>>) {
+    let statuses = Arc::new(Mutex::new(Vec::new()));
+    let mut device = FidoDevice::with_transport(simulator.transport()).unwrap();
+    let recorded = statuses.clone();
+    device.set_keepalive_callback(move |status| recorded.lock().unwrap().push(status));
+    (device, statuses)
+}
+
+#[test]
+fn callback_receives_every_keepalive() {
+    let simulator = Simulator::new();
+    let (mut device, statuses) = recording_device(&simulator);
+    simulator.state().keepalives = vec![KeepaliveStatus::Processing, KeepaliveStatus::UpNeeded];
+    let credential = device
+        .make_credential("example.com", &[1], "user", &[0; 32])
+        .unwrap();
+    assert_eq!(
+        *statuses.lock().unwrap(),
+        [KeepaliveStatus::Processing, KeepaliveStatus::UpNeeded]
+    );
+    assert!(device.get_assertion(&credential, &[0; 32]).unwrap());
+    assert_eq!(statuses.lock().unwrap().len(), 4);
+}
+
+#[test]
+fn callback_is_not_invoked_without_keepalives() {
+    let simulator = Simulator::new();
+    let (mut device, statuses) = recording_device(&simulator);
+    device
+        .make_credential("example.com", &[1], "user", &[0; 32])
+        .unwrap();
+    assert!(statuses.lock().unwrap().is_empty());
+}
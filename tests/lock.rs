@@ -0,0 +1,70 @@
+// This file is part of ctap, a Rust implementation of the FIDO2 protocol.
+// Copyright (c) Ariën Holthuizen <contact@ardaxi.com>
+// Licensed under the Apache License, Version 2.0, <LICENSE-APACHE or
+// http://apache.org/licenses/LICENSE-2.0> or the MIT license <LICENSE-MIT or
+// http://opensource.org/licenses/MIT>, at your option. This file may not be
+// copied, modified, or distributed except according to those terms.
+#![cfg(feature = "virtual-authenticator")]
+extern crate ctap;
+extern crate failure;
+
+mod common;
+
+use std::time::Duration;
+
+use failure::Fail;
+
+use ctap::{CtapError, FidoDevice, FidoErrorKind, RetryPolicy};
+
+use common::Simulator;
+
+const CTAPHID_LOCK: u8 = 0x84;
+
+/// The payloads of the LOCK requests written, which fit in a single packet.
+fn locks(simulator: &Simulator) -> Vec<Vec<u8>> {
+    simulator
+        .state()
+        .reports
+        .iter()
+        // Reports start with their ID, then the channel, command and length.
+        .filter(|report| report[5] == CTAPHID_LOCK)
+        .map(|report| report[8..8 + report[7] as usize].to_vec())
+        .collect()
+}
+
+#[test]
+fn lock_is_released_on_drop() {
+    let simulator = Simulator::new();
+    let mut device = FidoDevice::with_transport(simulator.transport()).unwrap();
+    {
+        let mut lock = device.lock(Duration::from_secs(2)).unwrap();
+        assert_eq!(locks(&simulator), [vec![2]]);
+        lock.ping(&[1, 2, 3]).unwrap();
+    }
+    assert_eq!(locks(&simulator), [vec![2], vec![0]]);
+}
+
+#[test]
+fn lock_duration_is_rounded_up() {
+    let simulator = Simulator::new();
+    let mut device = FidoDevice::with_transport(simulator.transport()).unwrap();
+    drop(device.lock(Duration::from_millis(1500)).unwrap());
+    drop(device.lock(Duration::from_secs(60)).unwrap());
+    assert_eq!(locks(&simulator), [vec![2], vec![0], vec![10], vec![0]]);
+}
+
+#[test]
+fn other_channels_are_busy_while_locked() {
+    let simulator = Simulator::new();
+    let mut device = FidoDevice::with_transport(simulator.transport()).unwrap();
+    let mut other = FidoDevice::with_transport(simulator.transport()).unwrap();
+    other.set_retry_policy(RetryPolicy::never());
+    {
+        let _lock = device.lock(Duration::from_secs(2)).unwrap();
+        let err = other.ping(&[1, 2, 3]).unwrap_err();
+        assert_eq!(err.kind(), FidoErrorKind::ParseCtap);
+        let cause = err.cause().and_then(|cause| cause.downcast_ref::<CtapError>());
+        assert_eq!(cause, Some(&CtapError::ChannelBusy));
+    }
+    other.ping(&[1, 2, 3]).unwrap();
+}
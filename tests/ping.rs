@@ -0,0 +1,54 @@
+// This file is part of ctap, a Rust implementation of the FIDO2 protocol.
+// Copyright (c) Ariën Holthuizen <contact@ardaxi.com>
+// Licensed under the Apache License, Version 2.0, <LICENSE-APACHE or
+// http://apache.org/licenses/LICENSE-2.0> or the MIT license <LICENSE-MIT or
+// http://opensource.org/licenses/MIT>, at your option. This file may not be
+// copied, modified, or distributed except according to those terms.
+#![cfg(feature = "virtual-authenticator")]
+extern crate ctap;
+
+mod common;
+
+use std::thread;
+use std::time::{Duration, Instant};
+
+use ctap::{FidoDevice, FidoErrorKind};
+
+use common::Simulator;
+
+const DELAY: Duration = Duration::from_millis(50);
+
+#[test]
+fn ping_is_echoed() {
+    let simulator = Simulator::new();
+    let mut device = FidoDevice::with_transport(simulator.transport()).unwrap();
+    device.ping(&[]).unwrap();
+    device.ping(&[0x42; 200]).unwrap();
+}
+
+#[test]
+fn corrupted_echo() {
+    let simulator = Simulator::new();
+    let mut device = FidoDevice::with_transport(simulator.transport()).unwrap();
+    simulator.state().tamper = Some(Box::new(|mut reports: Vec<Vec<u8>>| {
+        // The first byte of the payload, after the channel, command and length.
+        reports[0][7] ^= 0xff;
+        reports
+    }));
+    let err = device.ping(&[0x42; 100]).unwrap_err();
+    assert_eq!(err.kind(), FidoErrorKind::PingMismatch);
+}
+
+#[test]
+fn latency_is_measured() {
+    let simulator = Simulator::new();
+    let mut device = FidoDevice::with_transport(simulator.transport()).unwrap();
+    simulator.state().tamper = Some(Box::new(|reports| {
+        thread::sleep(DELAY);
+        reports
+    }));
+    let start = Instant::now();
+    let latency = device.ping(&[1, 2, 3]).unwrap();
+    assert!(latency >= DELAY);
+    assert!(latency <= start.elapsed());
+}
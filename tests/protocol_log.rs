@@ -0,0 +1,100 @@
+// This file is part of ctap, a Rust implementation of the FIDO2 protocol.
+// Copyright (c) Ariën Holthuizen <contact@ardaxi.com>
+// Licensed under the Apache License, Version 2.0, <LICENSE-APACHE or
+// http://apache.org/licenses/LICENSE-2.0> or the MIT license <LICENSE-MIT or
+// http://opensource.org/licenses/MIT>, at your option. This file may not be
+// copied, modified, or distributed except according to those terms.
+#![cfg(all(feature = "trace", feature = "virtual-authenticator"))]
+extern crate ctap;
+extern crate log;
+
+use std::sync::Mutex;
+
+use log::{Level, LevelFilter, Log, Metadata, Record};
+
+use ctap::{AuthenticatorConfig, FidoDevice, MemoryTransport, VirtualAuthenticator};
+
+static MESSAGES: Mutex<Vec<(Level, String)>> = Mutex::new(Vec::new());
+
+struct Logger;
+
+impl Log for Logger {
+    fn enabled(&self, _metadata: &Metadata) -> bool {
+        true
+    }
+
+    fn log(&self, record: &Record) {
+        if record.target().starts_with("ctap") {
+            let message = (record.level(), record.args().to_string());
+            MESSAGES.lock().unwrap().push(message);
+        }
+    }
+
+    fn flush(&self) {}
+}
+
+static LOGGER: Logger = Logger;
+
+fn messages(level: Level) -> Vec<String> {
+    MESSAGES
+        .lock()
+        .unwrap()
+        .iter()
+        .filter(|message| message.0 == level)
+        .map(|message| message.1.clone())
+        .collect()
+}
+
+// There can only be one logger, so everything is tested at once.
+#[test]
+fn logs_protocol_without_secrets() {
+    log::set_logger(&LOGGER).unwrap();
+    log::set_max_level(LevelFilter::Trace);
+
+    let config = AuthenticatorConfig {
+        pin: Some("1234".to_string()),
+        ..Default::default()
+    };
+    let transport = MemoryTransport::new(VirtualAuthenticator::new(config));
+    let mut device = FidoDevice::with_transport(transport).unwrap();
+    assert!(device.unlock("4321").is_err());
+    device.unlock("1234").unwrap();
+    device
+        .make_credential("example.com", &[1], "user", &[0; 32])
+        .unwrap();
+
+    let frames = messages(Level::Trace);
+    assert!(frames
+        .iter()
+        .any(|message| message.starts_with("sending Init on channel [ff, ff, ff, ff], 8 bytes")));
+    assert!(frames
+        .iter()
+        .any(|message| message.starts_with("received Cbor on channel")));
+
+    let cbor = messages(Level::Debug);
+    assert_eq!(cbor[0], "request: authenticatorGetInfo (0x04)");
+    assert!(cbor[1].starts_with("response: status 0x00 {1: [\"FIDO_2_0\"]"));
+    // The wrong PIN is rejected with CTAP2_ERR_PIN_INVALID.
+    assert!(cbor
+        .iter()
+        .any(|message| message == "response: status 0x31"));
+
+    let get_pin_token: Vec<_> = cbor
+        .iter()
+        .filter(|message| message.contains("authenticatorClientPIN (0x06) {1: 1, 2: 5,"))
+        .collect();
+    assert_eq!(get_pin_token.len(), 2);
+    assert!(get_pin_token
+        .iter()
+        .all(|message| message.ends_with(", 6: / redacted /}")));
+    assert!(cbor
+        .iter()
+        .any(|message| message == "response: status 0x00 {2: / redacted /}"));
+
+    let make_credential = cbor
+        .iter()
+        .find(|message| message.contains("authenticatorMakeCredential"))
+        .unwrap();
+    assert!(make_credential.contains("2: {\"id\": \"example.com\"}"));
+    assert!(make_credential.contains("8: / redacted /, 9: 1}"));
+}
@@ -0,0 +1,140 @@
+// This file is part of ctap, a Rust implementation of the FIDO2 protocol.
+// Copyright (c) Ariën Holthuizen <contact@ardaxi.com>
+// Licensed under the Apache License, Version 2.0, <LICENSE-APACHE or
+// http://apache.org/licenses/LICENSE-2.0> or the MIT license <LICENSE-MIT or
+// http://opensource.org/licenses/MIT>, at your option. This file may not be
+// copied, modified, or distributed except according to those terms.
+#![cfg(feature = "virtual-authenticator")]
+extern crate ctap;
+extern crate failure;
+
+mod common;
+
+use failure::Fail;
+
+use ctap::{CtapError, FidoDevice, FidoErrorKind};
+
+use common::Simulator;
+
+static OTHER_CID: [u8; 4] = [0x12, 0x34, 0x56, 0x78];
+
+/// Changes the reports of a response before they are read.
+type Tamper = fn(Vec<Vec<u8>>) -> Vec<Vec<u8>>;
+
+/// Open a device, and change the response to a 150-byte ping, which takes three
+/// packets, with `tamper`.
+fn ping(tamper: Tamper) -> Result<(), ctap::FidoError> {
+    let simulator = Simulator::new();
+    let mut device = FidoDevice::with_transport(simulator.transport()).unwrap();
+    simulator.state().tamper = Some(Box::new(tamper));
+    device.ping(&[0x42; 150]).map(|_| ())
+}
+
+fn init_packet(cid: &[u8], cmd: u8, payload: &[u8]) -> Vec<u8> {
+    let mut report = cid.to_vec();
+    report.push(0x80 | cmd);
+    report.push((payload.len() >> 8) as u8);
+    report.push(payload.len() as u8);
+    report.extend_from_slice(payload);
+    report.resize(64, 0);
+    report
+}
+
+fn cont_packet(cid: &[u8], seq: u8) -> Vec<u8> {
+    let mut report = cid.to_vec();
+    report.push(seq);
+    report.resize(64, 0x42);
+    report
+}
+
+fn ctap_error(err: &ctap::FidoError) -> Option<CtapError> {
+    err.cause()
+        .and_then(|cause| cause.downcast_ref::<CtapError>())
+        .cloned()
+}
+
+#[test]
+fn untampered_response() {
+    ping(|reports| {
+        assert_eq!(reports.len(), 3);
+        reports
+    })
+    .unwrap();
+}
+
+#[test]
+fn keepalive_during_response() {
+    ping(|mut reports| {
+        let keepalive = init_packet(&reports[0][0..4], 0x3b, &[0x01]);
+        reports.insert(1, keepalive);
+        reports
+    })
+    .unwrap();
+}
+
+#[test]
+fn error_during_response() {
+    let err = ping(|mut reports| {
+        let error = init_packet(&reports[0][0..4], 0x3f, &[0x7f]);
+        reports.insert(2, error);
+        reports
+    })
+    .unwrap_err();
+    assert_eq!(err.kind(), FidoErrorKind::ParseCtap);
+    assert_eq!(ctap_error(&err), Some(CtapError::Other));
+}
+
+#[test]
+fn other_channels_are_skipped() {
+    ping(|mut reports| {
+        reports.insert(0, init_packet(&OTHER_CID, 0x3f, &[0x04]));
+        reports.insert(1, cont_packet(&OTHER_CID, 0));
+        reports.insert(2, init_packet(&OTHER_CID, 0x01, &[0; 100]));
+        reports.insert(3, cont_packet(&OTHER_CID, 0));
+        reports.insert(5, init_packet(&OTHER_CID, 0x3f, &[0x06]));
+        reports
+    })
+    .unwrap();
+}
+
+#[test]
+fn stray_continuation_packets_are_skipped() {
+    ping(|mut reports| {
+        let stray = cont_packet(&reports[0][0..4], 1);
+        reports.insert(0, stray);
+        reports
+    })
+    .unwrap();
+}
+
+#[test]
+fn other_commands_are_skipped() {
+    ping(|mut reports| {
+        let wink = init_packet(&reports[0][0..4], 0x08, &[]);
+        reports.insert(1, wink);
+        reports
+    })
+    .unwrap();
+}
+
+#[test]
+fn restarted_response() {
+    ping(|mut reports| {
+        let first = reports[0].clone();
+        let second = reports[1].clone();
+        reports.insert(0, second);
+        reports.insert(0, first);
+        reports
+    })
+    .unwrap();
+}
+
+#[test]
+fn wrong_sequence_is_an_error() {
+    let err = ping(|mut reports| {
+        reports.swap(1, 2);
+        reports
+    })
+    .unwrap_err();
+    assert_eq!(err.kind(), FidoErrorKind::InvalidSequence);
+}
@@ -0,0 +1,207 @@
+// This file is part of ctap, a Rust implementation of the FIDO2 protocol.
+// Copyright (c) Ariën Holthuizen <contact@ardaxi.com>
+// Licensed under the Apache License, Version 2.0, <LICENSE-APACHE or
+// http://apache.org/licenses/LICENSE-2.0> or the MIT license <LICENSE-MIT or
+// http://opensource.org/licenses/MIT>, at your option. This file may not be
+// copied, modified, or distributed except according to those terms.
+#![cfg(feature = "virtual-authenticator")]
+extern crate ctap;
+
+use std::env;
+use std::fs;
+use std::io::{self, Cursor};
+use std::path::PathBuf;
+use std::process;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+
+use ctap::{Direction, FidoDevice, MemoryTransport, RecordingTransport, ReplayTransport,
+           ReportWriter, Trace, Transport, VirtualAuthenticator};
+
+/// A transport that can write reports out of band, like a hidraw device.
+struct OutOfBandTransport {
+    inner: MemoryTransport,
+    writes: Arc<AtomicUsize>,
+}
+
+impl Transport for OutOfBandTransport {
+    fn write_report(&mut self, report: &[u8]) -> io::Result<()> {
+        self.inner.write_report(report)
+    }
+
+    fn read_report(&mut self, buf: &mut [u8], timeout: Option<Duration>) -> io::Result<usize> {
+        self.inner.read_report(buf, timeout)
+    }
+
+    fn report_writer(&self) -> Option<Box<dyn ReportWriter>> {
+        Some(Box::new(CountingWriter(self.writes.clone())))
+    }
+}
+
+struct CountingWriter(Arc<AtomicUsize>);
+
+impl ReportWriter for CountingWriter {
+    fn write_report(&mut self, _report: &[u8]) -> io::Result<()> {
+        self.0.fetch_add(1, Ordering::SeqCst);
+        Ok(())
+    }
+}
+
+fn trace_path(name: &str) -> PathBuf {
+    env::temp_dir().join(format!("ctap-{}-{}.trace", name, process::id()))
+}
+
+fn record_session(path: &PathBuf) -> Vec<u8> {
+    let transport = MemoryTransport::new(VirtualAuthenticator::default());
+    let transport = RecordingTransport::create(transport, path).unwrap();
+    let mut device = FidoDevice::with_transport(transport).unwrap();
+    let credential = device
+        .make_credential("example.com", &[1], "user", &[0; 32])
+        .unwrap();
+    assert!(device.get_assertion(&credential, &[1; 32]).unwrap());
+    credential.id
+}
+
+#[test]
+fn replays_recorded_session() {
+    let path = trace_path("replay");
+    let credential_id = record_session(&path);
+
+    let trace = Trace::open(&path).unwrap();
+    assert_eq!(trace.input_report_size, 64);
+    assert_eq!(trace.output_report_id, None);
+    assert_eq!(trace.reports[0].direction, Direction::Output);
+    assert_eq!(trace.reports[0].data.len(), 65);
+    assert!(trace
+        .reports
+        .windows(2)
+        .all(|reports| reports[0].timestamp <= reports[1].timestamp));
+
+    let mut transport = ReplayTransport::open(&path).unwrap();
+    transport.set_verify_output(true);
+    let mut device = FidoDevice::with_transport(transport).unwrap();
+    let credential = device
+        .make_credential("example.com", &[1], "user", &[0; 32])
+        .unwrap();
+    assert_eq!(credential.id, credential_id);
+    assert!(device.get_assertion(&credential, &[1; 32]).unwrap());
+    fs::remove_file(&path).unwrap();
+}
+
+#[test]
+fn replay_detects_different_requests() {
+    let path = trace_path("verify");
+    record_session(&path);
+
+    let mut transport = ReplayTransport::open(&path).unwrap();
+    transport.set_verify_output(true);
+    let mut device = FidoDevice::with_transport(transport).unwrap();
+    assert!(device
+        .make_credential("example.org", &[1], "user", &[0; 32])
+        .is_err());
+    fs::remove_file(&path).unwrap();
+}
+
+#[test]
+fn replay_follows_the_trace() {
+    let trace = "ctap-trace 1\n\
+                 # Comments and empty lines are ignored.\n\
+                 input 64 none\n\
+                 output 64 none\n\
+                 \n\
+                 out 0.000010 000102\n\
+                 in 0.001000 0304\n";
+    let mut transport = ReplayTransport::new(Trace::read(Cursor::new(trace)).unwrap());
+    assert_eq!(transport.remaining(), 2);
+    let mut buf = [0; 64];
+    let err = transport.read_report(&mut buf, None).unwrap_err();
+    assert_eq!(err.kind(), io::ErrorKind::TimedOut);
+
+    transport.write_report(&[0, 1, 2]).unwrap();
+    assert_eq!(transport.read_report(&mut buf, None).unwrap(), 2);
+    assert_eq!(&buf[..2], &[3, 4]);
+    assert_eq!(transport.remaining(), 0);
+
+    let err = transport.write_report(&[0, 1, 2]).unwrap_err();
+    assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+    let err = transport.read_report(&mut buf, None).unwrap_err();
+    assert_eq!(err.kind(), io::ErrorKind::TimedOut);
+}
+
+#[test]
+fn recording_writes_trace() {
+    let transport = MemoryTransport::new(VirtualAuthenticator::default());
+    let mut transport = RecordingTransport::new(transport, Vec::new()).unwrap();
+    let mut report = vec![0, 0xff, 0xff, 0xff, 0xff, 0x86, 0, 8, 1, 2, 3, 4, 5, 6, 7, 8];
+    report.resize(65, 0);
+    transport.write_report(&report).unwrap();
+    let mut buf = [0; 64];
+    assert_eq!(transport.read_report(&mut buf, None).unwrap(), 64);
+    assert!(transport.read_report(&mut buf, None).is_err());
+
+    let (_, trace) = transport.into_inner();
+    let trace = Trace::read(Cursor::new(trace)).unwrap();
+    assert_eq!(trace.reports.len(), 2);
+    assert_eq!(trace.reports[0].data, report);
+    assert_eq!(trace.reports[1].direction, Direction::Input);
+    assert_eq!(&trace.reports[1].data[..], &buf[..]);
+}
+
+#[test]
+fn report_writer_is_forwarded() {
+    let writes = Arc::new(AtomicUsize::new(0));
+    let transport = OutOfBandTransport {
+        inner: MemoryTransport::new(VirtualAuthenticator::default()),
+        writes: writes.clone(),
+    };
+    let transport = RecordingTransport::new(transport, Vec::new()).unwrap();
+    let mut writer = transport.report_writer().unwrap();
+    writer.write_report(&[0; 65]).unwrap();
+    assert_eq!(writes.load(Ordering::SeqCst), 1);
+
+    // Out-of-band writes don't end up in the trace.
+    let (_, trace) = transport.into_inner();
+    let trace = Trace::read(Cursor::new(trace)).unwrap();
+    assert!(trace.reports.is_empty());
+}
+
+#[test]
+fn empty_reads_are_replayed() {
+    let transport = ReplayTransport::new(
+        Trace::read(Cursor::new(
+            "ctap-trace 1\ninput 64 none\noutput 64 none\nin 0.000010 \nin 0.000020\n",
+        ))
+        .unwrap(),
+    );
+    let mut buf = [0; 64];
+    let mut transport = RecordingTransport::new(transport, Vec::new()).unwrap();
+    assert_eq!(transport.read_report(&mut buf, None).unwrap(), 0);
+    assert_eq!(transport.read_report(&mut buf, None).unwrap(), 0);
+
+    // The recording of those reads can be read back.
+    let (_, trace) = transport.into_inner();
+    let trace = Trace::read(Cursor::new(trace)).unwrap();
+    assert_eq!(trace.reports.len(), 2);
+    assert_eq!(trace.reports[0].direction, Direction::Input);
+    assert!(trace.reports[0].data.is_empty());
+}
+
+#[test]
+fn invalid_traces_are_rejected() {
+    let traces = [
+        "",
+        "not a trace\n",
+        "ctap-trace 1\ninput 64 none\n",
+        "ctap-trace 1\ninput 64 none\noutput x none\n",
+        "ctap-trace 1\ninput 64 none\noutput 64 none\nsideways 0.0 00\n",
+        "ctap-trace 1\ninput 64 none\noutput 64 none\nout 0.0 0\n",
+        "ctap-trace 1\ninput 64 none\noutput 64 none\nin 0 00\n",
+        "ctap-trace 1\ninput 64 none\noutput 64 none\nin\n",
+        "ctap-trace 1\ninput 64 none\noutput 64 none\nin 0.0 00 00\n",
+    ];
+    for trace in traces.iter() {
+        let err = Trace::read(Cursor::new(trace)).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData, "{:?}", trace);
+    }
+}
@@ -0,0 +1,200 @@
+// This file is part of ctap, a Rust implementation of the FIDO2 protocol.
+// Copyright (c) Ariën Holthuizen <contact@ardaxi.com>
+// Licensed under the Apache License, Version 2.0, <LICENSE-APACHE or
+// http://apache.org/licenses/LICENSE-2.0> or the MIT license <LICENSE-MIT or
+// http://opensource.org/licenses/MIT>, at your option. This file may not be
+// copied, modified, or distributed except according to those terms.
+extern crate ctap;
+
+use ctap::{DescriptorError, ReportDescriptor, ReportKind};
+
+static FIDO: &[u8] = &[
+    0x06, 0xd0, 0xf1, // Usage Page (FIDO Alliance)
+    0x09, 0x01, // Usage (CTAPHID)
+    0xa1, 0x01, // Collection (Application)
+    0x09, 0x20, //   Usage (Input Report Data)
+    0x15, 0x00, //   Logical Minimum (0)
+    0x26, 0xff, 0x00, //   Logical Maximum (255)
+    0x75, 0x08, //   Report Size (8)
+    0x95, 0x40, //   Report Count (64)
+    0x81, 0x02, //   Input (Data, Variable, Absolute)
+    0x09, 0x21, //   Usage (Output Report Data)
+    0x15, 0x00, //   Logical Minimum (0)
+    0x26, 0xff, 0x00, //   Logical Maximum (255)
+    0x75, 0x08, //   Report Size (8)
+    0x95, 0x40, //   Report Count (64)
+    0x91, 0x02, //   Output (Data, Variable, Absolute)
+    0xc0, // End Collection
+];
+
+#[test]
+fn fido_collection() {
+    let descriptor = ReportDescriptor::parse(FIDO).unwrap();
+    assert_eq!(descriptor.collections.len(), 1);
+    let collection = descriptor.find_collection(0xf1d0, 0x01).unwrap();
+    assert_eq!(collection.collection_type, 0x01);
+    assert_eq!(collection.fields.len(), 2);
+    assert!(collection.report_ids(ReportKind::Input).is_empty());
+    assert_eq!(collection.report_length(ReportKind::Input, None), 64);
+    assert_eq!(collection.report_length(ReportKind::Output, None), 64);
+    assert_eq!(collection.report_length(ReportKind::Feature, None), 0);
+}
+
+#[test]
+fn composite_device_with_report_ids() {
+    let descriptor = ReportDescriptor::parse(&[
+        0x05, 0x01, // Usage Page (Generic Desktop)
+        0x09, 0x02, // Usage (Mouse)
+        0xa1, 0x01, // Collection (Application)
+        0x85, 0x01, //   Report ID (1)
+        0x09, 0x01, //   Usage (Pointer)
+        0xa1, 0x00, //   Collection (Physical)
+        0x75, 0x08, //     Report Size (8)
+        0x95, 0x03, //     Report Count (3)
+        0x81, 0x06, //     Input (Data, Variable, Relative)
+        0xc0, //   End Collection
+        0xc0, // End Collection
+        0x06, 0xd0, 0xf1, // Usage Page (FIDO Alliance)
+        0x09, 0x01, // Usage (CTAPHID)
+        0xa1, 0x01, // Collection (Application)
+        0x85, 0x02, //   Report ID (2)
+        0x75, 0x08, //   Report Size (8)
+        0x95, 0x40, //   Report Count (64)
+        0x81, 0x02, //   Input (Data, Variable, Absolute)
+        0x91, 0x02, //   Output (Data, Variable, Absolute)
+        0xc0, // End Collection
+    ]).unwrap();
+    assert_eq!(descriptor.collections.len(), 2);
+
+    let mouse = &descriptor.collections[0];
+    assert_eq!((mouse.usage_page, mouse.usage), (0x01, 0x02));
+    assert!(mouse.fields.is_empty());
+    assert_eq!(mouse.children.len(), 1);
+    assert_eq!(mouse.children[0].usage, 0x01);
+    assert_eq!(mouse.report_ids(ReportKind::Input), vec![1]);
+    assert_eq!(mouse.report_length(ReportKind::Input, Some(1)), 3);
+
+    let fido = descriptor.find_collection(0xf1d0, 0x01).unwrap();
+    assert_eq!(fido.report_ids(ReportKind::Input), vec![2]);
+    assert_eq!(fido.report_ids(ReportKind::Output), vec![2]);
+    assert_eq!(fido.report_length(ReportKind::Input, Some(2)), 64);
+    assert_eq!(fido.report_length(ReportKind::Input, Some(1)), 0);
+}
+
+#[test]
+fn push_and_pop() {
+    let descriptor = ReportDescriptor::parse(&[
+        0x06, 0xd0, 0xf1, // Usage Page (FIDO Alliance)
+        0x09, 0x01, // Usage (CTAPHID)
+        0xa1, 0x01, // Collection (Application)
+        0x75, 0x08, //   Report Size (8)
+        0x95, 0x40, //   Report Count (64)
+        0xa4, //   Push
+        0x95, 0x01, //   Report Count (1)
+        0xb1, 0x02, //   Feature (Data, Variable, Absolute)
+        0xb4, //   Pop
+        0x81, 0x02, //   Input (Data, Variable, Absolute)
+        0xc0, // End Collection
+    ]).unwrap();
+    let collection = &descriptor.collections[0];
+    assert_eq!(collection.report_length(ReportKind::Feature, None), 1);
+    assert_eq!(collection.report_length(ReportKind::Input, None), 64);
+}
+
+#[test]
+fn extended_usage() {
+    let descriptor = ReportDescriptor::parse(&[
+        0x05, 0x01, // Usage Page (Generic Desktop)
+        0x0b, 0x01, 0x00, 0xd0, 0xf1, // Usage (FIDO Alliance: CTAPHID)
+        0xa1, 0x01, // Collection (Application)
+        0xc0, // End Collection
+    ]).unwrap();
+    assert!(descriptor.find_collection(0xf1d0, 0x01).is_some());
+}
+
+#[test]
+fn long_items_are_skipped() {
+    let descriptor = ReportDescriptor::parse(&[
+        0xfe, 0x02, 0x10, 0xaa, 0xbb, // Long item with two bytes of data
+        0x06, 0xd0, 0xf1, // Usage Page (FIDO Alliance)
+        0x09, 0x01, // Usage (CTAPHID)
+        0xa1, 0x01, // Collection (Application)
+        0xc0, // End Collection
+    ]).unwrap();
+    assert!(descriptor.find_collection(0xf1d0, 0x01).is_some());
+}
+
+#[test]
+fn empty_descriptor() {
+    let descriptor = ReportDescriptor::parse(&[]).unwrap();
+    assert!(descriptor.collections.is_empty());
+}
+
+#[test]
+fn truncated_item() {
+    assert_eq!(
+        ReportDescriptor::parse(&FIDO[..(FIDO.len() - 4)]),
+        Err(DescriptorError::Truncated(FIDO.len() - 5))
+    );
+    assert_eq!(
+        ReportDescriptor::parse(&[0x07, 0xd0, 0xf1]),
+        Err(DescriptorError::Truncated(0))
+    );
+}
+
+#[test]
+fn truncated_long_item() {
+    assert_eq!(ReportDescriptor::parse(&[0xfe]), Err(DescriptorError::Truncated(0)));
+    assert_eq!(
+        ReportDescriptor::parse(&[0xfe, 0x04, 0x10, 0x00]),
+        Err(DescriptorError::Truncated(0))
+    );
+}
+
+#[test]
+fn unmatched_end_collection() {
+    assert_eq!(
+        ReportDescriptor::parse(&[0xa1, 0x01, 0xc0, 0xc0]),
+        Err(DescriptorError::UnmatchedEndCollection(3))
+    );
+}
+
+#[test]
+fn unclosed_collection() {
+    assert_eq!(
+        ReportDescriptor::parse(&FIDO[..(FIDO.len() - 1)]),
+        Err(DescriptorError::UnclosedCollection)
+    );
+}
+
+#[test]
+fn unmatched_pop() {
+    assert_eq!(
+        ReportDescriptor::parse(&[0xa4, 0xb4, 0xb4]),
+        Err(DescriptorError::UnmatchedPop(2))
+    );
+}
+
+#[test]
+fn zero_report_id() {
+    assert_eq!(
+        ReportDescriptor::parse(&[0x05, 0x01, 0x85, 0x00]),
+        Err(DescriptorError::InvalidReportId(2))
+    );
+}
+
+#[test]
+fn report_id_above_255() {
+    assert_eq!(
+        ReportDescriptor::parse(&[0x05, 0x01, 0x86, 0x01, 0x01]),
+        Err(DescriptorError::InvalidReportId(2))
+    );
+}
+
+#[test]
+fn collection_type_above_255() {
+    assert_eq!(
+        ReportDescriptor::parse(&[0x05, 0x01, 0x09, 0x01, 0xa2, 0x00, 0x01, 0xc0]),
+        Err(DescriptorError::InvalidCollectionType(4))
+    );
+}
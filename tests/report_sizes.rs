@@ -0,0 +1,58 @@
+// This file is part of ctap, a Rust implementation of the FIDO2 protocol.
+// Copyright (c) Ariën Holthuizen <contact@ardaxi.com>
+// Licensed under the Apache License, Version 2.0, <LICENSE-APACHE or
+// http://apache.org/licenses/LICENSE-2.0> or the MIT license <LICENSE-MIT or
+// http://opensource.org/licenses/MIT>, at your option. This file may not be
+// copied, modified, or distributed except according to those terms.
+#![cfg(feature = "virtual-authenticator")]
+extern crate ctap;
+
+mod common;
+
+use ctap::{DeviceCapabilities, FidoDevice, FidoErrorKind};
+
+use common::Simulator;
+
+fn capabilities() -> DeviceCapabilities {
+    DeviceCapabilities {
+        wink: true,
+        cbor: true,
+        nmsg: true,
+    }
+}
+
+#[test]
+fn asymmetric_report_sizes() {
+    let simulator = Simulator::with_report_sizes(64, 32, capabilities());
+    let mut device = FidoDevice::with_transport(simulator.transport()).unwrap();
+    // Large enough to take several packets in both directions.
+    device.ping(&[0x42; 100]).unwrap();
+    let credential = device
+        .make_credential("example.com", &[1], "user", &[0; 32])
+        .unwrap();
+    assert!(device.get_assertion(&credential, &[0; 32]).unwrap());
+    let state = simulator.state();
+    assert!(state.reports.len() > 3);
+    // Every output report is 32 bytes long, after its report ID.
+    assert!(state.reports.iter().all(|report| report.len() == 33));
+}
+
+#[test]
+fn output_reports_shorter_than_a_packet_header() {
+    let simulator = Simulator::new();
+    let mut transport = simulator.transport();
+    transport.output_report_size = 7;
+    let err = FidoDevice::with_transport(transport).err().unwrap();
+    assert_eq!(err.kind(), FidoErrorKind::DeviceUnsupported);
+    assert!(simulator.state().reports.is_empty());
+}
+
+#[test]
+fn input_reports_shorter_than_a_packet_header() {
+    let simulator = Simulator::new();
+    let mut transport = simulator.transport();
+    transport.input_report_size = 7;
+    let err = FidoDevice::with_transport(transport).err().unwrap();
+    assert_eq!(err.kind(), FidoErrorKind::DeviceUnsupported);
+    assert!(simulator.state().reports.is_empty());
+}
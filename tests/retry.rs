@@ -0,0 +1,78 @@
+// This file is part of ctap, a Rust implementation of the FIDO2 protocol.
+// Copyright (c) Ariën Holthuizen <contact@ardaxi.com>
+// Licensed under the Apache License, Version 2.0, <LICENSE-APACHE or
+// http://apache.org/licenses/LICENSE-2.0> or the MIT license <LICENSE-MIT or
+// http://opensource.org/licenses/MIT>, at your option. This file may not be
+// copied, modified, or distributed except according to those terms.
+#![cfg(feature = "virtual-authenticator")]
+extern crate ctap;
+extern crate failure;
+
+mod common;
+
+use std::time::Duration;
+
+use failure::Fail;
+
+use ctap::{CtapCommand, CtapError, FidoDevice, FidoErrorKind, RetryPolicy};
+
+use common::Simulator;
+
+/// A policy that retries quickly, so the tests don't take long.
+fn retries(attempts: u32) -> RetryPolicy {
+    RetryPolicy {
+        attempts,
+        backoff: Duration::from_millis(1),
+        max_backoff: Duration::from_millis(4),
+        jitter: Duration::from_millis(1),
+    }
+}
+
+/// Make a credential after the authenticator was told to fail the next
+/// requests with `errors`. Returns the error, if any, and how many times the
+/// request was sent.
+fn make_credential(policy: RetryPolicy, errors: &[CtapError]) -> (Option<CtapError>, usize) {
+    let simulator = Simulator::new();
+    let mut device = FidoDevice::with_transport(simulator.transport()).unwrap();
+    device.set_retry_policy(policy);
+    simulator.state().errors.extend(errors);
+    let error = device
+        .make_credential("example.com", &[1], "user", &[0; 32])
+        .err()
+        .map(|err| {
+            assert_eq!(err.kind(), FidoErrorKind::ParseCtap);
+            *err.cause().and_then(|cause| cause.downcast_ref::<CtapError>()).unwrap()
+        });
+    let state = simulator.state();
+    let sent = state
+        .messages
+        .iter()
+        .filter(|message| message.cmd == CtapCommand::Cbor)
+        .count();
+    // GetInfo was sent when the device was opened.
+    (error, sent - 1)
+}
+
+#[test]
+fn transient_errors_are_retried() {
+    let errors = [CtapError::ChannelBusy, CtapError::MsgTimeout];
+    assert_eq!(make_credential(retries(2), &errors), (None, 3));
+}
+
+#[test]
+fn retries_are_exhausted() {
+    let errors = [CtapError::ChannelBusy; 3];
+    assert_eq!(make_credential(retries(2), &errors), (Some(CtapError::ChannelBusy), 3));
+}
+
+#[test]
+fn never_retry() {
+    let errors = [CtapError::MsgTimeout];
+    assert_eq!(make_credential(RetryPolicy::never(), &errors), (Some(CtapError::MsgTimeout), 1));
+}
+
+#[test]
+fn other_errors_are_not_retried() {
+    let errors = [CtapError::InvalidPar];
+    assert_eq!(make_credential(retries(2), &errors), (Some(CtapError::InvalidPar), 1));
+}
@@ -0,0 +1,131 @@
+// This file is part of ctap, a Rust implementation of the FIDO2 protocol.
+// Copyright (c) Ariën Holthuizen <contact@ardaxi.com>
+// Licensed under the Apache License, Version 2.0, <LICENSE-APACHE or
+// http://apache.org/licenses/LICENSE-2.0> or the MIT license <LICENSE-MIT or
+// http://opensource.org/licenses/MIT>, at your option. This file may not be
+// copied, modified, or distributed except according to those terms.
+#![cfg(feature = "virtual-authenticator")]
+extern crate ctap;
+
+mod common;
+
+use std::io;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::thread;
+use std::time::{Duration, Instant};
+
+use ctap::{
+    AuthenticatorConfig, FidoDevice, FidoErrorKind, MemoryTransport, Transport,
+    VirtualAuthenticator,
+};
+
+use common::{SimulatedTransport, Simulator};
+
+const TIMEOUT: Duration = Duration::from_millis(200);
+
+/// A transport that panics once it is armed, like a buggy driver would.
+struct PanickingTransport {
+    inner: MemoryTransport,
+    armed: Arc<AtomicBool>,
+}
+
+impl Transport for PanickingTransport {
+    fn write_report(&mut self, report: &[u8]) -> io::Result<()> {
+        if self.armed.load(Ordering::SeqCst) {
+            panic!("transport failed");
+        }
+        self.inner.write_report(report)
+    }
+
+    fn read_report(&mut self, buf: &mut [u8], timeout: Option<Duration>) -> io::Result<usize> {
+        self.inner.read_report(buf, timeout)
+    }
+}
+
+fn device(config: AuthenticatorConfig) -> FidoDevice<MemoryTransport> {
+    let transport = MemoryTransport::new(VirtualAuthenticator::new(config));
+    FidoDevice::with_transport(transport).unwrap()
+}
+
+fn panicking_device(armed: &Arc<AtomicBool>) -> FidoDevice<PanickingTransport> {
+    let transport = PanickingTransport {
+        inner: MemoryTransport::new(VirtualAuthenticator::default()),
+        armed: armed.clone(),
+    };
+    FidoDevice::with_transport(transport).unwrap()
+}
+
+#[test]
+fn selects_the_touched_device() {
+    let untouched = AuthenticatorConfig {
+        user_present: false,
+        ..Default::default()
+    };
+    let devices = vec![device(untouched), device(AuthenticatorConfig::default())];
+    ctap::select_from(devices).unwrap();
+}
+
+#[test]
+fn fails_without_devices() {
+    let err = ctap::select_from(Vec::<FidoDevice<MemoryTransport>>::new())
+        .err()
+        .unwrap();
+    assert_eq!(err.kind(), FidoErrorKind::NoDevices);
+}
+
+#[test]
+fn fails_when_every_device_fails() {
+    let untouched = AuthenticatorConfig {
+        user_present: false,
+        ..Default::default()
+    };
+    let err = ctap::select_from(vec![device(untouched)]).err().unwrap();
+    assert_ne!(err.kind(), FidoErrorKind::NoDevices);
+}
+
+#[test]
+fn fails_when_every_device_panics() {
+    let armed = Arc::new(AtomicBool::new(false));
+    let devices = vec![panicking_device(&armed), panicking_device(&armed)];
+    armed.store(true, Ordering::SeqCst);
+    let err = ctap::select_from(devices).err().unwrap();
+    assert_eq!(err.kind(), FidoErrorKind::NoDevices);
+}
+
+/// A device that waits for a touch that never comes, and doesn't answer when
+/// it is cancelled either.
+fn unresponsive_device(simulator: &Simulator) -> FidoDevice<SimulatedTransport> {
+    let device = FidoDevice::with_transport(simulator.transport()).unwrap();
+    let mut state = simulator.state();
+    state.hold = true;
+    state.answer_cancel = false;
+    device
+}
+
+#[test]
+fn unresponsive_devices_time_out() {
+    let simulator = Simulator::new();
+    let mut device = unresponsive_device(&simulator);
+    device.set_timeout(Some(TIMEOUT));
+    let err = ctap::select_from(vec![device]).err().unwrap();
+    assert_eq!(err.kind(), FidoErrorKind::Timeout);
+}
+
+#[test]
+fn losing_devices_are_dropped() {
+    let loser = Simulator::new();
+    let mut device = unresponsive_device(&loser);
+    device.set_timeout(Some(TIMEOUT));
+    let winner = Simulator::new();
+    let devices = vec![device, FidoDevice::with_transport(winner.transport()).unwrap()];
+    let selected = ctap::select_from(devices).unwrap();
+    // The winner keeps waiting forever, as it did before.
+    assert_eq!(selected.timeout(), None);
+    let deadline = Instant::now() + Duration::from_secs(5);
+    while loser.is_connected() {
+        assert!(Instant::now() < deadline, "the losing device wasn't dropped");
+        thread::sleep(Duration::from_millis(10));
+    }
+    assert!(loser.state().cancels() >= 1);
+}
@@ -0,0 +1,57 @@
+// This file is part of ctap, a Rust implementation of the FIDO2 protocol.
+// Copyright (c) Ariën Holthuizen <contact@ardaxi.com>
+// Licensed under the Apache License, Version 2.0, <LICENSE-APACHE or
+// http://apache.org/licenses/LICENSE-2.0> or the MIT license <LICENSE-MIT or
+// http://opensource.org/licenses/MIT>, at your option. This file may not be
+// copied, modified, or distributed except according to those terms.
+#![cfg(feature = "virtual-authenticator")]
+extern crate ctap;
+
+mod common;
+
+use std::time::{Duration, Instant};
+
+use ctap::{FidoDevice, FidoErrorKind};
+
+use common::Simulator;
+
+const TIMEOUT: Duration = Duration::from_millis(200);
+// How long the device waits for the answer to its CANCEL.
+const CANCEL_TIMEOUT: Duration = Duration::from_millis(500);
+
+#[test]
+fn request_to_unresponsive_authenticator_times_out() {
+    let simulator = Simulator::new();
+    simulator.state().hold = true;
+    simulator.state().answer_cancel = false;
+    let mut device = FidoDevice::with_transport(simulator.transport()).unwrap();
+    device.set_timeout(Some(TIMEOUT));
+    let start = Instant::now();
+    let err = device
+        .make_credential("example.com", &[1], "user", &[0; 32])
+        .unwrap_err();
+    assert_eq!(err.kind(), FidoErrorKind::Timeout);
+    assert_eq!(simulator.state().cancels(), 1);
+    // The device waited for a late response after sending CANCEL.
+    assert!(start.elapsed() >= TIMEOUT + CANCEL_TIMEOUT);
+}
+
+#[test]
+fn late_response_is_not_mistaken_for_the_next() {
+    let simulator = Simulator::new();
+    simulator.state().hold = true;
+    let mut device = FidoDevice::with_transport(simulator.transport()).unwrap();
+    device.set_timeout(Some(TIMEOUT));
+    let start = Instant::now();
+    let err = device
+        .make_credential("example.com", &[1], "user", &[0; 32])
+        .unwrap_err();
+    assert_eq!(err.kind(), FidoErrorKind::Timeout);
+    assert_eq!(simulator.state().cancels(), 1);
+    // The authenticator answered the CANCEL right away.
+    assert!(start.elapsed() < TIMEOUT + CANCEL_TIMEOUT);
+    simulator.state().hold = false;
+    device
+        .make_credential("example.com", &[1], "user", &[0; 32])
+        .unwrap();
+}
@@ -0,0 +1,236 @@
+// This file is part of ctap, a Rust implementation of the FIDO2 protocol.
+// Copyright (c) Ariën Holthuizen <contact@ardaxi.com>
+// Licensed under the Apache License, Version 2.0, <LICENSE-APACHE or
+// http://apache.org/licenses/LICENSE-2.0> or the MIT license <LICENSE-MIT or
+// http://opensource.org/licenses/MIT>, at your option. This file may not be
+// copied, modified, or distributed except according to those terms.
+#![cfg(feature = "virtual-authenticator")]
+extern crate ctap;
+extern crate ring;
+extern crate untrusted;
+
+mod common;
+
+use std::time::Duration;
+
+use ring::rand::SystemRandom;
+use ring::signature;
+use untrusted::Input;
+
+use ctap::{DeviceCapabilities, FidoCredential, FidoDevice, FidoErrorKind};
+
+use common::{SimulatedTransport, Simulator};
+
+static SW_NO_ERROR: [u8; 2] = [0x90, 0x00];
+static SW_CONDITIONS_NOT_SATISFIED: [u8; 2] = [0x69, 0x85];
+static SW_WRONG_DATA: [u8; 2] = [0x6a, 0x80];
+static SW_INS_NOT_SUPPORTED: [u8; 2] = [0x6d, 0x00];
+// A self-signed certificate isn't needed, the attestation isn't checked.
+static ATTESTATION_CERTIFICATE: [u8; 5] = [0x30, 0x03, 0x02, 0x01, 0x00];
+
+struct Key {
+    handle: Vec<u8>,
+    application: Vec<u8>,
+    pkcs8: Vec<u8>,
+}
+
+/// A U2F security key that doesn't implement CTAP2, answering U2F_REGISTER
+/// and U2F_AUTHENTICATE messages sent through CTAPHID_MSG.
+struct U2fKey {
+    rng: SystemRandom,
+    keys: Vec<Key>,
+    counter: u32,
+    // The number of requests to deny before the simulated user touches the
+    // key, so the host has to poll.
+    touch_after: usize,
+    denied: usize,
+}
+
+impl U2fKey {
+    fn new(touch_after: usize) -> Self {
+        U2fKey {
+            rng: SystemRandom::new(),
+            keys: Vec::new(),
+            counter: 0,
+            touch_after,
+            denied: 0,
+        }
+    }
+
+    fn handle_apdu(&mut self, apdu: &[u8]) -> Vec<u8> {
+        // Requests are sent with extended length encoding.
+        let data = if apdu.len() > 7 {
+            let length = ((apdu[5] as usize) << 8) | apdu[6] as usize;
+            &apdu[7..(7 + length)]
+        } else {
+            &[]
+        };
+        let response = match apdu[1] {
+            0x01 => self.register(data),
+            0x02 => self.authenticate(data),
+            0x03 => Ok(b"U2F_V2".to_vec()),
+            _ => Err(SW_INS_NOT_SUPPORTED),
+        };
+        match response {
+            Ok(mut response) => {
+                response.extend_from_slice(&SW_NO_ERROR);
+                response
+            }
+            Err(status) => status.to_vec(),
+        }
+    }
+
+    fn user_presence(&mut self) -> Result<(), [u8; 2]> {
+        if self.denied < self.touch_after {
+            self.denied += 1;
+            return Err(SW_CONDITIONS_NOT_SATISFIED);
+        }
+        self.denied = 0;
+        Ok(())
+    }
+
+    fn register(&mut self, data: &[u8]) -> Result<Vec<u8>, [u8; 2]> {
+        let (challenge, application) = data.split_at(32);
+        self.user_presence()?;
+        let pkcs8 = signature::ECDSAKeyPair::generate_pkcs8(
+            &signature::ECDSA_P256_SHA256_ASN1_SIGNING,
+            &self.rng,
+        )
+        .unwrap();
+        // The public key makes up the end of the PKCS#8 document.
+        let public_key = &pkcs8.as_ref()[(pkcs8.as_ref().len() - 65)..];
+        let handle = vec![self.keys.len() as u8; 48];
+        let mut signed = vec![0x00];
+        signed.extend_from_slice(application);
+        signed.extend_from_slice(challenge);
+        signed.extend_from_slice(&handle);
+        signed.extend_from_slice(public_key);
+
+        let mut response = vec![0x05];
+        response.extend_from_slice(public_key);
+        response.push(handle.len() as u8);
+        response.extend_from_slice(&handle);
+        response.extend_from_slice(&ATTESTATION_CERTIFICATE);
+        response.extend(self.sign(pkcs8.as_ref(), &signed));
+        self.keys.push(Key {
+            handle,
+            application: application.to_vec(),
+            pkcs8: pkcs8.as_ref().to_vec(),
+        });
+        Ok(response)
+    }
+
+    fn authenticate(&mut self, data: &[u8]) -> Result<Vec<u8>, [u8; 2]> {
+        let (challenge, data) = data.split_at(32);
+        let (application, data) = data.split_at(32);
+        let handle = &data[1..];
+        let pkcs8 = self
+            .keys
+            .iter()
+            .find(|key| key.handle == handle && key.application == application)
+            .map(|key| key.pkcs8.clone())
+            .ok_or(SW_WRONG_DATA)?;
+        self.user_presence()?;
+        self.counter += 1;
+        let mut response = vec![0x01];
+        response.extend_from_slice(&self.counter.to_be_bytes());
+        let mut signed = application.to_vec();
+        signed.extend_from_slice(&response);
+        signed.extend_from_slice(challenge);
+        response.extend(self.sign(&pkcs8, &signed));
+        Ok(response)
+    }
+
+    fn sign(&self, pkcs8: &[u8], data: &[u8]) -> Vec<u8> {
+        let key = signature::ECDSAKeyPair::from_pkcs8(
+            &signature::ECDSA_P256_SHA256_ASN1_SIGNING,
+            Input::from(pkcs8),
+        )
+        .unwrap();
+        key.sign(Input::from(data), &self.rng)
+            .unwrap()
+            .as_ref()
+            .to_vec()
+    }
+}
+
+/// A transport to a simulated U2F key with the given capabilities.
+fn key_transport(capabilities: DeviceCapabilities, touch_after: usize) -> SimulatedTransport {
+    let simulator = Simulator::with_capabilities(capabilities);
+    let mut key = U2fKey::new(touch_after);
+    simulator.state().msg = Some(Box::new(move |apdu| key.handle_apdu(apdu)));
+    simulator.transport()
+}
+
+fn u2f_transport(touch_after: usize) -> SimulatedTransport {
+    let capabilities = DeviceCapabilities {
+        wink: false,
+        cbor: false,
+        nmsg: false,
+    };
+    key_transport(capabilities, touch_after)
+}
+
+#[test]
+fn registers_and_authenticates() {
+    let mut device = FidoDevice::with_transport(u2f_transport(0)).unwrap();
+    assert!(!device.capabilities().cbor);
+    let credential = device
+        .make_credential("example.com", &[1], "user", &[1; 32])
+        .unwrap();
+    assert_eq!(credential.rp_id, "example.com");
+    assert_eq!(credential.public_key.len(), 65);
+    assert!(device.get_assertion(&credential, &[2; 32]).unwrap());
+}
+
+#[test]
+fn waits_for_user_presence() {
+    let mut device = FidoDevice::with_transport(u2f_transport(2)).unwrap();
+    let credential = device
+        .make_credential("example.com", &[1], "user", &[1; 32])
+        .unwrap();
+    assert!(device.get_assertion(&credential, &[2; 32]).unwrap());
+}
+
+#[test]
+fn times_out_without_user_presence() {
+    let mut device = FidoDevice::with_transport(u2f_transport(usize::MAX)).unwrap();
+    device.set_timeout(Some(Duration::from_millis(250)));
+    let err = device
+        .make_credential("example.com", &[1], "user", &[1; 32])
+        .unwrap_err();
+    assert_eq!(err.kind(), FidoErrorKind::Timeout);
+}
+
+#[test]
+fn rejects_credentials_of_other_relying_parties() {
+    let mut device = FidoDevice::with_transport(u2f_transport(0)).unwrap();
+    let credential = device
+        .make_credential("example.com", &[1], "user", &[1; 32])
+        .unwrap();
+    let credential = FidoCredential {
+        rp_id: "example.org".to_string(),
+        ..credential
+    };
+    let err = device.get_assertion(&credential, &[2; 32]).unwrap_err();
+    assert_eq!(err.kind(), FidoErrorKind::U2fError(0x6a80));
+}
+
+#[test]
+fn pins_are_unsupported() {
+    let mut device = FidoDevice::with_transport(u2f_transport(0)).unwrap();
+    let err = device.unlock("1234").unwrap_err();
+    assert_eq!(err.kind(), FidoErrorKind::CapabilityUnsupported);
+}
+
+#[test]
+fn requires_ctap1_without_ctap2() {
+    let capabilities = DeviceCapabilities {
+        wink: false,
+        cbor: false,
+        nmsg: true,
+    };
+    let transport = key_transport(capabilities, 0);
+    let err = FidoDevice::with_transport(transport).err().unwrap();
+    assert_eq!(err.kind(), FidoErrorKind::DeviceUnsupported);
+}
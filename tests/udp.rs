@@ -0,0 +1,68 @@
+// This file is part of ctap, a Rust implementation of the FIDO2 protocol.
+// Copyright (c) Ariën Holthuizen <contact@ardaxi.com>
+// Licensed under the Apache License, Version 2.0, <LICENSE-APACHE or
+// http://apache.org/licenses/LICENSE-2.0> or the MIT license <LICENSE-MIT or
+// http://opensource.org/licenses/MIT>, at your option. This file may not be
+// copied, modified, or distributed except according to those terms.
+#![cfg(feature = "virtual-authenticator")]
+extern crate ctap;
+
+mod common;
+
+use std::net::UdpSocket;
+use std::thread;
+use std::time::Duration;
+
+use ctap::{FidoDevice, FidoErrorKind, UdpTransport};
+
+use common::Simulator;
+
+/// Run a simulator on a new socket, handling the first `reports` reports it
+/// receives and ignoring any after that. Returns the simulator's address.
+fn simulator(reports: usize) -> String {
+    let socket = UdpSocket::bind("127.0.0.1:0").unwrap();
+    let address = socket.local_addr().unwrap().to_string();
+    thread::spawn(move || {
+        let simulator = Simulator::new();
+        let mut handled = 0;
+        // Datagrams carry the report without a report ID.
+        let mut buf = [0; 65];
+        loop {
+            let (read, host) = socket.recv_from(&mut buf[1..]).unwrap();
+            assert_eq!(read, 64);
+            if handled == reports {
+                continue;
+            }
+            simulator.write(&buf);
+            for report in simulator.take_responses() {
+                socket.send_to(&report, host).unwrap();
+            }
+            handled += 1;
+        }
+    });
+    address
+}
+
+#[test]
+fn talks_to_simulator() {
+    let address = simulator(usize::MAX);
+    let transport = UdpTransport::connect("127.0.0.1:0", &address).unwrap();
+    let mut device = FidoDevice::with_transport(transport).unwrap();
+    let credential = device
+        .make_credential("example.com", &[1], "user", &[0; 32])
+        .unwrap();
+    assert!(device.get_assertion(&credential, &[0; 32]).unwrap());
+}
+
+#[test]
+fn times_out_without_response() {
+    // Only the INIT and GetInfo requests are answered.
+    let address = simulator(2);
+    let transport = UdpTransport::connect("127.0.0.1:0", &address).unwrap();
+    let mut device = FidoDevice::with_transport(transport).unwrap();
+    device.set_timeout(Some(Duration::from_millis(50)));
+    let err = device
+        .make_credential("example.com", &[1], "user", &[0; 32])
+        .unwrap_err();
+    assert_eq!(err.kind(), FidoErrorKind::Timeout);
+}
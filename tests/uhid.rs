@@ -0,0 +1,49 @@
+// This file is part of ctap, a Rust implementation of the FIDO2 protocol.
+// Copyright (c) Ariën Holthuizen <contact@ardaxi.com>
+// Licensed under the Apache License, Version 2.0, <LICENSE-APACHE or
+// http://apache.org/licenses/LICENSE-2.0> or the MIT license <LICENSE-MIT or
+// http://opensource.org/licenses/MIT>, at your option. This file may not be
+// copied, modified, or distributed except according to those terms.
+#![cfg(feature = "uhid")]
+extern crate ctap;
+
+use std::time::Duration;
+
+use ctap::{AuthenticatorConfig, FidoDevice, UhidDevice, VirtualAuthenticator};
+
+// These tests need write access to /dev/uhid, so they only run when asked
+// for with `cargo test --features uhid -- --ignored`.
+
+#[test]
+#[ignore = "needs write access to /dev/uhid"]
+fn device_is_enumerated() {
+    let device = UhidDevice::create(VirtualAuthenticator::default()).unwrap();
+    let info = device.device_info(Duration::from_secs(5)).unwrap();
+    assert!(info.is_fido());
+    assert_eq!(info.input_report_size, 64);
+    assert_eq!(info.output_report_size, 64);
+    assert_eq!(info.input_report_id, None);
+    assert_eq!(info.vendor_id, 0x1209);
+    assert_eq!(info.serial_number.as_deref(), Some(device.serial_number()));
+    assert!(ctap::get_devices()
+        .unwrap()
+        .any(|found| found.path == info.path));
+}
+
+#[test]
+#[ignore = "needs write access to /dev/uhid"]
+fn authenticates_through_hidraw() {
+    let config = AuthenticatorConfig {
+        pin: Some("1234".to_string()),
+        ..Default::default()
+    };
+    let device = UhidDevice::create(VirtualAuthenticator::new(config)).unwrap();
+    let info = device.device_info(Duration::from_secs(5)).unwrap();
+    let mut fido = FidoDevice::new(&info).unwrap();
+    fido.unlock("1234").unwrap();
+    let credential = fido
+        .make_credential("example.com", &[1], "user", &[0; 32])
+        .unwrap();
+    assert!(fido.get_assertion(&credential, &[0; 32]).unwrap());
+    assert_eq!(device.authenticator().lock().unwrap().credential_count(), 1);
+}
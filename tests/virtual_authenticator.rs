@@ -0,0 +1,276 @@
+// This file is part of ctap, a Rust implementation of the FIDO2 protocol.
+// Copyright (c) Ariën Holthuizen <contact@ardaxi.com>
+// Licensed under the Apache License, Version 2.0, <LICENSE-APACHE or
+// http://apache.org/licenses/LICENSE-2.0> or the MIT license <LICENSE-MIT or
+// http://opensource.org/licenses/MIT>, at your option. This file may not be
+// copied, modified, or distributed except according to those terms.
+#![cfg(feature = "virtual-authenticator")]
+extern crate cbor;
+extern crate ctap;
+extern crate failure;
+
+use std::io::Cursor;
+
+use cbor::value::{Key, Value};
+use cbor::{Config, Encoder, GenericDecoder};
+use failure::Fail;
+
+use ctap::{
+    AuthenticatorConfig, FidoDevice, FidoError, FidoErrorKind, MemoryTransport,
+    VirtualAuthenticator,
+};
+
+fn device(config: AuthenticatorConfig) -> (FidoDevice<MemoryTransport>, MemoryTransport) {
+    let transport = MemoryTransport::new(VirtualAuthenticator::new(config));
+    let handle = transport.connect();
+    (FidoDevice::with_transport(transport).unwrap(), handle)
+}
+
+/// The CTAP2 status code an error was caused by, if any.
+fn status(err: &FidoError) -> Option<u8> {
+    match err.kind() {
+        FidoErrorKind::CborError(status) => Some(status),
+        _ => err
+            .cause()
+            .and_then(|cause| cause.downcast_ref::<FidoError>())
+            .and_then(status),
+    }
+}
+
+fn make_credential_request(user_id: &[u8], resident: bool) -> Vec<u8> {
+    let mut encoder = Encoder::new(vec![0x01]);
+    encoder.object(5).unwrap();
+    encoder.u8(0x01).unwrap();
+    encoder.bytes(&[0; 32]).unwrap();
+    encoder.u8(0x02).unwrap();
+    encoder.object(1).unwrap();
+    encoder.text("id").unwrap();
+    encoder.text("example.com").unwrap();
+    encoder.u8(0x03).unwrap();
+    encoder.object(2).unwrap();
+    encoder.text("id").unwrap();
+    encoder.bytes(user_id).unwrap();
+    encoder.text("name").unwrap();
+    encoder.text("user").unwrap();
+    encoder.u8(0x04).unwrap();
+    encoder.array(1).unwrap();
+    encoder.object(2).unwrap();
+    encoder.text("alg").unwrap();
+    encoder.i8(-7).unwrap();
+    encoder.text("type").unwrap();
+    encoder.text("public-key").unwrap();
+    encoder.u8(0x07).unwrap();
+    encoder.object(1).unwrap();
+    encoder.text("rk").unwrap();
+    encoder.bool(resident).unwrap();
+    encoder.into_writer()
+}
+
+fn get_assertion_request() -> Vec<u8> {
+    let mut encoder = Encoder::new(vec![0x02]);
+    encoder.object(2).unwrap();
+    encoder.u8(0x01).unwrap();
+    encoder.text("example.com").unwrap();
+    encoder.u8(0x02).unwrap();
+    encoder.bytes(&[0; 32]).unwrap();
+    encoder.into_writer()
+}
+
+fn decode_response(response: &[u8]) -> (u8, Option<Value>) {
+    if response.len() == 1 {
+        return (response[0], None);
+    }
+    let mut decoder = GenericDecoder::new(Config::default(), Cursor::new(&response[1..]));
+    (response[0], Some(decoder.value().unwrap()))
+}
+
+#[test]
+fn make_credential_and_get_assertion() {
+    let config = AuthenticatorConfig {
+        aaguid: [7; 16],
+        ..Default::default()
+    };
+    let (mut device, handle) = device(config);
+    assert_eq!(device.aaguid(), &[7; 16]);
+    let credential = device
+        .make_credential("example.com", &[1], "user", &[0; 32])
+        .unwrap();
+    assert_eq!(handle.authenticator().lock().unwrap().credential_count(), 1);
+    assert!(device.get_assertion(&credential, &[1; 32]).unwrap());
+}
+
+#[test]
+fn ping_spans_several_packets() {
+    let (mut device, _) = device(AuthenticatorConfig::default());
+    device.ping(&[0x42; 200]).unwrap();
+}
+
+#[test]
+fn pin_is_required_when_set() {
+    let config = AuthenticatorConfig {
+        pin: Some("1234".to_string()),
+        ..Default::default()
+    };
+    let (mut device, _) = device(config);
+    let err = device
+        .make_credential("example.com", &[1], "user", &[0; 32])
+        .unwrap_err();
+    assert_eq!(err.kind(), FidoErrorKind::PinRequired);
+}
+
+#[test]
+fn unlock_with_pin() {
+    let config = AuthenticatorConfig {
+        pin: Some("1234".to_string()),
+        ..Default::default()
+    };
+    let (mut device, handle) = device(config);
+    let err = device.unlock("4321").unwrap_err();
+    assert_eq!(status(&err), Some(0x31));
+    assert_eq!(handle.authenticator().lock().unwrap().pin_retries(), 7);
+
+    device.unlock("1234").unwrap();
+    assert_eq!(handle.authenticator().lock().unwrap().pin_retries(), 8);
+    let credential = device
+        .make_credential("example.com", &[1], "user", &[0; 32])
+        .unwrap();
+    assert!(device.get_assertion(&credential, &[0; 32]).unwrap());
+}
+
+#[test]
+fn pin_token_is_invalidated_by_power_cycle() {
+    let config = AuthenticatorConfig {
+        pin: Some("1234".to_string()),
+        ..Default::default()
+    };
+    let (mut device, handle) = device(config);
+    device.unlock("1234").unwrap();
+    handle.authenticator().lock().unwrap().power_cycle();
+    let err = device
+        .make_credential("example.com", &[1], "user", &[0; 32])
+        .unwrap_err();
+    assert_eq!(status(&err), Some(0x33));
+}
+
+#[test]
+fn select_without_creating_credentials() {
+    let (mut device, handle) = device(AuthenticatorConfig::default());
+    device.select().unwrap();
+    assert_eq!(handle.authenticator().lock().unwrap().credential_count(), 0);
+
+    let config = AuthenticatorConfig {
+        versions: vec!["FIDO_2_0".to_string(), "FIDO_2_1".to_string()],
+        ..Default::default()
+    };
+    let (mut device, _) = self::device(config);
+    device.select().unwrap();
+}
+
+#[test]
+fn select_is_denied_without_user() {
+    let config = AuthenticatorConfig {
+        user_present: false,
+        ..Default::default()
+    };
+    let (mut device, _) = device(config);
+    let err = device.select().unwrap_err();
+    assert_eq!(status(&err), Some(0x27));
+}
+
+#[test]
+fn get_next_assertion_returns_resident_keys() {
+    let mut authenticator = VirtualAuthenticator::default();
+    assert_eq!(
+        authenticator.handle_request(&make_credential_request(&[1], true))[0],
+        0
+    );
+    assert_eq!(
+        authenticator.handle_request(&make_credential_request(&[2], true))[0],
+        0
+    );
+    assert_eq!(
+        authenticator.handle_request(&make_credential_request(&[3], false))[0],
+        0
+    );
+
+    let (status, response) =
+        decode_response(&authenticator.handle_request(&get_assertion_request()));
+    assert_eq!(status, 0);
+    let response = match response {
+        Some(Value::Map(response)) => response,
+        response => panic!("unexpected response {:?}", response),
+    };
+    assert_eq!(response.get(&Key::u64(0x05)), Some(&Value::U8(2)));
+    assert!(response.contains_key(&Key::u64(0x04)));
+
+    let (status, response) = decode_response(&authenticator.handle_request(&[0x08]));
+    assert_eq!(status, 0);
+    let response = match response {
+        Some(Value::Map(response)) => response,
+        response => panic!("unexpected response {:?}", response),
+    };
+    assert!(!response.contains_key(&Key::u64(0x05)));
+
+    assert_eq!(authenticator.handle_request(&[0x08]), vec![0x30]);
+}
+
+#[test]
+fn get_next_assertion_must_follow_get_assertion() {
+    let mut authenticator = VirtualAuthenticator::default();
+    authenticator.handle_request(&make_credential_request(&[1], true));
+    authenticator.handle_request(&make_credential_request(&[2], true));
+    assert_eq!(authenticator.handle_request(&get_assertion_request())[0], 0);
+    assert_eq!(authenticator.handle_request(&[0x04])[0], 0);
+    assert_eq!(authenticator.handle_request(&[0x08]), vec![0x30]);
+}
+
+#[test]
+fn resident_key_replaces_same_user() {
+    let mut authenticator = VirtualAuthenticator::default();
+    authenticator.handle_request(&make_credential_request(&[1], true));
+    authenticator.handle_request(&make_credential_request(&[1], true));
+    assert_eq!(authenticator.credential_count(), 1);
+}
+
+#[test]
+fn resident_keys_can_be_disabled() {
+    let mut authenticator = VirtualAuthenticator::new(AuthenticatorConfig {
+        resident_keys: false,
+        ..Default::default()
+    });
+    assert_eq!(
+        authenticator.handle_request(&make_credential_request(&[1], true)),
+        vec![0x2b]
+    );
+}
+
+#[test]
+fn reset_removes_credentials_and_pin() {
+    let mut authenticator = VirtualAuthenticator::new(AuthenticatorConfig {
+        pin: Some("1234".to_string()),
+        ..Default::default()
+    });
+    // The PIN is required for new credentials, unless the user is verified
+    // some other way.
+    assert_eq!(
+        authenticator.handle_request(&make_credential_request(&[1], false)),
+        vec![0x36]
+    );
+    assert_eq!(authenticator.handle_request(&[0x07]), vec![0x00]);
+    assert!(!authenticator.has_pin());
+    assert_eq!(
+        authenticator.handle_request(&make_credential_request(&[1], false))[0],
+        0
+    );
+    assert_eq!(authenticator.handle_request(&[0x07]), vec![0x00]);
+    assert_eq!(authenticator.credential_count(), 0);
+}
+
+#[test]
+fn unknown_commands_are_rejected() {
+    let mut authenticator = VirtualAuthenticator::default();
+    assert_eq!(authenticator.handle_request(&[0x40]), vec![0x01]);
+    // authenticatorSelection only exists in CTAP2.1.
+    assert_eq!(authenticator.handle_request(&[0x0b]), vec![0x01]);
+    assert_eq!(authenticator.handle_request(&[0x01, 0xa1]), vec![0x12]);
+}
@@ -0,0 +1,45 @@
+// This file is part of ctap, a Rust implementation of the FIDO2 protocol.
+// Copyright (c) Ariën Holthuizen <contact@ardaxi.com>
+// Licensed under the Apache License, Version 2.0, <LICENSE-APACHE or
+// http://apache.org/licenses/LICENSE-2.0> or the MIT license <LICENSE-MIT or
+// http://opensource.org/licenses/MIT>, at your option. This file may not be
+// copied, modified, or distributed except according to those terms.
+#![cfg(feature = "virtual-authenticator")]
+extern crate ctap;
+
+mod common;
+
+use ctap::{CtapCommand, DeviceCapabilities, FidoDevice, FidoErrorKind};
+
+use common::Simulator;
+
+fn winks(simulator: &Simulator) -> usize {
+    simulator
+        .state()
+        .messages
+        .iter()
+        .filter(|message| message.cmd == CtapCommand::Wink)
+        .count()
+}
+
+#[test]
+fn wink() {
+    let simulator = Simulator::new();
+    let mut device = FidoDevice::with_transport(simulator.transport()).unwrap();
+    device.wink().unwrap();
+    assert_eq!(winks(&simulator), 1);
+}
+
+#[test]
+fn wink_unsupported() {
+    let simulator = Simulator::with_capabilities(DeviceCapabilities {
+        wink: false,
+        cbor: true,
+        nmsg: true,
+    });
+    let mut device = FidoDevice::with_transport(simulator.transport()).unwrap();
+    let err = device.wink().unwrap_err();
+    assert_eq!(err.kind(), FidoErrorKind::CapabilityUnsupported);
+    // The authenticator isn't asked at all.
+    assert_eq!(winks(&simulator), 0);
+}